@@ -1,6 +1,6 @@
 //#![allow(unused_imports)]
-use client_common::core::client::Client;
-use common::crypto::totp::parse_totp_uri;
+use client_common::{core::client::Client, webauthn::SoftwareAuthenticator};
+use common::api::SecondFactor;
 use eyre::WrapErr;
 use rustyline::{error::ReadlineError, history::DefaultHistory};
 use rustyline::Editor;
@@ -8,6 +8,9 @@ use rustyline::Editor;
 use tracing::{error, info};
 use tracing_subscriber::EnvFilter; // could be async_compat::CompatExt
 
+// must match the server's `webauthn_origin`
+const WEBAUTHN_ORIGIN: &str = "http://127.0.0.1:8081";
+
 
 pub fn setup_logger() -> eyre::Result<()> {
 
@@ -35,6 +38,8 @@ fn main() -> eyre::Result<()>{
     let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
     
     let mut client = Client::default();
+    client.set_device_label(&format!("cli on {}", std::env::consts::OS));
+    let mut authenticator = SoftwareAuthenticator::new(WEBAUTHN_ORIGIN);
 
     // `()` can be used when no completer is required
     let mut rl = rustyline::DefaultEditor::new()?;
@@ -42,8 +47,7 @@ fn main() -> eyre::Result<()>{
     //     println!("No previous history.");
     // }
     loop {
-        //let readline = rl.readline(&format!("{}> ", client.get_username()?.unwrap_or_default()));
-        let readline = rl.readline("> ");
+        let readline = rl.readline(&format!("{}> ", client.get_username().ok().flatten().unwrap_or_default()));
         match readline {
             Ok(line) => {
                 rl.add_history_entry(line.as_str());
@@ -51,17 +55,45 @@ fn main() -> eyre::Result<()>{
                     ["signup", username, password] => client.signup(username, password).await.map(|e| format!("{:?}", e)),
                     ["login", username, password] => client.login(username, password, false, false).await.map(|e| format!("{:?}", e)),
                     ["login_uber", username, password] => client.login(username, password, true, false).await.map(|e| format!("{:?}", e)),
-                    ["login_recovery", recovery_key] => client.login_recovery(recovery_key, false, false).await.map(|e| format!("{:?}", e)),
-                    ["login_recovery_uber", recovery_key] => client.login_recovery(recovery_key, true, false).await.map(|e| format!("{:?}", e)),
+                    ["login_recovery", ref recovery_key @ ..] => client.login_recovery(&recovery_key.join(" "), false, false).await.map(|e| format!("{:?}", e)),
+                    ["login_recovery_uber", ref recovery_key @ ..] => client.login_recovery(&recovery_key.join(" "), true, false).await.map(|e| format!("{:?}", e)),
+                    ["set_contact_email", email] => client.set_contact_email(email).await.map(|e| format!("{:?}", e)),
+                    ["verify_contact_email", code] => client.verify_contact_email(code).await.map(|e| format!("{:?}", e)),
+                    ["pending_recovery"] => Ok(format!("{:?}", client.get_pending_recovery())),
+                    ["cancel_recovery"] => client.cancel_recovery().await.map(|e| format!("{:?}", e)),
+                    ["refresh_session"] => client.refresh_session().await.map(|e| format!("{:?}", e)),
+                    ["list_sessions"] => client.list_sessions().await.map(|sessions| sessions.iter().map(|s| format!("{} {}{} ip={} created={} last_seen={}",
+                        bs58::encode(s.session_id.as_slice()).into_string(), s.device_label, if s.current {" (current)"} else {""}, s.ip, s.created, s.last_seen)).collect::<Vec<_>>().join("\n")),
+                    ["revoke_session", session_id] => client.revoke_session(session_id).await.map(|e| format!("{:?}", e)),
+                    ["trust_device", "on"] => Ok(format!("{:?}", client.set_trust_device(true))),
+                    ["trust_device", "off"] => Ok(format!("{:?}", client.set_trust_device(false))),
+                    ["list_trusted_devices"] => client.list_trusted_devices().await.map(|devices| devices.iter().map(|d| format!("{} {} created={} last_used={}",
+                        bs58::encode(d.trusted_device_id.as_slice()).into_string(), d.device_label, d.created, d.last_used)).collect::<Vec<_>>().join("\n")),
+                    ["revoke_trusted_device", trusted_device_id] => client.revoke_trusted_device(trusted_device_id).await.map(|e| format!("{:?}", e)),
+                    ["reauth", username, password] => client.reauthenticate_for_uber(username, password, None).await.map(|e| format!("{:?}", e)),
+                    ["reauth_totp", username, password, code] => client.reauthenticate_for_uber(username, password, Some(SecondFactor::Totp(code.to_owned()))).await.map(|e| format!("{:?}", e)),
+                    ["reauth_backup_code", username, password, code] => client.reauthenticate_for_uber(username, password, Some(SecondFactor::BackupCode(code.to_owned()))).await.map(|e| format!("{:?}", e)),
+                    ["reauth_webauthn", username, password] => match client.get_webauthn_second_factor(&mut authenticator).await {
+                        Ok(second_factor) => client.reauthenticate_for_uber(username, password, Some(second_factor)).await.map(|e| format!("{:?}", e)),
+                        Err(e) => Err(e),
+                    },
+                    ["reauth_recovery", ref recovery_key @ ..] => client.reauthenticate_for_uber_recovery(&recovery_key.join(" "), None).await.map(|e| format!("{:?}", e)),
                     ["set_username_password", username, password] => client.set_username_password(username, password).await.map(|e| format!("{:?}", e)),
+                    ["change_password", username, password, new_password] => client.change_password(username, password, new_password).await.map(|e| format!("{:?}", e)),
                     ["change_recovery_key"] => client.change_recovery_key().await.map(|e| format!("{:?}", e)),
                     ["rotate_master_key"] => client.rotate_master_key().await.map(|e| format!("{:?}", e)),
-                    ["logout"] => Ok(format!("{:?}", client.logout())),
+                    ["delete_account"] => client.delete_account().await.map(|e| format!("{:?}", e)),
+                    ["logout"] => Ok(format!("{:?}", client.logout().await)),
                     ["hibp", password] => client_common::hibp(password).await.map(|e| format!("{:?}", e)),
-                    ["set_totp", uri] => {
-                        let (secret, digits, algo, period) = parse_totp_uri(uri)?;
-                        client.set_totp(&secret, digits, &algo, period).await.map(|e| format!("{:?}", e))
-                    },
+                    ["begin_totp_enrollment"] => client.begin_totp_enrollment().await.map(|e| format!("{:?}", e)),
+                    ["confirm_totp_enrollment", code] => client.confirm_totp_enrollment(code).await.map(|e| format!("{:?}", e)),
+                    ["submit_totp", code] => client.submit_totp(code).await.map(|e| format!("{:?}", e)),
+                    ["submit_backup_code", code] => client.submit_backup_code(code).await.map(|e| format!("{:?}", e)),
+                    ["generate_backup_codes"] => client.generate_backup_codes().await.map(|e| format!("{:?}", e)),
+                    ["backup_codes_count"] => client.get_backup_codes_count().await.map(|e| format!("{:?}", e)),
+                    ["register_webauthn", name] => client.register_webauthn(&mut authenticator, name).await.map(|e| format!("{:?}", e)),
+                    ["submit_webauthn"] => client.submit_webauthn(&mut authenticator).await.map(|e| format!("{:?}", e)),
+                    ["unset_webauthn"] => client.unset_webauthn().await.map(|e| format!("{:?}", e)),
                     ["unset_totp"] => client.unset_totp().await.map(|e| format!("{:?}", e)),
                     ["check_totp", uri, input] => common::crypto::totp::check_totp (uri, input).map(|e| format!("{:?}", e)),
                     _ => Err(eyre::eyre!("invalid command")),
@@ -1,6 +1,6 @@
 use std::mem;
 
-use common::{api::{self, MasterKey, private_data::PrivateData, session_token::{Clearance, SessionToken}}, crypto::crypto_boxes::{AuthBox}};
+use common::{api::{self, ExportKey, MasterKey, private_data::PrivateData, session_token::{Clearance, SessionToken}, trusted_device_token::TrustedDeviceToken}, crypto::crypto_boxes::{AuthBox, SecretBox}};
 
 use crate::rpc_client::RpcClient;
 
@@ -12,15 +12,27 @@ pub struct Client {
     #[derivative(Debug="ignore")]
     rpc_client: RpcClient,
     user: User,
+    pending_recovery: Option<i64>, // timestamp at which a recovery login will be possible, as reported by the last login
+    device_label: String, // sent at login, helps the user recognize its sessions
+    #[derivative(Debug="ignore")]
+    session_signing_key: ed25519_dalek::SigningKey, // regenerated for each session, the session token is bound to it
+    trust_device: bool, // ask for a trusted device token when submitting a second factor
+    trusted_device_token: Option<SecretBox<TrustedDeviceToken>>, // sent at login to skip the second factor
 }
 
 #[derive(Debug)]
 pub enum User {
     None,
-    NeedSecondFactor(AuthBox<SessionToken>),
+    NeedSecondFactor(NeedSecondFactor),
     LoggedIn(LoggedIn),
 }
 
+#[derive(Debug, Clone)]
+pub struct NeedSecondFactor {
+    export_key: ExportKey, // needed to unseal the master key once the second factor is verified
+    authed_session_token: AuthBox<SessionToken>,
+}
+
 #[derive(Debug, Clone)]
 pub struct LoggedIn {
     master_key: MasterKey,
@@ -33,6 +45,11 @@ impl Default for Client {
         Self {
             rpc_client: RpcClient::new("http://127.0.0.1:8081/api"),
             user: User::None,
+            pending_recovery: None,
+            device_label: "unknown device".to_owned(),
+            session_signing_key: ed25519_dalek::SigningKey::generate(&mut rand::thread_rng()),
+            trust_device: false,
+            trusted_device_token: None,
         }
     }
 }
@@ -45,6 +62,13 @@ impl User {
         }
     }
 
+    fn get_ref_need_second_factor(&self) -> api::Result<&NeedSecondFactor> {
+        match self {
+            User::NeedSecondFactor(nsf) => Ok(nsf),
+            _ => Err(eyre::eyre!("not waiting for a second factor").into()),
+        }
+    }
+
     fn take_logged(&mut self) -> api::Result<LoggedIn> {
         let mut u = mem::replace(self, User::None);
         match u {
@@ -56,11 +80,27 @@ impl User {
         }
     }
 
+    fn get_authed_session_token(&self) -> api::Result<&AuthBox<SessionToken>> {
+        match self {
+            User::None => Err(eyre::eyre!("not logged in").into()),
+            User::NeedSecondFactor(nsf) => Ok(&nsf.authed_session_token),
+            User::LoggedIn(li) => Ok(&li.authed_session_token),
+        }
+    }
+
+    fn set_authed_session_token(&mut self, authed_session_token: AuthBox<SessionToken>) {
+        match self {
+            User::None => (),
+            User::NeedSecondFactor(nsf) => nsf.authed_session_token = authed_session_token,
+            User::LoggedIn(li) => li.authed_session_token = authed_session_token,
+        }
+    }
+
     fn get_clearance(&self) -> api::Result<Clearance> {
         Ok(
             match self {
                 User::None => Clearance::None,
-                User::NeedSecondFactor(ast) => ast.get_unverified()?.get_clearance_at_emission(), 
+                User::NeedSecondFactor(nsf) => nsf.authed_session_token.get_unverified()?.get_clearance_at_emission(),
                 User::LoggedIn(li) => li.authed_session_token.get_unverified()?.get_clearance_at_emission(),
             }
         )
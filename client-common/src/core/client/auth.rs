@@ -1,16 +1,16 @@
 use std::{iter};
 
-use common::{api::{AddUser, AddUserRet, Credentials, GetExportKeys, GetExportKeysRet, GetUserPrivateData, GetUserPrivateDataRet, LoginFinish, LoginFinishRet, LoginStart, LoginStartRet, MasterKey, NewCredentials, NewCredentialsRet, RotateMasterKey, RotateMasterKeyRet, SetCredentials, SetTotp, Totp, TotpAlgo, Username, private_data::PrivateData, session_token::{Clearance}}, consts::{OPAQUE_S_ID, OPAQUE_S_ID_RECOVERY}};
+use common::{api::{self, AddUser, AddUserRet, BeginTotpEnrollment, BeginTotpEnrollmentRet, BeginWebauthnAssertion, BeginWebauthnAssertionRet, BeginWebauthnRegistration, BeginWebauthnRegistrationRet, CancelRecovery, ChangePasswordFinish, ChangePasswordStart, ChangePasswordStartRet, ConfirmTotpEnrollment, Credentials, DeleteAccount, DeleteAccountRet, ExportKey, FinishWebauthnRegistration, GenerateBackupCodes, GenerateBackupCodesRet, GetBackupCodesCount, GetBackupCodesCountRet, GetExportKeys, GetExportKeysRet, GetUserPrivateData, GetUserPrivateDataRet, ListSessions, ListSessionsRet, ListTrustedDevices, ListTrustedDevicesRet, LoginFinish, LoginFinishRet, LoginStart, LoginStartRet, Logout, MasterKey, NewCredentials, NewCredentialsRet, ReauthenticateForUberFinish, ReauthenticateForUberFinishRet, ReauthenticateForUberStart, ReauthenticateForUberStartRet, RefreshSession, RefreshSessionRet, ResponseHeader, RevokeSession, RevokeTrustedDevice, RotateMasterKey, RotateMasterKeyRet, RpcTrait, SecondFactor, SessionId, SessionInfo, SetContactEmail, SetCredentials, SetUserPrivateData, TrustedDeviceId, TrustedDeviceInfo, UnsetTotp, UnsetWebauthn, Username, VerifyContactEmail, VerifySecondFactor, VerifySecondFactorRet, private_data::PrivateData, session_token::{Clearance, SessionToken}, trusted_device_token::TrustedDeviceToken}, consts::{OPAQUE_S_ID, OPAQUE_S_ID_RECOVERY}, recovery_key, username};
 use eyre::bail;
 use sha2::Digest;
-use common::crypto::crypto_boxes::Seal;
-use std::str::FromStr;
+use tracing::warn;
+use common::crypto::crypto_boxes::{AuthBox, Seal, SecretBox};
 
-use crate::{opaque};
-use super::{Client, LoggedIn, User};
+use crate::{opaque, webauthn::Authenticator};
+use super::{Client, LoggedIn, NeedSecondFactor, User};
 
 fn gen_recovery_credentials() -> (Vec<u8>, Vec<u8>) {
-    let password_recovery = iter::repeat_with(rand::random).take(16).collect::<Vec<_>>();
+    let password_recovery = iter::repeat_with(rand::random).take(recovery_key::KEY_LEN).collect::<Vec<_>>();
     let username_recovery = derive_username_recovery(&password_recovery);
     (username_recovery, password_recovery)
 }
@@ -19,7 +19,31 @@ fn derive_username_recovery(password_recovery: &[u8]) -> Vec<u8> {
     sha2::Sha256::digest(&password_recovery)[0..16].to_vec()
 }
 
+// recovery keys handed out before they were words are a single bs58 string
+fn decode_recovery_key(key: &str) -> eyre::Result<Vec<u8>> {
+    match key.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [legacy] => Ok(bs58::decode(legacy).into_vec()?),
+        _ => recovery_key::decode(key),
+    }
+}
+
+// usernames registered before they were validated must still be usable as typed
+fn login_username(username: &str) -> String {
+    username::normalize(username).unwrap_or_else(|_| username.to_owned())
+}
+
 impl Client {
+    // signs the request and swaps in the refreshed session token sent back with the response
+    async fn authed_call<T: RpcTrait>(&mut self, c: T) -> api::Result<T::Ret> {
+        let (ResponseHeader { refreshed_session_token }, ret) = self.rpc_client.call_authed(&self.session_signing_key, c).await?;
+
+        if let Some(authed_session_token) = refreshed_session_token {
+            self.user.set_authed_session_token(authed_session_token);
+        }
+
+        Ok(ret)
+    }
+
     async fn new_user_impl(&mut self, username: &Username, password: &[u8], username_recovery: &Username, password_recovery: &[u8]) -> eyre::Result<()> {     
         // gen master_key (long-term user private content key)
         let master_key = MasterKey::gen();
@@ -27,6 +51,7 @@ impl Client {
         // instantiate private data
         let private_data = PrivateData {
             ident_signing_key: ed25519_dalek::SigningKey::generate(&mut rand::thread_rng()),
+            username: Some(String::from_utf8(username.as_slice().to_vec())?),
         };
 
         // seal private_data with master_key
@@ -36,11 +61,14 @@ impl Client {
         let credentials_recovery = self.new_credentials_impl(&master_key, username_recovery, password_recovery, true).await?;
 
         // request a new user creation
+        self.session_signing_key = ed25519_dalek::SigningKey::generate(&mut rand::thread_rng());
         let AddUserRet {authed_session_token} = self.rpc_client.call(
             AddUser {
                 credentials,
                 credentials_recovery,
                 secret_private_data,
+                device_label: self.device_label.clone(),
+                device_public_key: self.session_signing_key.verifying_key().to_bytes().to_vec().into(),
             }
         ).await?;
 
@@ -64,6 +92,7 @@ impl Client {
             NewCredentials {
                 opaque_msg,
                 username: username.clone(),
+                recovery,
             }
         ).await?;
 
@@ -85,17 +114,17 @@ impl Client {
     }
 
     pub async fn rotate_master_key(&mut self) -> eyre::Result<()> {
-        let logged_user = self.user.get_ref_logged()?;
-
         let GetExportKeysRet {
             secret_export_key,
             secret_export_key_recovery
-        } = self.rpc_client.call(
+        } = self.authed_call(
             GetExportKeys {
-                authed_session_token: logged_user.authed_session_token.clone(),
+                authed_session_token: self.user.get_ref_logged()?.authed_session_token.clone(),
             }
         ).await?;
 
+        let logged_user = self.user.get_ref_logged()?;
+
         let export_key = secret_export_key.unseal(logged_user.master_key.as_slice())?;
         let export_key_recovery = secret_export_key_recovery.unseal(logged_user.master_key.as_slice())?;
 
@@ -115,7 +144,7 @@ impl Client {
         // this would risk writting new data encrypted with the wrong key, which would irreversibly corrupt the data...
         let logged_user  = self.user.take_logged()?;
 
-        let RotateMasterKeyRet { authed_session_token } = self.rpc_client.call(
+        let RotateMasterKeyRet { authed_session_token } = self.authed_call(
             RotateMasterKey {
                 authed_session_token: logged_user.authed_session_token.clone(),
                 secret_private_data,
@@ -143,7 +172,7 @@ impl Client {
         let credentials = self.new_credentials_impl(&logged_user.master_key, username, password, recovery).await?;
 
         // finish server-side OPAQUE registration and set credentials to user
-        self.rpc_client.call(
+        self.authed_call(
             SetCredentials {
                 recovery,
                 credentials,
@@ -169,93 +198,452 @@ impl Client {
         // finish client-side OPAQUE login
         let (opaque_msg, export_key_current) = opaque::login_finish(&opaque_state, &opaque_msg, username, if recovery { &OPAQUE_S_ID_RECOVERY } else { &OPAQUE_S_ID })?;
 
-        // finish server-side OPAQUE login
-        let LoginFinishRet {authed_session_token, secret_master_key} = self.rpc_client.call(
-            LoginFinish{secret_server_state, opaque_msg, uber_clearance, auto_logout}
+        // finish server-side OPAQUE login, binding the session to a new key
+        self.session_signing_key = ed25519_dalek::SigningKey::generate(&mut rand::thread_rng());
+        let LoginFinishRet {authed_session_token, secret_master_key, pending_recovery, account_deletion_canceled} = self.rpc_client.call(
+            LoginFinish{secret_server_state, opaque_msg, uber_clearance, auto_logout, device_label: self.device_label.clone(), device_public_key: self.session_signing_key.verifying_key().to_bytes().to_vec().into(), trusted_device_token: self.trusted_device_token.clone()}
         ).await?;
+        self.pending_recovery = pending_recovery;
+        if account_deletion_canceled {
+            warn!("the pending deletion of the account has been canceled");
+        }
 
         // check if we are logged or if we need a second factor
         self.user = match authed_session_token.get_unverified()?.get_clearance_at_emission() {
             Clearance::NeedSecondFactor => {
-                User::NeedSecondFactor( authed_session_token )
-            }
-            Clearance::LoggedIn | Clearance::Uber => {
-                // unseal master key
-                let master_key = secret_master_key
-                    .ok_or_else(|| eyre::eyre!("no master key received"))?
-                    .unseal(export_key_current.as_slice())?;
-
-                // download user private data
-                let GetUserPrivateDataRet { secret_private_data } = self.rpc_client.call(
-                    GetUserPrivateData {
-                        authed_session_token: authed_session_token.clone(),
-                    }
-                ).await?;
-
-                // recover user's private data
-                let private_data = secret_private_data.unseal(master_key.as_slice())?;
-
-                User::LoggedIn( LoggedIn {
-                    master_key,
-                    private_data,
+                User::NeedSecondFactor( NeedSecondFactor {
+                    export_key: export_key_current,
                     authed_session_token,
                 })
             }
+            Clearance::LoggedIn | Clearance::Uber => {
+                let secret_master_key = secret_master_key.ok_or_else(|| eyre::eyre!("no master key received"))?;
+                User::LoggedIn( self.logged_in_impl(authed_session_token, &secret_master_key, &export_key_current).await? )
+            }
             _ => bail!("invalid clearance")
         };
         
         Ok(())
     }
 
+    async fn reauthenticate_for_uber_impl(&mut self, username: &Username, password: &[u8], second_factor: Option<SecondFactor>) -> eyre::Result<()> {
+        let recovery = self.user.get_ref_logged()?.authed_session_token.get_unverified()?.recovery;
+
+        // start client-side OPAQUE login
+        let (opaque_state, opaque_msg) = opaque::login_start(password)?;
+
+        // start server-side OPAQUE login
+        let ReauthenticateForUberStartRet { secret_server_state, opaque_msg } = self.authed_call(
+            ReauthenticateForUberStart {
+                authed_session_token: self.user.get_ref_logged()?.authed_session_token.clone(),
+                username: username.clone(),
+                opaque_msg,
+            }
+        ).await?;
+
+        // finish client-side OPAQUE login
+        let (opaque_msg, _) = opaque::login_finish(&opaque_state, &opaque_msg, username, if recovery { &OPAQUE_S_ID_RECOVERY } else { &OPAQUE_S_ID })?;
+
+        // finish server-side OPAQUE login, our session gets uber rights
+        let ReauthenticateForUberFinishRet { authed_session_token } = self.authed_call(
+            ReauthenticateForUberFinish {
+                authed_session_token: self.user.get_ref_logged()?.authed_session_token.clone(),
+                secret_server_state,
+                opaque_msg,
+                second_factor,
+            }
+        ).await?;
+
+        self.user.set_authed_session_token(authed_session_token);
+        Ok(())
+    }
+
+    async fn logged_in_impl(&self, authed_session_token: AuthBox<SessionToken>, secret_master_key: &SecretBox<MasterKey>, export_key: &ExportKey) -> eyre::Result<LoggedIn> {
+        // unseal master key
+        let master_key = secret_master_key.unseal(export_key.as_slice())?;
+
+        // download user private data
+        let (_, GetUserPrivateDataRet { secret_private_data }) = self.rpc_client.call_authed(
+            &self.session_signing_key,
+            GetUserPrivateData {
+                authed_session_token: authed_session_token.clone(),
+            }
+        ).await?;
+
+        // recover user's private data
+        let private_data = secret_private_data.unseal(master_key.as_slice())?;
+
+        Ok( LoggedIn {
+            master_key,
+            private_data,
+            authed_session_token,
+        })
+    }
+
     // --- public functions
 
     pub fn get_clearance(&self) -> eyre::Result<Clearance> {
         Ok(self.user.get_clearance()?)
     }
 
-    pub async fn set_totp(&mut self, totp_secret: &[u8], totp_digits: u8, totp_algo: &str, totp_period: u32) -> eyre::Result<()> { 
+    // returns the otpauth:// URI to import in the user's authenticator
+    pub async fn begin_totp_enrollment(&mut self) -> eyre::Result<String> {
         let logged_user  = self.user.get_ref_logged()?;
 
-        let totp = Totp {
-            secret: totp_secret.into(),
-            digits: totp_digits,
-            algo: TotpAlgo::from_str(totp_algo)?,
-            period: totp_period,
+        // users created before their username was kept in their private data are told apart by their user_id
+        let account = match &logged_user.private_data.username {
+            Some(username) => username.clone(),
+            None => bs58::encode(logged_user.authed_session_token.get_unverified()?.user_id.as_slice()).into_string(),
         };
 
-        self.rpc_client.call(
-            SetTotp {
+        let BeginTotpEnrollmentRet { uri, .. } = self.authed_call(
+            BeginTotpEnrollment {
                 authed_session_token: logged_user.authed_session_token.clone(),
-                totp: Some(totp),
+                account,
+            }
+        ).await?;
+
+        Ok(uri)
+    }
+
+    pub async fn confirm_totp_enrollment(&mut self, totp_code: &str) -> eyre::Result<()> {
+        let logged_user  = self.user.get_ref_logged()?;
+
+        self.authed_call(
+            ConfirmTotpEnrollment {
+                authed_session_token: logged_user.authed_session_token.clone(),
+                totp_code: totp_code.to_owned(),
             }
         ).await?;
 
         Ok(())
     }
 
+    async fn verify_second_factor_impl(&mut self, second_factor: SecondFactor) -> eyre::Result<()> {
+        let need_second_factor = self.user.get_ref_need_second_factor()?;
+        let export_key = need_second_factor.export_key.clone();
+
+        let VerifySecondFactorRet { authed_session_token, secret_master_key, trusted_device_token, account_deletion_canceled } = self.authed_call(
+            VerifySecondFactor {
+                authed_session_token: need_second_factor.authed_session_token.clone(),
+                second_factor,
+                trust_device: self.trust_device,
+            }
+        ).await?;
+
+        if trusted_device_token.is_some() {
+            self.trusted_device_token = trusted_device_token;
+        }
+        if account_deletion_canceled {
+            warn!("the pending deletion of the account has been canceled");
+        }
+
+        self.user = User::LoggedIn( self.logged_in_impl(authed_session_token, &secret_master_key, &export_key).await? );
+
+        Ok(())
+    }
+
+    pub async fn submit_totp(&mut self, totp_code: &str) -> eyre::Result<Clearance> {
+        self.verify_second_factor_impl(SecondFactor::Totp(totp_code.to_owned())).await?;
+        self.get_clearance()
+    }
+
+    pub async fn submit_backup_code(&mut self, backup_code: &str) -> eyre::Result<Clearance> {
+        self.verify_second_factor_impl(SecondFactor::BackupCode(backup_code.to_owned())).await?;
+        self.get_clearance()
+    }
+
+    pub async fn submit_webauthn(&mut self, authenticator: &mut impl Authenticator) -> eyre::Result<Clearance> {
+        self.user.get_ref_need_second_factor()?;
+        let second_factor = self.get_webauthn_second_factor(authenticator).await?;
+
+        self.verify_second_factor_impl(second_factor).await?;
+        self.get_clearance()
+    }
+
+    // signs a fresh challenge, to be used with submit_* or reauthenticate_for_uber*
+    pub async fn get_webauthn_second_factor(&mut self, authenticator: &mut impl Authenticator) -> eyre::Result<SecondFactor> {
+        let BeginWebauthnAssertionRet { options } = self.authed_call(
+            BeginWebauthnAssertion {
+                authed_session_token: self.user.get_authed_session_token()?.clone(),
+            }
+        ).await?;
+
+        Ok(SecondFactor::Webauthn(authenticator.get_assertion(&options)?))
+    }
+
+    pub async fn register_webauthn(&mut self, authenticator: &mut impl Authenticator, name: &str) -> eyre::Result<()> {
+        let logged_user  = self.user.get_ref_logged()?;
+
+        let BeginWebauthnRegistrationRet { options } = self.authed_call(
+            BeginWebauthnRegistration {
+                authed_session_token: logged_user.authed_session_token.clone(),
+            }
+        ).await?;
+
+        let attestation = authenticator.make_credential(&options)?;
+
+        self.authed_call(
+            FinishWebauthnRegistration {
+                authed_session_token: self.user.get_ref_logged()?.authed_session_token.clone(),
+                name: name.to_owned(),
+                attestation,
+            }
+        ).await?;
+
+        Ok(())
+    }
+
+    pub async fn unset_webauthn(&mut self) -> eyre::Result<()> {
+        let logged_user  = self.user.get_ref_logged()?;
+
+        self.authed_call(
+            UnsetWebauthn {
+                authed_session_token: logged_user.authed_session_token.clone(),
+            }
+        ).await?;
+
+        Ok(())
+    }
+
+    pub async fn generate_backup_codes(&mut self) -> eyre::Result<Vec<String>> {
+        let logged_user  = self.user.get_ref_logged()?;
+
+        let GenerateBackupCodesRet { backup_codes } = self.authed_call(
+            GenerateBackupCodes {
+                authed_session_token: logged_user.authed_session_token.clone(),
+            }
+        ).await?;
+
+        Ok(backup_codes)
+    }
+
+    pub async fn get_backup_codes_count(&mut self) -> eyre::Result<u32> {
+        let logged_user  = self.user.get_ref_logged()?;
+
+        let GetBackupCodesCountRet { count } = self.authed_call(
+            GetBackupCodesCount {
+                authed_session_token: logged_user.authed_session_token.clone(),
+            }
+        ).await?;
+
+        Ok(count)
+    }
+
     pub async fn unset_totp(&mut self) -> eyre::Result<()> { 
         let logged_user  = self.user.get_ref_logged()?;
 
-        self.rpc_client.call(
-            SetTotp {
+        self.authed_call(
+            UnsetTotp {
                 authed_session_token: logged_user.authed_session_token.clone(),
-                totp: None,
             }
         ).await?;
 
         Ok(())
     }
  
+    pub async fn set_contact_email(&mut self, email: &str) -> eyre::Result<()> {
+        if !crate::check_email(email) {
+            bail!("invalid email");
+        }
+
+        let logged_user  = self.user.get_ref_logged()?;
+
+        self.authed_call(
+            SetContactEmail {
+                authed_session_token: logged_user.authed_session_token.clone(),
+                email: email.to_owned(),
+            }
+        ).await?;
+
+        Ok(())
+    }
+
+    pub async fn verify_contact_email(&mut self, code: &str) -> eyre::Result<()> {
+        let logged_user  = self.user.get_ref_logged()?;
+
+        self.authed_call(
+            VerifyContactEmail {
+                authed_session_token: logged_user.authed_session_token.clone(),
+                code: code.to_owned(),
+            }
+        ).await?;
+
+        Ok(())
+    }
+
+    // None for users created before it was stored
+    pub fn get_username(&self) -> eyre::Result<Option<String>> {
+        Ok(self.user.get_ref_logged()?.private_data.username.clone())
+    }
+
+    pub fn set_device_label(&mut self, device_label: &str) {
+        self.device_label = device_label.to_owned();
+    }
+
+    pub async fn list_sessions(&mut self) -> eyre::Result<Vec<SessionInfo>> {
+        let logged_user  = self.user.get_ref_logged()?;
+
+        let ListSessionsRet { sessions } = self.authed_call(
+            ListSessions {
+                authed_session_token: logged_user.authed_session_token.clone(),
+            }
+        ).await?;
+
+        Ok(sessions)
+    }
+
+    pub async fn revoke_session(&mut self, session_id: &str) -> eyre::Result<()> {
+        let logged_user  = self.user.get_ref_logged()?;
+
+        self.authed_call(
+            RevokeSession {
+                authed_session_token: logged_user.authed_session_token.clone(),
+                session_id: SessionId::from_vec(bs58::decode(session_id).into_vec()?),
+            }
+        ).await?;
+
+        Ok(())
+    }
+
+    // also done transparently by every authenticated RPC
+    pub async fn refresh_session(&mut self) -> eyre::Result<()> {
+        let RefreshSessionRet { authed_session_token } = self.authed_call(
+            RefreshSession {
+                authed_session_token: self.user.get_authed_session_token()?.clone(),
+            }
+        ).await?;
+
+        self.user.set_authed_session_token(authed_session_token);
+        Ok(())
+    }
+
+    // applies to the next second factors submitted
+    pub fn set_trust_device(&mut self, trust_device: bool) {
+        self.trust_device = trust_device;
+    }
+
+    // to be stored by the application, so that the device stays trusted across restarts
+    pub fn get_trusted_device_token(&self) -> Option<&SecretBox<TrustedDeviceToken>> {
+        self.trusted_device_token.as_ref()
+    }
+
+    pub fn set_trusted_device_token(&mut self, trusted_device_token: Option<SecretBox<TrustedDeviceToken>>) {
+        self.trusted_device_token = trusted_device_token;
+    }
+
+    pub async fn list_trusted_devices(&mut self) -> eyre::Result<Vec<TrustedDeviceInfo>> {
+        let logged_user  = self.user.get_ref_logged()?;
+
+        let ListTrustedDevicesRet { trusted_devices } = self.authed_call(
+            ListTrustedDevices {
+                authed_session_token: logged_user.authed_session_token.clone(),
+            }
+        ).await?;
+
+        Ok(trusted_devices)
+    }
+
+    pub async fn revoke_trusted_device(&mut self, trusted_device_id: &str) -> eyre::Result<()> {
+        let logged_user  = self.user.get_ref_logged()?;
+
+        self.authed_call(
+            RevokeTrustedDevice {
+                authed_session_token: logged_user.authed_session_token.clone(),
+                trusted_device_id: TrustedDeviceId::from_vec(bs58::decode(trusted_device_id).into_vec()?),
+            }
+        ).await?;
+
+        Ok(())
+    }
+
+    pub fn get_pending_recovery(&self) -> Option<i64> {
+        self.pending_recovery
+    }
+
+    pub async fn cancel_recovery(&mut self) -> eyre::Result<()> {
+        let logged_user  = self.user.get_ref_logged()?;
+
+        self.authed_call(
+            CancelRecovery {
+                authed_session_token: logged_user.authed_session_token.clone(),
+            }
+        ).await?;
+
+        self.pending_recovery = None;
+        Ok(())
+    }
+
     pub async fn signup(&mut self, username: &str, password: &str) -> eyre::Result<String> {
+        let username = username::normalize(username)?;
         let (username_recovery, password_recovery) = gen_recovery_credentials();
         self.new_user_impl(&Username::from(username), password.as_bytes(), &Username::from(username_recovery), &password_recovery).await?;
         
-        let recovery_key = bs58::encode( &password_recovery).into_string();
-        Ok(recovery_key)
+        recovery_key::encode(&password_recovery)
     }
 
     pub async fn set_username_password(&mut self, username: &str, password: &str) -> eyre::Result<()> {
-        self.set_credentials_impl(&Username::from(username), password.as_bytes(), false).await?;
+        let username = username::normalize(username)?;
+        self.set_credentials_impl(&Username::from(username.as_str()), password.as_bytes(), false).await?;
+
+        // keep the displayable copy in sync
+        let logged_user = self.user.get_ref_logged()?;
+        let mut private_data = logged_user.private_data.clone();
+        private_data.username = Some(username);
+        let secret_private_data = private_data.seal(logged_user.master_key.as_slice())?;
+
+        self.authed_call(
+            SetUserPrivateData {
+                authed_session_token: logged_user.authed_session_token.clone(),
+                secret_private_data,
+            }
+        ).await?;
+
+        if let User::LoggedIn(li) = &mut self.user {
+            li.private_data = private_data;
+        }
+
+        Ok(())
+    }
+
+    // proves the current password instead of requiring uber rights. the username and the recovery key are kept
+    pub async fn change_password(&mut self, username: &str, password: &str, new_password: &str) -> eyre::Result<()> {
+        let username = Username::from(login_username(username));
+
+        // start client-side OPAQUE login with the current password, and registration of the new one
+        let (opaque_state, opaque_msg) = opaque::login_start(password.as_bytes())?;
+        let (opaque_state_registration, opaque_msg_registration) = opaque::registration_start(new_password.as_bytes())?;
+
+        // start both server-side
+        let ChangePasswordStartRet { secret_server_state, opaque_msg, opaque_msg_registration } = self.authed_call(
+            ChangePasswordStart {
+                authed_session_token: self.user.get_ref_logged()?.authed_session_token.clone(),
+                username: username.clone(),
+                opaque_msg,
+                opaque_msg_registration,
+            }
+        ).await?;
+
+        // finish both client-side
+        let (opaque_msg, _) = opaque::login_finish(&opaque_state, &opaque_msg, &username, &OPAQUE_S_ID)?;
+        let (opaque_msg_registration, export_key) = opaque::registration_finish(&opaque_state_registration, &opaque_msg_registration, &username, &OPAQUE_S_ID)?;
+
+        // reseal master_key with the new export_key, and the other way around
+        let logged_user = self.user.get_ref_logged()?;
+        let secret_master_key = logged_user.master_key.seal(export_key.as_slice())?;
+        let secret_export_key = export_key.seal(logged_user.master_key.as_slice())?;
+
+        // finish both server-side, the new password replaces the current one
+        self.authed_call(
+            ChangePasswordFinish {
+                authed_session_token: logged_user.authed_session_token.clone(),
+                secret_server_state,
+                opaque_msg,
+                opaque_msg_registration,
+                secret_master_key,
+                secret_export_key,
+            }
+        ).await?;
 
         Ok(())
     }
@@ -264,23 +652,58 @@ impl Client {
         let (username_recovery, password_recovery) = gen_recovery_credentials();
         self.set_credentials_impl(&Username::from(username_recovery), &password_recovery, true).await?;
 
-        let recovery_key = bs58::encode( &password_recovery).into_string();
-        Ok(recovery_key)
+        recovery_key::encode(&password_recovery)
     }
 
     pub async fn login(&mut self, username: &str, password: &str, uber_clearance: bool, auto_logout: bool) -> eyre::Result<Clearance> {
-        self.login_impl(&Username::from(username), password.as_bytes(), uber_clearance, false, auto_logout).await?;
+        self.login_impl(&Username::from(login_username(username)), password.as_bytes(), uber_clearance, false, auto_logout).await?;
         self.get_clearance()
     }
 
     pub async fn login_recovery(&mut self, recovery_key: &str, uber_clearance: bool, auto_logout: bool) -> eyre::Result<Clearance> {
-        let password_recovery = bs58::decode(recovery_key).into_vec()?;
+        let password_recovery = decode_recovery_key(recovery_key)?;
         let username_recovery = derive_username_recovery(&password_recovery);
         self.login_impl(&Username::from(username_recovery), &password_recovery, uber_clearance, true, auto_logout).await?;
         self.get_clearance()
     }
 
-    pub fn logout(&mut self) {
+    // the second factor is only needed if the user enrolled one
+    pub async fn reauthenticate_for_uber(&mut self, username: &str, password: &str, second_factor: Option<SecondFactor>) -> eyre::Result<Clearance> {
+        self.reauthenticate_for_uber_impl(&Username::from(login_username(username)), password.as_bytes(), second_factor).await?;
+        self.get_clearance()
+    }
+
+    // for sessions opened with the recovery key
+    pub async fn reauthenticate_for_uber_recovery(&mut self, recovery_key: &str, second_factor: Option<SecondFactor>) -> eyre::Result<Clearance> {
+        let password_recovery = decode_recovery_key(recovery_key)?;
+        let username_recovery = derive_username_recovery(&password_recovery);
+        self.reauthenticate_for_uber_impl(&Username::from(username_recovery), &password_recovery, second_factor).await?;
+        self.get_clearance()
+    }
+
+    // the server is asked to revoke the session, but local state is cleared even if it can't be reached
+    // requires uber rights. returns the timestamp until which logging in cancels the deletion, if the server allows it
+    pub async fn delete_account(&mut self) -> eyre::Result<Option<i64>> {
+        let logged_user  = self.user.get_ref_logged()?;
+
+        let DeleteAccountRet { purge_at } = self.authed_call(
+            DeleteAccount {
+                authed_session_token: logged_user.authed_session_token.clone(),
+            }
+        ).await?;
+
+        // every session of the account has been closed
+        self.user = User::None;
+        Ok(purge_at)
+    }
+
+    pub async fn logout(&mut self) {
+        if let Ok(authed_session_token) = self.user.get_authed_session_token().cloned() {
+            if let Err(e) = self.authed_call(Logout { authed_session_token }).await {
+                warn!("failed to revoke the session on the server: {:#}", e);
+            }
+        }
+
         self.user = User::None;
     }
 }
@@ -1,12 +1,12 @@
 //#![allow(unused_imports)]
 mod rpc_client;
 pub mod core;
-mod opaque;
+pub mod opaque;
 mod hibp;
+pub mod webauthn;
 
 pub fn check_email(email: &str) -> bool {
-    // validator::validate_email(email)
-    panic!("missing")
+    validator::ValidateEmail::validate_email(&email)
 }
 
 pub fn check_password_strength(password: &str, email: &str) -> u8 {
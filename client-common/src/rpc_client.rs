@@ -1,5 +1,6 @@
-use common::api::{self, RpcTrait};
+use common::{api::{self, RequestHeader, ResponseHeader, RpcTrait, session_token::SessionProof}, clock::SystemClock};
 use eyre::WrapErr;
+use serde::{Deserialize, de::DeserializeOwned};
 use tracing::warn;
 
 #[derive(Clone)]
@@ -17,8 +18,23 @@ impl RpcClient {
     }
 
     pub async fn call<T: RpcTrait>(&self, c: T) -> api::Result<T::Ret> {
-        let c = c.into_call();
-        let body = rmp_serde::encode::to_vec_named(&c).wrap_err("Serialization error")?;
+        let rpc = rmp_serde::encode::to_vec_named(&c.into_call()).wrap_err("Serialization error")?;
+        Ok(self.send(&RequestHeader::default(), &rpc).await?.1)
+    }
+
+    // signs the request with the key the session token is bound to, and asks for the token to be refreshed
+    pub async fn call_authed<T: RpcTrait>(&self, session_signing_key: &ed25519_dalek::SigningKey, c: T) -> api::Result<(ResponseHeader, T::Ret)> {
+        let rpc = rmp_serde::encode::to_vec_named(&c.into_call()).wrap_err("Serialization error")?;
+        let header = RequestHeader {
+            refresh_session_token: true,
+            session_proof: Some(SessionProof::sign(&SystemClock, session_signing_key, &rpc)),
+        };
+        self.send(&header, &rpc).await
+    }
+
+    async fn send<R: DeserializeOwned>(&self, header: &RequestHeader, rpc: &[u8]) -> api::Result<(ResponseHeader, R)> {
+        let mut body = rmp_serde::encode::to_vec_named(header).wrap_err("Serialization error")?;
+        body.extend_from_slice(rpc);
 
         let mut retries = 1;
         let res = loop {
@@ -35,6 +51,10 @@ impl RpcClient {
         }.wrap_err("Reqwest error")?;
 
         let body = res.bytes().await.wrap_err("Body error")?;
-        rmp_serde::decode::from_slice(&body).wrap_err("Deserialization error")?
+        let mut de = rmp_serde::Deserializer::new(&body[..]);
+        let header = ResponseHeader::deserialize(&mut de).wrap_err("Deserialization error")?;
+        let ret = api::Result::<R>::deserialize(&mut de).wrap_err("Deserialization error")?;
+
+        Ok((header, ret?))
     }
 }
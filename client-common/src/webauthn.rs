@@ -0,0 +1,130 @@
+use ciborium::Value;
+use common::api::{WebauthnAssertion, WebauthnAttestation, WebauthnChallenge, WebauthnCreationOptions, WebauthnCredentialId, WebauthnRequestOptions};
+use data_encoding::BASE64URL_NOPAD;
+use ed25519_dalek::Signer;
+use eyre::{bail, eyre};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+// from https://www.iana.org/assignments/cose/cose.xhtml
+const COSE_ALG_EDDSA: i64 = -8;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+// anything able to perform the client side of the WebAuthn ceremonies: a browser, a hardware security key or our software authenticator
+pub trait Authenticator {
+    fn make_credential(&mut self, options: &WebauthnCreationOptions) -> eyre::Result<WebauthnAttestation>;
+    fn get_assertion(&mut self, options: &WebauthnRequestOptions) -> eyre::Result<WebauthnAssertion>;
+}
+
+// plays both the role of the browser and of the security key, without any user interaction.
+// credentials only live in memory: it is meant to be used by tests and by the CLI
+pub struct SoftwareAuthenticator {
+    origin: String,
+    credentials: Vec<SoftwareCredential>,
+}
+
+struct SoftwareCredential {
+    rp_id: String,
+    credential_id: WebauthnCredentialId,
+    signing_key: ed25519_dalek::SigningKey,
+    sign_count: u32,
+}
+
+impl SoftwareAuthenticator {
+    pub fn new(origin: &str) -> Self {
+        Self {
+            origin: origin.to_owned(),
+            credentials: Vec::new(),
+        }
+    }
+
+    fn client_data_json(&self, type_: &str, challenge: &WebauthnChallenge) -> eyre::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(&serde_json::json!({
+            "type": type_,
+            "challenge": BASE64URL_NOPAD.encode(challenge.as_slice()),
+            "origin": self.origin,
+            "crossOrigin": false,
+        }))?)
+    }
+}
+
+impl Authenticator for SoftwareAuthenticator {
+    fn make_credential(&mut self, options: &WebauthnCreationOptions) -> eyre::Result<WebauthnAttestation> {
+        if !options.algorithms.contains(&COSE_ALG_EDDSA) {
+            bail!("no supported algorithm");
+        }
+
+        if self.credentials.iter().any(|c| c.rp_id == options.rp_id && options.exclude_credentials.iter().any(|id| id.as_slice() == c.credential_id.as_slice())) {
+            bail!("a credential is already registered for this relying party");
+        }
+
+        let signing_key = ed25519_dalek::SigningKey::generate(&mut rand::thread_rng());
+        let credential_id = WebauthnCredentialId::from(rand::thread_rng().gen::<[u8; 16]>());
+
+        // from RFC8152: kty = OKP, alg = EdDSA, crv = Ed25519, x = public key
+        let cose_key = Value::Map(vec![
+            (Value::from(1), Value::from(1)),
+            (Value::from(3), Value::from(COSE_ALG_EDDSA)),
+            (Value::from(-1), Value::from(6)),
+            (Value::from(-2), Value::Bytes(signing_key.verifying_key().to_bytes().to_vec())),
+        ]);
+
+        let mut auth_data = Sha256::digest(options.rp_id.as_bytes()).to_vec();
+        auth_data.push(FLAG_USER_PRESENT | FLAG_ATTESTED_CREDENTIAL_DATA);
+        auth_data.extend_from_slice(&0u32.to_be_bytes()); // sign_count
+        auth_data.extend_from_slice(&[0u8; 16]); // AAGUID
+        auth_data.extend_from_slice(&(credential_id.as_slice().len() as u16).to_be_bytes());
+        auth_data.extend_from_slice(credential_id.as_slice());
+        ciborium::ser::into_writer(&cose_key, &mut auth_data).map_err(|e| eyre!("failed to serialize COSE key: {}", e))?;
+
+        let attestation_object = Value::Map(vec![
+            (Value::from("fmt"), Value::from("none")),
+            (Value::from("attStmt"), Value::Map(vec![])),
+            (Value::from("authData"), Value::Bytes(auth_data)),
+        ]);
+        let mut attestation_object_bytes = Vec::new();
+        ciborium::ser::into_writer(&attestation_object, &mut attestation_object_bytes).map_err(|e| eyre!("failed to serialize attestation object: {}", e))?;
+
+        let client_data_json = self.client_data_json("webauthn.create", &options.challenge)?;
+
+        self.credentials.push(SoftwareCredential {
+            rp_id: options.rp_id.clone(),
+            credential_id: credential_id.clone(),
+            signing_key,
+            sign_count: 0,
+        });
+
+        Ok(WebauthnAttestation {
+            credential_id,
+            client_data_json: client_data_json.into(),
+            attestation_object: attestation_object_bytes.into(),
+        })
+    }
+
+    fn get_assertion(&mut self, options: &WebauthnRequestOptions) -> eyre::Result<WebauthnAssertion> {
+        let client_data_json = self.client_data_json("webauthn.get", &options.challenge)?;
+
+        let credential = self.credentials.iter_mut()
+            .find(|c| c.rp_id == options.rp_id && options.allow_credentials.iter().any(|id| id.as_slice() == c.credential_id.as_slice()))
+            .ok_or_else(|| eyre!("no credential registered for this relying party"))?;
+
+        credential.sign_count += 1;
+
+        let mut auth_data = Sha256::digest(options.rp_id.as_bytes()).to_vec();
+        auth_data.push(FLAG_USER_PRESENT);
+        auth_data.extend_from_slice(&credential.sign_count.to_be_bytes());
+
+        let mut signed_data = auth_data.clone();
+        signed_data.extend_from_slice(&Sha256::digest(&client_data_json));
+        let signature = credential.signing_key.sign(&signed_data);
+
+        Ok(WebauthnAssertion {
+            credential_id: credential.credential_id.clone(),
+            client_data_json: client_data_json.into(),
+            authenticator_data: auth_data.into(),
+            signature: signature.to_bytes().to_vec().into(),
+        })
+    }
+}
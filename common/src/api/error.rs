@@ -7,12 +7,32 @@ pub enum Error {
 
     #[error("InvalidSessionToken")]
     InvalidSessionToken,
+    #[error("InvalidSessionProof")]
+    InvalidSessionProof, // missing, expired, replayed or not signed by the session's device key
     #[error("Conflict")]
-    Conflict,
+    Conflict, // e.g. a username already taken, or looking like one
     #[error("NotFound")]
     NotFound,
     #[error("InvalidPassword")]
     InvalidPassword,
+    #[error("InvalidSecondFactor")]
+    InvalidSecondFactor,
+    #[error("InvalidEmail")]
+    InvalidEmail,
+    #[error("InvalidUsername")]
+    InvalidUsername, // not normalized, too short or too long, or with disallowed or confusable characters
+    #[error("InvalidVerificationCode")]
+    InvalidVerificationCode,
+    #[error("InvalidServerState")]
+    InvalidServerState, // expired or issued for another RPC, the client must start over
+    #[error("RecoveryPending(retry after {retry_after}s)")]
+    RecoveryPending {
+        retry_after: u32, // seconds, the recovery can still be canceled by a normal login until then
+    },
+    #[error("RateLimited(retry after {retry_after}s)")]
+    RateLimited {
+        retry_after: u32, // seconds
+    },
 
     /* Execution errors which interrupted request processing but falls outside normal operation.
        Intentionnaly doesn't specify if expected or not, nor if client-side or server-side
@@ -2,6 +2,7 @@ mod error;
 mod rpc;
 pub mod newtypes;
 pub mod session_token;
+pub mod trusted_device_token;
 pub mod private_data;
 
 pub use error::*;
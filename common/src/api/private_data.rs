@@ -4,12 +4,15 @@ use serde::{Deserialize, Serialize};
 pub struct PrivateData {
     #[serde(with = "serde_keypair")]
     pub ident_signing_key: ed25519_dalek::SigningKey,
+    #[serde(default)] // absent for users created before it was stored
+    pub username: Option<String>, // for display, the server only stores a keyed hash of it
 }
 
 impl Clone for PrivateData {
     fn clone(&self) -> Self {
         Self {
             ident_signing_key: ed25519_dalek::SigningKey::from_bytes(&self.ident_signing_key.to_bytes()),
+            username: self.username.clone(),
         }
     }
 }
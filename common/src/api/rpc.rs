@@ -4,7 +4,7 @@ use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
 use crate::crypto::crypto_boxes::{AuthBox, SecretBox};
 
-use super::{newtypes::Bytes, private_data::PrivateData, session_token::SessionToken};
+use super::{newtypes::Bytes, private_data::PrivateData, session_token::{SessionProof, SessionToken}, trusted_device_token::TrustedDeviceToken};
 
 use strum_macros::{AsRefStr, EnumString};
 
@@ -15,6 +15,8 @@ pub enum Rpc {
     AddUser(AddUser),
     NewCredentials(NewCredentials),
     SetCredentials(SetCredentials),
+    ChangePasswordStart(ChangePasswordStart),
+    ChangePasswordFinish(ChangePasswordFinish),
 
     GetExportKeys(GetExportKeys),
     RotateMasterKey(RotateMasterKey),
@@ -25,7 +27,81 @@ pub enum Rpc {
     GetUserPrivateData(GetUserPrivateData),
     SetUserPrivateData(SetUserPrivateData),
 
-    SetTotp(SetTotp),
+    BeginTotpEnrollment(BeginTotpEnrollment),
+    ConfirmTotpEnrollment(ConfirmTotpEnrollment),
+    UnsetTotp(UnsetTotp),
+    GenerateBackupCodes(GenerateBackupCodes),
+    GetBackupCodesCount(GetBackupCodesCount),
+    BeginWebauthnRegistration(BeginWebauthnRegistration),
+    FinishWebauthnRegistration(FinishWebauthnRegistration),
+    UnsetWebauthn(UnsetWebauthn),
+    BeginWebauthnAssertion(BeginWebauthnAssertion),
+    VerifySecondFactor(VerifySecondFactor),
+    CancelRecovery(CancelRecovery),
+    SetContactEmail(SetContactEmail),
+    VerifyContactEmail(VerifyContactEmail),
+    ListSessions(ListSessions),
+    RevokeSession(RevokeSession),
+    Logout(Logout),
+    RefreshSession(RefreshSession),
+    ReauthenticateForUberStart(ReauthenticateForUberStart),
+    ReauthenticateForUberFinish(ReauthenticateForUberFinish),
+    ListTrustedDevices(ListTrustedDevices),
+    RevokeTrustedDevice(RevokeTrustedDevice),
+    DeleteAccount(DeleteAccount),
+}
+
+impl Rpc {
+    pub fn get_authed_session_token(&self) -> Option<&AuthBox<SessionToken>> {
+        match self {
+            Rpc::AddUser(_) | Rpc::NewCredentials(_) | Rpc::LoginStart(_) | Rpc::LoginFinish(_) => None,
+
+            Rpc::SetCredentials(args) => Some(&args.authed_session_token),
+            Rpc::ChangePasswordStart(args) => Some(&args.authed_session_token),
+            Rpc::ChangePasswordFinish(args) => Some(&args.authed_session_token),
+            Rpc::GetExportKeys(args) => Some(&args.authed_session_token),
+            Rpc::RotateMasterKey(args) => Some(&args.authed_session_token),
+            Rpc::GetUserPrivateData(args) => Some(&args.authed_session_token),
+            Rpc::SetUserPrivateData(args) => Some(&args.authed_session_token),
+            Rpc::BeginTotpEnrollment(args) => Some(&args.authed_session_token),
+            Rpc::ConfirmTotpEnrollment(args) => Some(&args.authed_session_token),
+            Rpc::UnsetTotp(args) => Some(&args.authed_session_token),
+            Rpc::GenerateBackupCodes(args) => Some(&args.authed_session_token),
+            Rpc::GetBackupCodesCount(args) => Some(&args.authed_session_token),
+            Rpc::BeginWebauthnRegistration(args) => Some(&args.authed_session_token),
+            Rpc::FinishWebauthnRegistration(args) => Some(&args.authed_session_token),
+            Rpc::UnsetWebauthn(args) => Some(&args.authed_session_token),
+            Rpc::BeginWebauthnAssertion(args) => Some(&args.authed_session_token),
+            Rpc::VerifySecondFactor(args) => Some(&args.authed_session_token),
+            Rpc::CancelRecovery(args) => Some(&args.authed_session_token),
+            Rpc::SetContactEmail(args) => Some(&args.authed_session_token),
+            Rpc::VerifyContactEmail(args) => Some(&args.authed_session_token),
+            Rpc::ListSessions(args) => Some(&args.authed_session_token),
+            Rpc::RevokeSession(args) => Some(&args.authed_session_token),
+            Rpc::Logout(args) => Some(&args.authed_session_token),
+            Rpc::RefreshSession(args) => Some(&args.authed_session_token),
+            Rpc::ReauthenticateForUberStart(args) => Some(&args.authed_session_token),
+            Rpc::ReauthenticateForUberFinish(args) => Some(&args.authed_session_token),
+            Rpc::ListTrustedDevices(args) => Some(&args.authed_session_token),
+            Rpc::RevokeTrustedDevice(args) => Some(&args.authed_session_token),
+            Rpc::DeleteAccount(args) => Some(&args.authed_session_token),
+        }
+    }
+}
+
+// --- Headers
+
+// serialized just before the `Rpc`
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RequestHeader {
+    pub refresh_session_token: bool, // asks for the session token of the RPC to be refreshed and sent back
+    pub session_proof: Option<SessionProof>, // required by RPCs carrying a session token
+}
+
+// serialized just before the `Result` of the RPC
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ResponseHeader {
+    pub refreshed_session_token: Option<AuthBox<SessionToken>>, // only on success, if it was asked for
 }
 
 // --- Trait
@@ -61,6 +137,24 @@ impl UserId {
     }
 }
 
+pub enum _SessionId {}
+pub type SessionId = Bytes<_SessionId>;
+
+impl SessionId {
+    pub fn gen() -> Self {
+        rand::thread_rng().gen::<[u8; 16]>().into()
+    }
+}
+
+pub enum _TrustedDeviceId {}
+pub type TrustedDeviceId = Bytes<_TrustedDeviceId>;
+
+impl TrustedDeviceId {
+    pub fn gen() -> Self {
+        rand::thread_rng().gen::<[u8; 16]>().into()
+    }
+}
+
 pub enum _Username {}
 pub type Username = Bytes<_Username>;
 
@@ -79,6 +173,42 @@ pub type ExportKey = Bytes<_ExportKey>;
 pub enum _TotpSecret {}
 pub type TotpSecret = Bytes<_TotpSecret>;
 
+impl TotpSecret {
+    pub fn gen() -> Self {
+        rand::thread_rng().gen::<[u8; 20]>().into() // 160bits as recommended by RFC4226
+    }
+}
+
+pub enum _WebauthnCredentialId {}
+pub type WebauthnCredentialId = Bytes<_WebauthnCredentialId>;
+
+pub enum _WebauthnChallenge {}
+pub type WebauthnChallenge = Bytes<_WebauthnChallenge>;
+
+impl WebauthnChallenge {
+    pub fn gen() -> Self {
+        rand::thread_rng().gen::<[u8; 32]>().into()
+    }
+}
+
+pub enum _WebauthnClientDataJson {}
+pub type WebauthnClientDataJson = Bytes<_WebauthnClientDataJson>;
+
+pub enum _WebauthnAttestationObject {}
+pub type WebauthnAttestationObject = Bytes<_WebauthnAttestationObject>;
+
+pub enum _WebauthnAuthenticatorData {}
+pub type WebauthnAuthenticatorData = Bytes<_WebauthnAuthenticatorData>;
+
+pub enum _WebauthnSignature {}
+pub type WebauthnSignature = Bytes<_WebauthnSignature>;
+
+pub enum _DevicePublicKey {}
+pub type DevicePublicKey = Bytes<_DevicePublicKey>; // ed25519, generated by the client for each session
+
+pub enum _SessionProofSignature {}
+pub type SessionProofSignature = Bytes<_SessionProofSignature>;
+
 
 // --- Standalone Structs and Enums
 
@@ -106,6 +236,66 @@ pub enum TotpAlgo {
     Sha512,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub enum SecondFactor {
+    Totp(String), // code displayed by the user's authenticator
+    BackupCode(String), // single-use code generated by GenerateBackupCodes
+    Webauthn(WebauthnAssertion), // challenge signed by a security key registered with FinishWebauthnRegistration
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionInfo {
+    pub session_id: SessionId,
+    pub device_label: String,
+    pub ip: String, // at login
+    pub created: i64,
+    pub last_seen: i64,
+    pub current: bool, // the session used to list the sessions
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TrustedDeviceInfo {
+    pub trusted_device_id: TrustedDeviceId,
+    pub device_label: String, // of the session in which the device was trusted
+    pub created: i64,
+    pub last_used: i64,
+}
+
+// subset of WebAuthn's PublicKeyCredentialCreationOptions
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WebauthnCreationOptions {
+    pub rp_id: String,
+    pub user_handle: UserId,
+    pub challenge: WebauthnChallenge,
+    pub algorithms: Vec<i64>, // COSE algorithm identifiers accepted by the server, by order of preference
+    pub exclude_credentials: Vec<WebauthnCredentialId>,
+}
+
+// subset of WebAuthn's AuthenticatorAttestationResponse
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WebauthnAttestation {
+    pub credential_id: WebauthnCredentialId,
+    pub client_data_json: WebauthnClientDataJson,
+    pub attestation_object: WebauthnAttestationObject,
+}
+
+// subset of WebAuthn's PublicKeyCredentialRequestOptions
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WebauthnRequestOptions {
+    pub rp_id: String,
+    pub challenge: WebauthnChallenge,
+    pub allow_credentials: Vec<WebauthnCredentialId>,
+}
+
+// subset of WebAuthn's AuthenticatorAssertionResponse
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WebauthnAssertion {
+    pub credential_id: WebauthnCredentialId,
+    pub client_data_json: WebauthnClientDataJson,
+    pub authenticator_data: WebauthnAuthenticatorData,
+    pub signature: WebauthnSignature,
+}
+
 // --- Rpc Structs
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -113,6 +303,8 @@ pub struct AddUser {
     pub credentials: Credentials,
     pub credentials_recovery: Credentials,
     pub secret_private_data: SecretBox<PrivateData>,
+    pub device_label: String, // shown in ListSessions
+    pub device_public_key: DevicePublicKey, // the session token will only be usable along with signatures by this key
 }
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AddUserRet {
@@ -129,7 +321,8 @@ impl RpcTrait for AddUser {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NewCredentials {
     pub opaque_msg: OpaqueClientStartMsg,
-    pub username: Username,
+    pub username: Username, // must be normalized, see common::username
+    pub recovery: bool, // recovery usernames are derived from the recovery key and aren't validated
 }
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NewCredentialsRet {
@@ -156,6 +349,42 @@ impl RpcTrait for SetCredentials {
     fn into_call(self) -> Rpc { Rpc::SetCredentials(self) }
 }
 
+// ChangePasswordStart
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChangePasswordStart {
+    pub authed_session_token: AuthBox<SessionToken>, // must have logged in rights, from a normal login
+    pub username: Username, // kept as is, the new password is registered under it
+    pub opaque_msg: OpaqueClientStartMsg, // login with the current password
+    pub opaque_msg_registration: OpaqueClientStartMsg, // registration of the new password
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChangePasswordStartRet {
+    pub secret_server_state: SecretServerState,
+    pub opaque_msg: OpaqueServerStartMsg,
+    pub opaque_msg_registration: OpaqueServerStartMsg,
+}
+impl RpcTrait for ChangePasswordStart {
+    const DISPLAY_NAME: &'static str = "ChangePasswordStart";
+    type Ret = ChangePasswordStartRet;
+    fn into_call(self) -> Rpc { Rpc::ChangePasswordStart(self) }
+}
+
+// ChangePasswordFinish
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChangePasswordFinish {
+    pub authed_session_token: AuthBox<SessionToken>, // must have logged in rights, from a normal login
+    pub secret_server_state: SecretServerState,
+    pub opaque_msg: OpaqueClientFinishMsg, // proves the current password
+    pub opaque_msg_registration: OpaqueClientFinishMsg,
+    pub secret_master_key: SecretBox<MasterKey>, // sealed with the export key of the new password
+    pub secret_export_key: SecretBox<ExportKey>, // sealed with the master key
+}
+impl RpcTrait for ChangePasswordFinish {
+    const DISPLAY_NAME: &'static str = "ChangePasswordFinish";
+    type Ret = ();
+    fn into_call(self) -> Rpc { Rpc::ChangePasswordFinish(self) }
+}
+
 // GetExportKeys
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GetExportKeys {
@@ -220,11 +449,16 @@ pub struct LoginFinish {
     pub opaque_msg: OpaqueClientFinishMsg,
     pub uber_clearance: bool,
     pub auto_logout: bool,
+    pub device_label: String, // shown in ListSessions
+    pub device_public_key: DevicePublicKey, // the session token will only be usable along with signatures by this key
+    pub trusted_device_token: Option<SecretBox<TrustedDeviceToken>>, // skips the second factor if still valid
 }
 #[derive(Serialize, Deserialize, Debug)]
 pub struct LoginFinishRet {
     pub authed_session_token: AuthBox<SessionToken>,
     pub secret_master_key: Option<SecretBox<MasterKey>>,
+    pub pending_recovery: Option<i64>, // someone logged in with the recovery key, it will be usable at this timestamp unless canceled
+    pub account_deletion_canceled: bool, // the account was about to be deleted, logging in canceled it. told by VerifySecondFactor if one is needed
 }
 impl RpcTrait for LoginFinish {
     const DISPLAY_NAME: &'static str = "LoginFinish";
@@ -259,14 +493,312 @@ impl RpcTrait for SetUserPrivateData {
     fn into_call(self) -> Rpc { Rpc::SetUserPrivateData(self) }
 }
 
-// SetTotp
+// BeginTotpEnrollment
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BeginTotpEnrollment {
+    pub authed_session_token: AuthBox<SessionToken>, // must have uber rights
+    pub account: String, // shown next to the issuer by authenticators, usually the username which the server doesn't keep
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BeginTotpEnrollmentRet {
+    pub totp: Totp,
+    pub uri: String, // otpauth:// URI to be imported in the user's authenticator
+}
+impl RpcTrait for BeginTotpEnrollment {
+    const DISPLAY_NAME: &'static str = "BeginTotpEnrollment";
+    type Ret = BeginTotpEnrollmentRet;
+    fn into_call(self) -> Rpc { Rpc::BeginTotpEnrollment(self) }
+}
+
+// ConfirmTotpEnrollment
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConfirmTotpEnrollment {
+    pub authed_session_token: AuthBox<SessionToken>, // must have uber rights
+    pub totp_code: String, // proves that the user's authenticator has correctly been set up
+}
+impl RpcTrait for ConfirmTotpEnrollment {
+    const DISPLAY_NAME: &'static str = "ConfirmTotpEnrollment";
+    type Ret = ();
+    fn into_call(self) -> Rpc { Rpc::ConfirmTotpEnrollment(self) }
+}
+
+// UnsetTotp
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UnsetTotp {
+    pub authed_session_token: AuthBox<SessionToken>, // must have uber rights
+}
+impl RpcTrait for UnsetTotp {
+    const DISPLAY_NAME: &'static str = "UnsetTotp";
+    type Ret = ();
+    fn into_call(self) -> Rpc { Rpc::UnsetTotp(self) }
+}
+
+// GenerateBackupCodes
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GenerateBackupCodes {
+    pub authed_session_token: AuthBox<SessionToken>, // must have uber rights
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GenerateBackupCodesRet {
+    pub backup_codes: Vec<String>, // replaces all previous codes, only returned once
+}
+impl RpcTrait for GenerateBackupCodes {
+    const DISPLAY_NAME: &'static str = "GenerateBackupCodes";
+    type Ret = GenerateBackupCodesRet;
+    fn into_call(self) -> Rpc { Rpc::GenerateBackupCodes(self) }
+}
+
+// GetBackupCodesCount
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetBackupCodesCount {
+    pub authed_session_token: AuthBox<SessionToken>,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetBackupCodesCountRet {
+    pub count: u32, // number of unused backup codes
+}
+impl RpcTrait for GetBackupCodesCount {
+    const DISPLAY_NAME: &'static str = "GetBackupCodesCount";
+    type Ret = GetBackupCodesCountRet;
+    fn into_call(self) -> Rpc { Rpc::GetBackupCodesCount(self) }
+}
+
+// BeginWebauthnRegistration
 #[derive(Serialize, Deserialize, Debug)]
-pub struct SetTotp {
+pub struct BeginWebauthnRegistration {
+    pub authed_session_token: AuthBox<SessionToken>, // must have uber rights
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BeginWebauthnRegistrationRet {
+    pub options: WebauthnCreationOptions,
+}
+impl RpcTrait for BeginWebauthnRegistration {
+    const DISPLAY_NAME: &'static str = "BeginWebauthnRegistration";
+    type Ret = BeginWebauthnRegistrationRet;
+    fn into_call(self) -> Rpc { Rpc::BeginWebauthnRegistration(self) }
+}
+
+// FinishWebauthnRegistration
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FinishWebauthnRegistration {
+    pub authed_session_token: AuthBox<SessionToken>, // must have uber rights
+    pub name: String, // helps the user to recognize the security key
+    pub attestation: WebauthnAttestation,
+}
+impl RpcTrait for FinishWebauthnRegistration {
+    const DISPLAY_NAME: &'static str = "FinishWebauthnRegistration";
+    type Ret = ();
+    fn into_call(self) -> Rpc { Rpc::FinishWebauthnRegistration(self) }
+}
+
+// UnsetWebauthn
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UnsetWebauthn {
+    pub authed_session_token: AuthBox<SessionToken>, // must have uber rights
+}
+impl RpcTrait for UnsetWebauthn {
+    const DISPLAY_NAME: &'static str = "UnsetWebauthn";
+    type Ret = ();
+    fn into_call(self) -> Rpc { Rpc::UnsetWebauthn(self) }
+}
+
+// BeginWebauthnAssertion
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BeginWebauthnAssertion {
+    pub authed_session_token: AuthBox<SessionToken>, // must at least have NeedSecondFactor rights
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BeginWebauthnAssertionRet {
+    pub options: WebauthnRequestOptions,
+}
+impl RpcTrait for BeginWebauthnAssertion {
+    const DISPLAY_NAME: &'static str = "BeginWebauthnAssertion";
+    type Ret = BeginWebauthnAssertionRet;
+    fn into_call(self) -> Rpc { Rpc::BeginWebauthnAssertion(self) }
+}
+
+// VerifySecondFactor
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VerifySecondFactor {
+    pub authed_session_token: AuthBox<SessionToken>, // must at least have NeedSecondFactor rights
+    pub second_factor: SecondFactor,
+    pub trust_device: bool, // to skip the second factor on the next logins from this device
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VerifySecondFactorRet {
     pub authed_session_token: AuthBox<SessionToken>,
-    pub totp: Option<Totp>,
+    pub secret_master_key: SecretBox<MasterKey>,
+    pub trusted_device_token: Option<SecretBox<TrustedDeviceToken>>, // if asked for, to be given back to LoginFinish
+    pub account_deletion_canceled: bool, // see LoginFinishRet
+}
+impl RpcTrait for VerifySecondFactor {
+    const DISPLAY_NAME: &'static str = "VerifySecondFactor";
+    type Ret = VerifySecondFactorRet;
+    fn into_call(self) -> Rpc { Rpc::VerifySecondFactor(self) }
+}
+
+// CancelRecovery
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CancelRecovery {
+    pub authed_session_token: AuthBox<SessionToken>, // must have logged in rights
+}
+impl RpcTrait for CancelRecovery {
+    const DISPLAY_NAME: &'static str = "CancelRecovery";
+    type Ret = ();
+    fn into_call(self) -> Rpc { Rpc::CancelRecovery(self) }
+}
+
+// SetContactEmail
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetContactEmail {
+    pub authed_session_token: AuthBox<SessionToken>, // must have uber rights
+    pub email: String, // a verification code is sent to this address
+}
+impl RpcTrait for SetContactEmail {
+    const DISPLAY_NAME: &'static str = "SetContactEmail";
+    type Ret = ();
+    fn into_call(self) -> Rpc { Rpc::SetContactEmail(self) }
 }
-impl RpcTrait for SetTotp {
-    const DISPLAY_NAME: &'static str = "SetTotp";
+
+// VerifyContactEmail
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VerifyContactEmail {
+    pub authed_session_token: AuthBox<SessionToken>, // must have uber rights
+    pub code: String,
+}
+impl RpcTrait for VerifyContactEmail {
+    const DISPLAY_NAME: &'static str = "VerifyContactEmail";
     type Ret = ();
-    fn into_call(self) -> Rpc { Rpc::SetTotp(self) }
-}
\ No newline at end of file
+    fn into_call(self) -> Rpc { Rpc::VerifyContactEmail(self) }
+}
+
+// ListSessions
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListSessions {
+    pub authed_session_token: AuthBox<SessionToken>, // must have logged in rights
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListSessionsRet {
+    pub sessions: Vec<SessionInfo>,
+}
+impl RpcTrait for ListSessions {
+    const DISPLAY_NAME: &'static str = "ListSessions";
+    type Ret = ListSessionsRet;
+    fn into_call(self) -> Rpc { Rpc::ListSessions(self) }
+}
+
+// RevokeSession
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RevokeSession {
+    pub authed_session_token: AuthBox<SessionToken>, // must have logged in rights
+    pub session_id: SessionId,
+}
+impl RpcTrait for RevokeSession {
+    const DISPLAY_NAME: &'static str = "RevokeSession";
+    type Ret = ();
+    fn into_call(self) -> Rpc { Rpc::RevokeSession(self) }
+}
+
+// Logout
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Logout {
+    pub authed_session_token: AuthBox<SessionToken>, // may lack the second factor
+}
+impl RpcTrait for Logout {
+    const DISPLAY_NAME: &'static str = "Logout";
+    type Ret = ();
+    fn into_call(self) -> Rpc { Rpc::Logout(self) }
+}
+
+// RefreshSession
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RefreshSession {
+    pub authed_session_token: AuthBox<SessionToken>, // may lack the second factor
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RefreshSessionRet {
+    pub authed_session_token: AuthBox<SessionToken>,
+}
+impl RpcTrait for RefreshSession {
+    const DISPLAY_NAME: &'static str = "RefreshSession";
+    type Ret = RefreshSessionRet;
+    fn into_call(self) -> Rpc { Rpc::RefreshSession(self) }
+}
+
+// ReauthenticateForUberStart
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReauthenticateForUberStart {
+    pub authed_session_token: AuthBox<SessionToken>, // must have logged in rights
+    pub username: Username, // recovery username if the session was opened with the recovery credentials
+    pub opaque_msg: OpaqueClientStartMsg,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReauthenticateForUberStartRet {
+    pub secret_server_state: SecretServerState,
+    pub opaque_msg: OpaqueServerStartMsg,
+}
+impl RpcTrait for ReauthenticateForUberStart {
+    const DISPLAY_NAME: &'static str = "ReauthenticateForUberStart";
+    type Ret = ReauthenticateForUberStartRet;
+    fn into_call(self) -> Rpc { Rpc::ReauthenticateForUberStart(self) }
+}
+
+// ReauthenticateForUberFinish
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReauthenticateForUberFinish {
+    pub authed_session_token: AuthBox<SessionToken>, // must have logged in rights
+    pub secret_server_state: SecretServerState,
+    pub opaque_msg: OpaqueClientFinishMsg,
+    pub second_factor: Option<SecondFactor>, // required if the user enrolled one
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReauthenticateForUberFinishRet {
+    pub authed_session_token: AuthBox<SessionToken>, // same session, with uber rights
+}
+impl RpcTrait for ReauthenticateForUberFinish {
+    const DISPLAY_NAME: &'static str = "ReauthenticateForUberFinish";
+    type Ret = ReauthenticateForUberFinishRet;
+    fn into_call(self) -> Rpc { Rpc::ReauthenticateForUberFinish(self) }
+}
+
+// ListTrustedDevices
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListTrustedDevices {
+    pub authed_session_token: AuthBox<SessionToken>, // must have logged in rights
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListTrustedDevicesRet {
+    pub trusted_devices: Vec<TrustedDeviceInfo>,
+}
+impl RpcTrait for ListTrustedDevices {
+    const DISPLAY_NAME: &'static str = "ListTrustedDevices";
+    type Ret = ListTrustedDevicesRet;
+    fn into_call(self) -> Rpc { Rpc::ListTrustedDevices(self) }
+}
+
+// RevokeTrustedDevice
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RevokeTrustedDevice {
+    pub authed_session_token: AuthBox<SessionToken>, // must have logged in rights
+    pub trusted_device_id: TrustedDeviceId,
+}
+impl RpcTrait for RevokeTrustedDevice {
+    const DISPLAY_NAME: &'static str = "RevokeTrustedDevice";
+    type Ret = ();
+    fn into_call(self) -> Rpc { Rpc::RevokeTrustedDevice(self) }
+}
+
+// DeleteAccount
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeleteAccount {
+    pub authed_session_token: AuthBox<SessionToken>, // must have uber rights
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeleteAccountRet {
+    pub purge_at: Option<i64>, // if the server has a grace period, a normal login cancels the deletion until this timestamp
+}
+impl RpcTrait for DeleteAccount {
+    const DISPLAY_NAME: &'static str = "DeleteAccount";
+    type Ret = DeleteAccountRet;
+    fn into_call(self) -> Rpc { Rpc::DeleteAccount(self) }
+}
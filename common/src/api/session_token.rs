@@ -1,25 +1,31 @@
 use serde::{Deserialize, Serialize};
 
-use crate::api;
+use crate::{api, clock::Clock};
 
+use ed25519_dalek::{Signer, Verifier};
 use eyre::eyre;
+use rand::Rng;
+use std::convert::TryInto;
 
-use api::{UserId};
+use api::{DevicePublicKey, SessionId, SessionProofSignature, UserId};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SessionToken {
     pub user_id: UserId,
+    pub session_id: SessionId, // identifies the session in the server's registry, so that it can be revoked
     pub version_master_key: u32,
+    pub device_public_key: DevicePublicKey, // requests using this token must be signed by the matching private key
     
     timestamp: i64, // user validated first factor at timestamp
     age: u32, // last refreshed N seconds after timestamp
     
     auto_logout: bool, // user want to auto logout
     lack_second_factor: bool,
+    pub recovery: bool, // user logged in with its recovery credentials
     uber: Option<u32>, // got uber rights at timestamp + uber.0
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum Clearance {
     None,
     NeedSecondFactor, // the user identified with one factor but his account requires a second one
@@ -28,12 +34,16 @@ pub enum Clearance {
 }
 
 impl SessionToken {
-    pub fn new(user_id: UserId, version_master_key: u32, lack_second_factor: bool, auto_logout: bool, uber: bool) -> Self {   
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(clock: &dyn Clock, user_id: UserId, version_master_key: u32, device_public_key: DevicePublicKey, lack_second_factor: bool, recovery: bool, auto_logout: bool, uber: bool) -> Self {
         SessionToken {
             user_id,
+            session_id: SessionId::gen(),
             version_master_key,
+            device_public_key,
             lack_second_factor,
-            timestamp: chrono::Utc::now().timestamp(),
+            recovery,
+            timestamp: clock.now(),
             age: 0,
             auto_logout,
             uber: if uber { Some(0) } else { None },
@@ -70,11 +80,12 @@ impl SessionToken {
     pub fn refresh_to(&mut self, time: i64, uber_duration: u32) {
         self.age = (time - self.timestamp) as u32;
 
-        self.uber.filter(|offset| { (self.timestamp + *offset as i64 + uber_duration as i64) < time });
+        // uber rights can't be regained by refreshing, they must be dropped once expired
+        self.uber = self.uber.filter(|offset| time <= self.timestamp + *offset as i64 + uber_duration as i64);
     }
 
-    pub fn adjusted_now(&self) -> api::Result<i64> {
-        let now = chrono::Utc::now().timestamp();
+    pub fn adjusted_now(&self, clock: &dyn Clock) -> api::Result<i64> {
+        let now = clock.now();
         if now + 5 > self.timestamp { // allow up to 5 seconds of desynchronization between servers
             Ok(now.max(self.timestamp)) // adjust to avoid negative offsets
         } else {
@@ -82,6 +93,11 @@ impl SessionToken {
         }
     }
 
+    // no clearance can be granted past that time, whatever is later done to the token
+    pub fn get_expiration(&self, one_factor_duration: u32, logged_duration: u32) -> i64 {
+        self.timestamp + one_factor_duration.max(logged_duration) as i64
+    }
+
     pub fn get_clearance_at_emission(&self) -> Clearance {
         match (self.lack_second_factor, self.uber) {
               (true                   , _        ) => Clearance::NeedSecondFactor,
@@ -90,10 +106,57 @@ impl SessionToken {
         }
     }
 
-    pub fn add_uber(&mut self) -> api::Result<()> {
-        let elapsed_time = self.adjusted_now()? - self.timestamp;
+    pub fn add_second_factor(&mut self) {
+        self.lack_second_factor = false;
+    }
+
+    pub fn add_uber(&mut self, clock: &dyn Clock) -> api::Result<()> {
+        let elapsed_time = self.adjusted_now(clock)? - self.timestamp;
         self.uber = Some(elapsed_time as u32);
         Ok(())
     }
 }
 
+// proves that a request comes from the device a session token has been issued to
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionProof {
+    pub timestamp: i64, // requests are only accepted for a short time
+    pub nonce: [u8; 16], // and only once
+    pub signature: SessionProofSignature,
+}
+
+impl SessionProof {
+    fn message(timestamp: i64, nonce: &[u8; 16], rpc: &[u8]) -> Vec<u8> {
+        let mut message = b"session_proof".to_vec();
+        message.extend_from_slice(&timestamp.to_be_bytes());
+        message.extend_from_slice(nonce);
+        message.extend_from_slice(rpc);
+        message
+    }
+
+    // `rpc` is the serialized `Rpc`, as sent
+    pub fn sign(clock: &dyn Clock, signing_key: &ed25519_dalek::SigningKey, rpc: &[u8]) -> Self {
+        let timestamp = clock.now();
+        let nonce = rand::thread_rng().gen::<[u8; 16]>();
+        let signature = signing_key.sign(&Self::message(timestamp, &nonce, rpc));
+
+        Self {
+            timestamp,
+            nonce,
+            signature: signature.to_bytes().to_vec().into(),
+        }
+    }
+
+    // requests signed more than `max_skew_sec` ago, or that far in the future, are refused
+    pub fn verify(&self, clock: &dyn Clock, max_skew_sec: u32, device_public_key: &DevicePublicKey, rpc: &[u8]) -> api::Result<()> {
+        if (clock.now() - self.timestamp).abs() > max_skew_sec as i64 {
+            return Err(api::Error::InvalidSessionProof);
+        }
+
+        let device_public_key: [u8; 32] = device_public_key.as_slice().try_into().map_err(|_| api::Error::InvalidSessionProof)?;
+        let device_public_key = ed25519_dalek::VerifyingKey::from_bytes(&device_public_key).map_err(|_| api::Error::InvalidSessionProof)?;
+        let signature = ed25519_dalek::Signature::from_slice(self.signature.as_slice()).map_err(|_| api::Error::InvalidSessionProof)?;
+
+        device_public_key.verify(&Self::message(self.timestamp, &self.nonce, rpc), &signature).map_err(|_| api::Error::InvalidSessionProof)
+    }
+}
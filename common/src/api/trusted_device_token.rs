@@ -0,0 +1,29 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{api, clock::Clock};
+
+use api::{TrustedDeviceId, UserId};
+
+// lets a device skip the second factor at login, issued once a second factor has been verified on it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TrustedDeviceToken {
+    pub user_id: UserId,
+    pub trusted_device_id: TrustedDeviceId, // identifies the device in the server's registry, so that it can be revoked
+    pub version_master_key: u32, // rotating the master key untrusts every device
+    timestamp: i64, // second factor verified at timestamp
+}
+
+impl TrustedDeviceToken {
+    pub fn new(clock: &dyn Clock, user_id: UserId, version_master_key: u32) -> Self {
+        TrustedDeviceToken {
+            user_id,
+            trusted_device_id: TrustedDeviceId::gen(),
+            version_master_key,
+            timestamp: clock.now(),
+        }
+    }
+
+    pub fn is_valid_at(&self, time: i64, trusted_device_duration: u32) -> bool {
+        time <= self.timestamp + trusted_device_duration as i64
+    }
+}
@@ -12,23 +12,33 @@ use crypto_common::KeyInit;
 // from RFC6238
 pub fn check_totp(uri: &str, input: &str) -> eyre::Result<()> {
     let (secret, digits, algo, period) = parse_totp_uri(uri)?;
-
     let time = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH).unwrap()
         .as_secs();
 
+    check_totp_code(&secret, digits, &algo, period, input, None, time)?;
+    Ok(())
+}
+
+// returns the HOTP counter matching the input at `time`, a unix timestamp.
+// counters lower or equal to `last_counter` are never accepted so that a code can only be used once
+pub fn check_totp_code(secret: &[u8], digits: u8, algo: &str, period: u32, input: &str, last_counter: Option<u64>, time: u64) -> eyre::Result<u64> {
     let counter = time / period as u64;
 
     for c in counter - 1 ..= counter {
-        let totp = match algo.as_str() {
-            "SHA1" => hotp::<Hmac<Sha1>>(&secret, digits, c),
-            "SHA256" => hotp::<Hmac<Sha256>>(&secret, digits, c),
-            "SHA512" => hotp::<Hmac<Sha512>>(&secret, digits, c),
+        if last_counter.is_some_and(|l| c <= l) {
+            continue
+        }
+
+        let totp = match algo {
+            "SHA1" => hotp::<Hmac<Sha1>>(secret, digits, c),
+            "SHA256" => hotp::<Hmac<Sha256>>(secret, digits, c),
+            "SHA512" => hotp::<Hmac<Sha512>>(secret, digits, c),
             _ => bail!("unknown algorithm {:?}", algo),
         }?;
 
         if totp == input {
-            return Ok(())
+            return Ok(c)
         }
     }
 
@@ -88,3 +98,20 @@ pub fn parse_totp_uri(uri: &str) -> eyre::Result<(Vec<u8>, u8, String, u32)> {
     Ok((secret, digits, algo, period))
 }
 
+
+// from https://github.com/google/google-authenticator/wiki/Key-Uri-Format
+// the label is `issuer:account` so that authenticators can tell apart several accounts of the same issuer
+pub fn build_totp_uri(issuer: &str, account: &str, secret: &[u8], digits: u8, algo: &str, period: u32) -> eyre::Result<String> {
+    let mut uri = Url::parse("otpauth://totp/")
+        .wrap_err("failed to parse url")?;
+
+    uri.set_path(&format!("{}:{}", issuer, account));
+    uri.query_pairs_mut()
+        .append_pair("secret", &BASE32_NOPAD.encode(secret))
+        .append_pair("issuer", issuer)
+        .append_pair("digits", &digits.to_string())
+        .append_pair("algorithm", algo)
+        .append_pair("period", &period.to_string());
+
+    Ok(uri.into())
+}
@@ -2,4 +2,7 @@
 
 pub mod api;
 pub mod crypto;
-pub mod consts;
\ No newline at end of file
+pub mod consts;
+pub mod clock;
+pub mod username;
+pub mod recovery_key;
\ No newline at end of file
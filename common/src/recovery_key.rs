@@ -0,0 +1,93 @@
+use eyre::ensure;
+use sha2::Digest;
+
+mod english;
+mod reed_solomon;
+
+pub use english::WORDLIST;
+
+pub const KEY_LEN: usize = 16; // bytes
+const DATA_WORDS: usize = 12; // the key and a 4 bits checksum, 11 bits per word, as a BIP39 mnemonic
+const PARITY_WORDS: usize = 4; // Reed-Solomon, fixes up to 2 wrong words or 4 missing ones
+pub const WORDS: usize = DATA_WORDS + PARITY_WORDS;
+
+pub const MISSING_WORD: &str = "?"; // stands for a word that can't be read anymore
+
+// the first 12 words are the BIP39 mnemonic of the key, the last 4 the parity
+pub fn encode(key: &[u8]) -> eyre::Result<String> {
+    ensure!(key.len() == KEY_LEN, "a recovery key is {} bytes long", KEY_LEN);
+
+    let mut data = Vec::with_capacity(WORDS);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    // only the first 4 bits of the last byte fit in the data words
+    for &byte in key.iter().chain(&[checksum(key)]) {
+        acc = acc << 8 | byte as u32;
+        bits += 8;
+        if bits >= 11 {
+            bits -= 11;
+            data.push((acc >> bits) as u16 & 0x7ff);
+            acc &= (1 << bits) - 1;
+        }
+    }
+
+    let parity = reed_solomon::encode(&data, PARITY_WORDS);
+
+    Ok(data.iter().chain(&parity).map(|&w| WORDLIST[w as usize]).collect::<Vec<_>>().join(" "))
+}
+
+// case insensitive. a word can be shortened to its first 4 letters.
+// unknown words and MISSING_WORD are corrected like missing ones, a wrong word costs as much as two missing ones
+pub fn decode(phrase: &str) -> eyre::Result<Vec<u8>> {
+    let words = phrase.split_whitespace().collect::<Vec<_>>();
+    ensure!(words.len() == WORDS, "a recovery key has {} words, not {}", WORDS, words.len());
+
+    let mut codeword = vec![0; WORDS];
+    let mut erasures = vec![];
+    for (i, word) in words.iter().enumerate() {
+        match word_index(word) {
+            Some(w) => codeword[i] = w,
+            None => erasures.push(i),
+        }
+    }
+
+    reed_solomon::correct(&mut codeword, PARITY_WORDS, &erasures)
+        .map_err(|_| eyre::eyre!("too many wrong or missing words in the recovery key"))?;
+
+    let mut key = Vec::with_capacity(KEY_LEN);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for &w in &codeword[..DATA_WORDS] {
+        acc = acc << 11 | w as u32;
+        bits += 11;
+        while bits >= 8 && key.len() < KEY_LEN {
+            bits -= 8;
+            key.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+
+    // what's left is the checksum, catching corrections which went wrong
+    ensure!(acc as u8 == checksum(&key) >> 4, "invalid recovery key");
+
+    Ok(key)
+}
+
+fn checksum(key: &[u8]) -> u8 {
+    sha2::Sha256::digest(key)[0]
+}
+
+fn word_index(word: &str) -> Option<u16> {
+    let word = word.to_lowercase();
+
+    if let Ok(i) = WORDLIST.binary_search(&word.as_str()) {
+        return Some(i as u16);
+    }
+
+    // the first 4 letters are unique to each word
+    let prefix = word.chars().take(4).collect::<String>();
+    if prefix.chars().count() < 4 {
+        return None;
+    }
+    WORDLIST.iter().position(|w| w.starts_with(&prefix)).map(|i| i as u16)
+}
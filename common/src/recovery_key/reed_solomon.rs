@@ -0,0 +1,174 @@
+// Reed-Solomon over GF(2^11), so that each word of the wordlist is exactly one symbol.
+// codewords are in reading order: the first symbol is the coefficient of the highest degree.
+// the generator's roots are α^0..α^(parity-1)
+
+use eyre::{bail, ensure};
+
+const GF_SIZE: usize = 1 << 11;
+const GF_ORDER: usize = GF_SIZE - 1; // of the multiplicative group
+const GF_POLY: usize = 0x805; // x^11 + x^2 + 1, primitive
+
+const fn gf_tables() -> ([u16; 2 * GF_SIZE], [u16; GF_SIZE]) {
+    let mut exp = [0u16; 2 * GF_SIZE];
+    let mut log = [0u16; GF_SIZE];
+
+    let mut x = 1;
+    let mut i = 0;
+    while i < GF_ORDER {
+        exp[i] = x as u16;
+        log[x] = i as u16;
+        x <<= 1;
+        if x & GF_SIZE != 0 {
+            x ^= GF_POLY;
+        }
+        i += 1;
+    }
+
+    // so that the sum of two logs can index it directly
+    while i < 2 * GF_SIZE {
+        exp[i] = exp[i - GF_ORDER];
+        i += 1;
+    }
+
+    (exp, log)
+}
+
+const GF_EXP: [u16; 2 * GF_SIZE] = gf_tables().0;
+const GF_LOG: [u16; GF_SIZE] = gf_tables().1;
+
+fn mul(a: u16, b: u16) -> u16 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    GF_EXP[GF_LOG[a as usize] as usize + GF_LOG[b as usize] as usize]
+}
+
+fn inv(a: u16) -> u16 {
+    GF_EXP[GF_ORDER - GF_LOG[a as usize] as usize]
+}
+
+fn alpha_pow(e: usize) -> u16 {
+    GF_EXP[e % GF_ORDER]
+}
+
+// polynomials below are in ascending order: the first coefficient is the constant one
+
+fn poly_eval(p: &[u16], x: u16) -> u16 {
+    p.iter().rev().fold(0, |acc, &c| mul(acc, x) ^ c)
+}
+
+fn poly_mul(p: &[u16], q: &[u16]) -> Vec<u16> {
+    let mut r = vec![0; p.len() + q.len() - 1];
+    for (i, &a) in p.iter().enumerate() {
+        for (j, &b) in q.iter().enumerate() {
+            r[i + j] ^= mul(a, b);
+        }
+    }
+    r
+}
+
+// p + c·x·q
+fn poly_add_shifted(p: &[u16], c: u16, q: &[u16]) -> Vec<u16> {
+    let mut r = p.to_vec();
+    r.resize(r.len().max(q.len() + 1), 0);
+    for (i, &b) in q.iter().enumerate() {
+        r[i + 1] ^= mul(c, b);
+    }
+    r
+}
+
+fn generator(parity: usize) -> Vec<u16> {
+    (0..parity).fold(vec![1], |g, j| poly_mul(&g, &[alpha_pow(j), 1]))
+}
+
+// the locator of the symbol at `i` in a codeword of `n` symbols
+fn locator(n: usize, i: usize) -> u16 {
+    alpha_pow(n - 1 - i)
+}
+
+fn compute_syndromes(codeword: &[u16], parity: usize) -> Vec<u16> {
+    (0..parity)
+        .map(|j| codeword.iter().fold(0, |acc, &c| mul(acc, alpha_pow(j)) ^ c))
+        .collect()
+}
+
+// returns the parity symbols to append to `data`
+pub(super) fn encode(data: &[u16], parity: usize) -> Vec<u16> {
+    let g = generator(parity);
+
+    // remainder of data·x^parity divided by the generator
+    let mut rem = vec![0; parity];
+    for &d in data {
+        let feedback = d ^ rem[parity - 1];
+        for j in (1..parity).rev() {
+            rem[j] = rem[j - 1] ^ mul(feedback, g[j]);
+        }
+        rem[0] = mul(feedback, g[0]);
+    }
+
+    rem.into_iter().rev().collect()
+}
+
+// fixes up to `parity` erased symbols, whose indexes are known, and half as many wrong ones, whose indexes aren't.
+// each wrong symbol costs as much as two erased ones
+pub(super) fn correct(codeword: &mut [u16], parity: usize, erasures: &[usize]) -> eyre::Result<()> {
+    let n = codeword.len();
+    ensure!(erasures.len() <= parity, "too many missing symbols");
+
+    let syndromes = compute_syndromes(codeword, parity);
+    if syndromes.iter().all(|&s| s == 0) {
+        return Ok(());
+    }
+
+    // Berlekamp-Massey, seeded with the locator of the erasures so that it finds the locator of all errata
+    let rho = erasures.len();
+    let mut lambda = erasures.iter().fold(vec![1], |l, &i| poly_mul(&l, &[1, locator(n, i)]));
+    let mut b = lambda.clone();
+    let mut l = rho;
+    for r in rho + 1..=parity {
+        let delta = lambda.iter().enumerate()
+            .filter(|&(j, _)| j < r)
+            .fold(0, |acc, (j, &c)| acc ^ mul(c, syndromes[r - 1 - j]));
+
+        if delta == 0 {
+            b.insert(0, 0);
+        } else if 2 * l < r + rho {
+            let t = poly_add_shifted(&lambda, delta, &b);
+            b = lambda.iter().map(|&c| mul(c, inv(delta))).collect();
+            l = r + rho - l;
+            lambda = t;
+        } else {
+            lambda = poly_add_shifted(&lambda, delta, &b);
+            b.insert(0, 0);
+        }
+    }
+    while lambda.last() == Some(&0) {
+        lambda.pop();
+    }
+    let degree = lambda.len() - 1;
+    ensure!(2 * (degree.max(rho) - rho) + rho <= parity, "too many wrong symbols");
+
+    // Chien search: the errata are where the locator has a root at the inverse of their locator
+    let positions = (0..n).filter(|&i| poly_eval(&lambda, inv(locator(n, i))) == 0).collect::<Vec<_>>();
+    ensure!(positions.len() == degree, "too many wrong symbols");
+
+    // Forney: the evaluator is S·Λ mod x^parity, and the formal derivative only keeps odd terms in characteristic 2
+    let mut omega = poly_mul(&syndromes, &lambda);
+    omega.truncate(parity);
+    let lambda_prime = lambda.iter().enumerate().skip(1)
+        .map(|(i, &c)| if i % 2 == 1 { c } else { 0 })
+        .collect::<Vec<_>>();
+
+    for i in positions {
+        let x = locator(n, i);
+        let denominator = poly_eval(&lambda_prime, inv(x));
+        if denominator == 0 {
+            bail!("too many wrong symbols");
+        }
+        codeword[i] ^= mul(x, mul(poly_eval(&omega, inv(x)), inv(denominator)));
+    }
+
+    // a pattern beyond what can be corrected may still have led to a wrong codeword
+    ensure!(compute_syndromes(codeword, parity).iter().all(|&s| s == 0), "too many wrong symbols");
+    Ok(())
+}
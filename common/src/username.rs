@@ -0,0 +1,55 @@
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::{RestrictionLevel, RestrictionLevelDetection, skeleton};
+
+use crate::api;
+
+pub const MIN_LEN: usize = 3; // characters
+pub const MAX_LEN: usize = 32; // bytes, as stored in the database
+
+// OPAQUE and the database compare usernames byte for byte, so the client must use the normalized form everywhere.
+// returns it, or InvalidUsername.
+// only the name itself is checked here, lookalikes of other users' names are refused by the server, see `skeletons`
+pub fn normalize(username: &str) -> api::Result<String> {
+    let username = username.trim().nfkc().collect::<String>();
+
+    if username.chars().count() < MIN_LEN || username.len() > MAX_LEN {
+        return Err(api::Error::InvalidUsername);
+    }
+
+    if !username.starts_with(char::is_alphanumeric) || !username.chars().all(|c| c.is_alphanumeric() || "._-".contains(c)) {
+        return Err(api::Error::InvalidUsername);
+    }
+
+    // characters unfit for identifiers, and homoglyphs mixed with another script, like a cyrillic 'а' in a latin name
+    if !username.as_str().check_restriction_level(RestrictionLevel::HighlyRestrictive) {
+        return Err(api::Error::InvalidUsername);
+    }
+
+    // whole names written in another script to look like an ascii one, like a cyrillic "асе"
+    if !username.is_ascii() && skeleton(&username).all(|c| c.is_ascii()) {
+        return Err(api::Error::InvalidUsername);
+    }
+
+    Ok(username)
+}
+
+// for the server, which must only accept already normalized usernames
+pub fn validate(username: &[u8]) -> api::Result<()> {
+    let username = std::str::from_utf8(username).map_err(|_| api::Error::InvalidUsername)?;
+
+    if normalize(username)? != username {
+        return Err(api::Error::InvalidUsername);
+    }
+    Ok(())
+}
+
+// two usernames which look alike, like "rn" and "m", "paypa1" and "paypal", or "alIce" and "alice", share a skeleton.
+// the UTS 39 skeleton tells "I" and "l" apart from "i", so the one of the lowercase name is given too
+pub fn skeletons(username: &str) -> Vec<String> {
+    let mut skeletons = vec![
+        skeleton(username).collect::<String>(),
+        skeleton(&username.to_lowercase()).collect::<String>(),
+    ];
+    skeletons.dedup();
+    skeletons
+}
@@ -0,0 +1,114 @@
+use common::recovery_key::{self, KEY_LEN, MISSING_WORD, WORDLIST, WORDS};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+// entropy and mnemonic, from the BIP39 test vectors
+#[rustfmt::skip]
+const BIP39_VECTORS: &[([u8; KEY_LEN], &str)] = &[
+    ([0x00; KEY_LEN], "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about"),
+    ([0x7f; KEY_LEN], "legal winner thank year wave sausage worth useful legal winner thank yellow"),
+    ([0x80; KEY_LEN], "letter advice cage absurd amount doctor acoustic avoid letter advice cage above"),
+    ([0xff; KEY_LEN], "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo wrong"),
+];
+
+fn keys() -> impl Iterator<Item = [u8; KEY_LEN]> {
+    let mut rng = StdRng::seed_from_u64(0);
+    BIP39_VECTORS.iter().map(|(key, _)| *key).chain(std::iter::repeat_with(move || rng.gen()).take(100))
+}
+
+// replaces the words at `wrong` by another word, and the ones at `missing` by MISSING_WORD
+fn damage(phrase: &str, wrong: &[usize], missing: &[usize]) -> String {
+    phrase.split(' ').enumerate()
+        .map(|(i, word)| {
+            if missing.contains(&i) {
+                MISSING_WORD.to_owned()
+            } else if wrong.contains(&i) {
+                let index = WORDLIST.binary_search(&word).unwrap();
+                WORDLIST[(index + 1 + i) % WORDLIST.len()].to_owned()
+            } else {
+                word.to_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[test]
+fn wordlist() {
+    assert_eq!(WORDLIST.len(), 2048);
+    assert!(WORDLIST.windows(2).all(|w| w[0] < w[1]), "not sorted");
+    let mut prefixes = WORDLIST.iter().map(|w| &w[..w.len().min(4)]).collect::<Vec<_>>();
+    prefixes.dedup();
+    assert_eq!(prefixes.len(), WORDLIST.len(), "prefixes aren't unique");
+}
+
+#[test]
+fn starts_with_the_bip39_mnemonic() {
+    for (key, mnemonic) in BIP39_VECTORS {
+        let phrase = recovery_key::encode(key).unwrap();
+        assert!(phrase.starts_with(&format!("{} ", mnemonic)), "{}", phrase);
+        assert_eq!(phrase.split(' ').count(), WORDS);
+    }
+}
+
+#[test]
+fn roundtrip() {
+    for key in keys() {
+        let phrase = recovery_key::encode(&key).unwrap();
+        assert_eq!(recovery_key::decode(&phrase).unwrap(), key, "{}", phrase);
+    }
+}
+
+#[test]
+fn lenient_input() {
+    let key = [0x7f; KEY_LEN];
+    let phrase = recovery_key::encode(&key).unwrap();
+
+    let shouted = format!("  {}\n", phrase.to_uppercase().replace(' ', "\t "));
+    assert_eq!(recovery_key::decode(&shouted).unwrap(), key);
+
+    let shortened = phrase.split(' ').map(|w| &w[..w.len().min(4)]).collect::<Vec<_>>().join(" ");
+    assert_eq!(recovery_key::decode(&shortened).unwrap(), key);
+
+    // misspelled after the first 4 letters
+    let misspelled = phrase.replacen("legal", "legol", 1);
+    assert_eq!(recovery_key::decode(&misspelled).unwrap(), key);
+}
+
+#[test]
+fn corrects_wrong_and_missing_words() {
+    #[rustfmt::skip]
+    let patterns: &[(&[usize], &[usize])] = &[
+        // wrong        missing
+        (&[0],          &[]),
+        (&[15],         &[]),
+        (&[3, 11],      &[]),
+        (&[12, 13],     &[]),
+        (&[],           &[0, 1, 2, 3]),
+        (&[],           &[5, 9, 12, 15]),
+        (&[7],          &[0, 14]),
+        (&[],           &[4]),
+    ];
+
+    for key in keys() {
+        let phrase = recovery_key::encode(&key).unwrap();
+        for (wrong, missing) in patterns {
+            let damaged = damage(&phrase, wrong, missing);
+            assert_eq!(recovery_key::decode(&damaged).unwrap(), key, "{}", damaged);
+        }
+    }
+}
+
+#[test]
+fn rejects_beyond_correction() {
+    let phrase = recovery_key::encode(&[0x80; KEY_LEN]).unwrap();
+
+    assert!(recovery_key::decode(&damage(&phrase, &[], &[0, 1, 2, 3, 4])).is_err());
+    assert!(recovery_key::decode(&damage(&phrase, &[5], &[0, 1, 2])).is_err());
+
+    // words can't be told apart once their position is lost
+    let truncated = phrase.split(' ').skip(1).collect::<Vec<_>>().join(" ");
+    assert!(recovery_key::decode(&truncated).is_err());
+    assert!(recovery_key::decode(&format!("{} zoo", phrase)).is_err());
+
+    assert!(recovery_key::encode(&[0; KEY_LEN - 1]).is_err());
+}
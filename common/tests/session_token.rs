@@ -0,0 +1,268 @@
+use common::{api::{self, DevicePublicKey, UserId, session_token::{Clearance, SessionProof, SessionToken}}, clock::ManualClock};
+
+const T0: i64 = 1_600_000_000;
+
+// all distinct so that each expiration can be told apart
+const ONE_FACTOR: u32 = 60;
+const LOGGED: u32 = 1000;
+const AUTO_LOGOUT: u32 = 100;
+const UBER: u32 = 30;
+
+#[derive(Clone, Copy)]
+enum Event {
+    Refresh(i64), // at T0 + offset
+    AddSecondFactor,
+    AddUber(i64), // at T0 + offset
+}
+
+struct Case {
+    name: &'static str,
+    lack_second_factor: bool,
+    auto_logout: bool,
+    uber: bool,
+    events: &'static [Event],
+    at: i64, // offset from T0
+    expected: Clearance,
+}
+
+const fn case(name: &'static str, lack_second_factor: bool, auto_logout: bool, uber: bool, events: &'static [Event], at: i64, expected: Clearance) -> Case {
+    Case { name, lack_second_factor, auto_logout, uber, events, at, expected }
+}
+
+use Clearance::{LoggedIn, NeedSecondFactor, None as NoClearance, Uber};
+use Event::*;
+
+const O: i64 = ONE_FACTOR as i64;
+const L: i64 = LOGGED as i64;
+const A: i64 = AUTO_LOGOUT as i64;
+const U: i64 = UBER as i64;
+
+#[rustfmt::skip]
+const CASES: &[Case] = &[
+    //    name                                           no 2fa auto   uber   events                                  at          expected
+    case("logged in at emission",                        false, false, false, &[],                                    0,          LoggedIn),
+    case("logged in until logged duration",              false, false, false, &[],                                    L,          LoggedIn),
+    case("logged in expires",                            false, false, false, &[],                                    L + 1,      NoClearance),
+    case("refreshing doesn't extend logged duration",    false, false, false, &[Refresh(L - 1)],                      L + 1,      NoClearance),
+
+    case("need second factor at emission",               true,  false, false, &[],                                    0,          NeedSecondFactor),
+    case("need second factor until one factor duration", true,  false, false, &[],                                    O,          NeedSecondFactor),
+    case("need second factor expires",                   true,  false, false, &[],                                    O + 1,      NoClearance),
+    case("need second factor isn't extended by refresh", true,  false, false, &[Refresh(O - 1)],                      O + 1,      NoClearance),
+    case("second factor added",                          true,  false, false, &[AddSecondFactor],                     0,          LoggedIn),
+    case("second factor added outlives one factor",      true,  false, false, &[AddSecondFactor],                     O + 1,      LoggedIn),
+    case("second factor added expires",                  true,  false, false, &[AddSecondFactor],                     L + 1,      NoClearance),
+    case("uber at login needs second factor first",      true,  false, true,  &[],                                    0,          NeedSecondFactor),
+    case("uber at login after second factor",            true,  false, true,  &[AddSecondFactor],                     U,          Uber),
+    case("uber at login after second factor expires",    true,  false, true,  &[AddSecondFactor],                     U + 1,      LoggedIn),
+
+    case("uber at emission",                             false, false, true,  &[],                                    0,          Uber),
+    case("uber until uber duration",                     false, false, true,  &[],                                    U,          Uber),
+    case("uber decays to logged in",                     false, false, true,  &[],                                    U + 1,      LoggedIn),
+    case("uber decayed then expires",                    false, false, true,  &[],                                    L + 1,      NoClearance),
+    case("uber kept by refresh within duration",         false, false, true,  &[Refresh(U)],                          U,          Uber),
+    case("uber dropped by refresh after duration",       false, false, true,  &[Refresh(U + 1)],                      U + 1,      LoggedIn),
+    case("uber not extended by refresh",                 false, false, true,  &[Refresh(U - 1)],                      U + 1,      LoggedIn),
+    case("uber added later",                             false, false, false, &[AddUber(200)],                        200,        Uber),
+    case("uber added later until uber duration",         false, false, false, &[AddUber(200)],                        200 + U,    Uber),
+    case("uber added later decays",                      false, false, false, &[AddUber(200)],                        200 + U + 1, LoggedIn),
+    case("uber added later can't outlive logged",        false, false, false, &[AddUber(L - 1)],                      L + 1,      NoClearance),
+    case("uber added again after decay",                 false, false, true,  &[Refresh(U + 1), AddUber(500)],        500 + U,    Uber),
+
+    case("auto logout at emission",                      false, true,  false, &[],                                    0,          LoggedIn),
+    case("auto logout until its duration",               false, true,  false, &[],                                    A,          LoggedIn),
+    case("auto logout expires",                          false, true,  false, &[],                                    A + 1,      NoClearance),
+    case("auto logout extended by refresh",              false, true,  false, &[Refresh(A)],                          2 * A,      LoggedIn),
+    case("auto logout refreshed then expires",           false, true,  false, &[Refresh(A)],                          2 * A + 1,  NoClearance),
+    case("auto logout can't outlive logged",             false, true,  false, &[Refresh(L - 1)],                      L + 1,      NoClearance),
+    case("auto logout with uber",                        false, true,  true,  &[],                                    U,          Uber),
+    case("auto logout with decayed uber",                false, true,  true,  &[],                                    U + 1,      LoggedIn),
+    case("auto logout with uber expires",                false, true,  true,  &[],                                    A + 1,      NoClearance),
+    case("auto logout needing second factor",            true,  true,  false, &[],                                    O,          NeedSecondFactor),
+    case("auto logout needing second factor expires",    true,  true,  false, &[],                                    O + 1,      NoClearance),
+    case("auto logout after second factor",              true,  true,  false, &[AddSecondFactor],                     A,          LoggedIn),
+    case("auto logout after second factor expires",      true,  true,  false, &[AddSecondFactor],                     A + 1,      NoClearance),
+];
+
+fn build(case: &Case) -> SessionToken {
+    let clock = ManualClock::new(T0);
+    let mut token = SessionToken::new(&clock, UserId::gen(), 0, DevicePublicKey::from(vec![0u8; 32]), case.lack_second_factor, false, case.auto_logout, case.uber);
+
+    for event in case.events {
+        match *event {
+            Refresh(offset) => token.refresh_to(T0 + offset, UBER),
+            AddSecondFactor => token.add_second_factor(),
+            AddUber(offset) => {
+                clock.set(T0 + offset);
+                token.add_uber(&clock).unwrap();
+            }
+        }
+    }
+
+    token
+}
+
+// which required clearance is satisfied by which granted clearance
+#[rustfmt::skip]
+const SATISFIES: &[(Clearance, Clearance, bool)] = &[
+    // required          granted             ok
+    (NoClearance,        NoClearance,        false),
+    (NoClearance,        NeedSecondFactor,   false),
+    (NoClearance,        LoggedIn,           false),
+    (NoClearance,        Uber,               false),
+    (NeedSecondFactor,   NoClearance,        false),
+    (NeedSecondFactor,   NeedSecondFactor,   true),
+    (NeedSecondFactor,   LoggedIn,           true),
+    (NeedSecondFactor,   Uber,               true),
+    (LoggedIn,           NoClearance,        false),
+    (LoggedIn,           NeedSecondFactor,   false),
+    (LoggedIn,           LoggedIn,           true),
+    (LoggedIn,           Uber,               true),
+    (Uber,               NoClearance,        false),
+    (Uber,               NeedSecondFactor,   false),
+    (Uber,               LoggedIn,           false),
+    (Uber,               Uber,               true),
+];
+
+#[test]
+fn clearance_transitions() {
+    for case in CASES {
+        let token = build(case);
+        let clearance = token.get_clearance_at(T0 + case.at, ONE_FACTOR, LOGGED, AUTO_LOGOUT, UBER).unwrap();
+        assert_eq!(clearance, case.expected, "{}", case.name);
+    }
+}
+
+#[test]
+fn validate_follows_clearance() {
+    for case in CASES {
+        let token = build(case);
+
+        for (required, granted, ok) in SATISFIES.iter().filter(|(_, granted, _)| *granted == case.expected) {
+            let res = token.validate_at(T0 + case.at, required.clone(), ONE_FACTOR, LOGGED, AUTO_LOGOUT, UBER);
+            match (ok, res) {
+                (true, Ok(())) | (false, Err(api::Error::InvalidSessionToken)) => (),
+                (_, res) => panic!("{}: requiring {:?} with {:?} gave {:?}", case.name, required, granted, res),
+            }
+        }
+    }
+}
+
+#[test]
+fn every_transition_is_covered() {
+    // each clearance must be reached, and each token kind must expire
+    for expected in [NoClearance, NeedSecondFactor, LoggedIn, Uber] {
+        assert!(CASES.iter().any(|c| c.expected == expected), "{:?} never reached", expected);
+    }
+    for (lack_second_factor, auto_logout, uber) in [(false, false, false), (true, false, false), (false, false, true), (false, true, false), (true, true, false), (false, true, true)] {
+        assert!(CASES.iter().any(|c| (c.lack_second_factor, c.auto_logout, c.uber) == (lack_second_factor, auto_logout, uber) && c.expected == NoClearance),
+            "{:?} never expires", (lack_second_factor, auto_logout, uber));
+    }
+}
+
+#[test]
+fn refresh_drops_expired_uber() {
+    let clock = ManualClock::new(T0);
+
+    let mut token = SessionToken::new(&clock, UserId::gen(), 0, DevicePublicKey::from(vec![0u8; 32]), false, false, false, true);
+    token.refresh_to(T0 + U, UBER);
+    assert_eq!(token.get_clearance_at_emission(), Uber);
+
+    token.refresh_to(T0 + U + 1, UBER);
+    assert_eq!(token.get_clearance_at_emission(), LoggedIn);
+
+    // refreshing to an earlier time doesn't bring it back
+    token.refresh_to(T0, UBER);
+    assert_eq!(token.get_clearance_at(T0, ONE_FACTOR, LOGGED, AUTO_LOGOUT, UBER).unwrap(), LoggedIn);
+}
+
+#[test]
+fn adjusted_now_tolerates_small_desynchronization() {
+    let clock = ManualClock::new(T0);
+    let token = SessionToken::new(&clock, UserId::gen(), 0, DevicePublicKey::from(vec![0u8; 32]), false, false, false, false);
+
+    clock.set(T0 + 10);
+    assert_eq!(token.adjusted_now(&clock).unwrap(), T0 + 10);
+
+    // a server slightly behind the one which emitted the token
+    clock.set(T0 - 4);
+    assert_eq!(token.adjusted_now(&clock).unwrap(), T0);
+
+    clock.set(T0 - 5);
+    assert!(token.adjusted_now(&clock).is_err());
+}
+
+#[test]
+fn expiration_covers_every_clearance() {
+    for case in CASES {
+        let token = build(case);
+        let expiration = token.get_expiration(ONE_FACTOR, LOGGED);
+        assert_eq!(token.get_clearance_at(expiration + 1, ONE_FACTOR, LOGGED, AUTO_LOGOUT, UBER).unwrap(), NoClearance, "{}", case.name);
+    }
+}
+
+const MAX_SKEW: u32 = 60;
+
+fn device_key(seed: u8) -> (ed25519_dalek::SigningKey, DevicePublicKey) {
+    let signing_key = ed25519_dalek::SigningKey::from_bytes(&[seed; 32]);
+    let public_key = DevicePublicKey::from(signing_key.verifying_key().to_bytes().to_vec());
+    (signing_key, public_key)
+}
+
+fn assert_invalid(res: api::Result<()>, what: &str) {
+    match res {
+        Err(api::Error::InvalidSessionProof) => (),
+        res => panic!("{}: expected InvalidSessionProof, got {:?}", what, res),
+    }
+}
+
+#[test]
+fn session_proof_accepts_valid_signature() {
+    let clock = ManualClock::new(T0);
+    let (signing_key, public_key) = device_key(1);
+
+    let proof = SessionProof::sign(&clock, &signing_key, b"rpc");
+    proof.verify(&clock, MAX_SKEW, &public_key, b"rpc").unwrap();
+}
+
+#[test]
+fn session_proof_rejects_tampering() {
+    let clock = ManualClock::new(T0);
+    let (signing_key, public_key) = device_key(1);
+    let (_, other_public_key) = device_key(2);
+
+    let proof = SessionProof::sign(&clock, &signing_key, b"rpc");
+    assert_invalid(proof.verify(&clock, MAX_SKEW, &public_key, b"rpd"), "tampered rpc");
+    assert_invalid(proof.verify(&clock, MAX_SKEW, &other_public_key, b"rpc"), "wrong device key");
+    assert_invalid(proof.verify(&clock, MAX_SKEW, &DevicePublicKey::from(vec![0u8; 31]), b"rpc"), "bad device key length");
+
+    let mut truncated = SessionProof::sign(&clock, &signing_key, b"rpc");
+    truncated.signature = truncated.signature.as_slice()[..63].to_vec().into();
+    assert_invalid(truncated.verify(&clock, MAX_SKEW, &public_key, b"rpc"), "bad signature length");
+
+    // the timestamp and nonce are signed too
+    let mut moved = SessionProof::sign(&clock, &signing_key, b"rpc");
+    moved.timestamp += 1;
+    assert_invalid(moved.verify(&clock, MAX_SKEW, &public_key, b"rpc"), "tampered timestamp");
+
+    let mut renonced = SessionProof::sign(&clock, &signing_key, b"rpc");
+    renonced.nonce[0] ^= 1;
+    assert_invalid(renonced.verify(&clock, MAX_SKEW, &public_key, b"rpc"), "tampered nonce");
+}
+
+#[test]
+fn session_proof_rejects_skewed_timestamp() {
+    let clock = ManualClock::new(T0);
+    let (signing_key, public_key) = device_key(1);
+    let proof = SessionProof::sign(&clock, &signing_key, b"rpc");
+
+    for (offset, ok) in [(MAX_SKEW as i64, true), (MAX_SKEW as i64 + 1, false), (-(MAX_SKEW as i64), true), (-(MAX_SKEW as i64) - 1, false)] {
+        clock.set(T0 + offset);
+        let res = proof.verify(&clock, MAX_SKEW, &public_key, b"rpc");
+        if ok {
+            res.unwrap();
+        } else {
+            assert_invalid(res, &format!("verified {}s after signing", offset));
+        }
+    }
+}
@@ -0,0 +1,19 @@
+use common::crypto::totp::{build_totp_uri, parse_totp_uri};
+
+#[test]
+fn uri_round_trips() {
+    let secret = [42u8; 20];
+    let uri = build_totp_uri("Cachou", "alice", &secret, 8, "SHA256", 60).unwrap();
+
+    assert_eq!(parse_totp_uri(&uri).unwrap(), (secret.to_vec(), 8, "SHA256".to_owned(), 60));
+}
+
+#[test]
+fn uri_label_names_the_account() {
+    let uri = build_totp_uri("Cachou", "alice", &[42u8; 20], 6, "SHA1", 30).unwrap();
+    assert!(uri.starts_with("otpauth://totp/Cachou:alice?"), "{}", uri);
+
+    // several accounts of the same issuer must still be told apart
+    let other = build_totp_uri("Cachou", "bob", &[42u8; 20], 6, "SHA1", 30).unwrap();
+    assert_ne!(uri, other);
+}
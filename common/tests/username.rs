@@ -0,0 +1,62 @@
+use common::{api, username};
+
+#[rustfmt::skip]
+const CASES: &[(&str, Option<&str>)] = &[
+    // input                              normalized
+    ("alice",                             Some("alice")),
+    ("  Alice_42  ",                      Some("Alice_42")),
+    ("jean-paul.dupont",                  Some("jean-paul.dupont")),
+    ("ｆｕｌｌｗｉｄｔｈ",                         Some("fullwidth")),   // NFKC
+    ("ﬁle",                               Some("file")),        // NFKC
+    ("josé",                              Some("josé")),
+    ("cafe\u{301}",                       Some("café")),        // composed
+    ("ユーザー",                              Some("ユーザー")),
+    ("Ελένη",                             Some("Ελένη")),
+
+    ("ab",                                None),                // too short
+    ("abcdefghijklmnopqrstuvwxyz0123456", None),                // too long
+    ("éééééééééééééééééé",                None),                // too long once encoded
+    ("_alice",                            None),                // must start with a letter or digit
+    ("alice bob",                         None),
+    ("alice@example.com",                 None),
+    ("alice\u{200b}",                     None),                // zero width space
+    ("pаypal",                            None),                // cyrillic 'а' among latin letters
+    ("асе",                               None),                // all cyrillic, looks like "ace"
+];
+
+#[test]
+fn normalize() {
+    for (input, expected) in CASES {
+        match (username::normalize(input), expected) {
+            (Ok(normalized), Some(expected)) => assert_eq!(&normalized, expected, "{:?}", input),
+            (Err(api::Error::InvalidUsername), None) => (),
+            (res, _) => panic!("{:?} gave {:?}", input, res),
+        }
+    }
+}
+
+#[test]
+fn validate_only_accepts_normalized() {
+    for (input, expected) in CASES {
+        let res = username::validate(input.as_bytes());
+        assert_eq!(res.is_ok(), Some(*input) == *expected, "{:?} gave {:?}", input, res);
+    }
+
+    assert!(username::validate(&[0xff, 0xfe, 0x61]).is_err());
+}
+
+fn look_alike(a: &str, b: &str) -> bool {
+    let b = username::skeletons(b);
+    username::skeletons(a).iter().any(|s| b.contains(s))
+}
+
+#[test]
+fn lookalikes_share_a_skeleton() {
+    for (a, b) in [("alice", "alIce"), ("alice", "aIice"), ("alice", "ALICE"), ("modern", "rnodern"), ("paypal", "paypa1"), ("ace", "асе")] {
+        assert!(look_alike(a, b), "{:?} and {:?}", a, b);
+    }
+
+    for (a, b) in [("alice", "bob"), ("alice", "alicia"), ("josé", "jose")] {
+        assert!(!look_alike(a, b), "{:?} and {:?}", a, b);
+    }
+}
@@ -1,9 +1,10 @@
 //#![allow(unused_imports)]
 
-use common::crypto::opaque::OpaqueConf;
+use common::{clock::{Clock, SystemClock}, crypto::opaque::OpaqueConf};
 use opaque_ke::ServerSetup;
 use rand::Rng;
-use std::{io::Write};
+use server::{config::Config, db::DbPool, notifier::{self, Notification}};
+use std::{fs::File, io::{Read, Write}};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -16,6 +17,13 @@ struct Opt {
 enum Command {
     CreateIdentityKey,
     CreateSecretKey,
+    CreateTotpKey,
+    RotateTotpKey,
+    SealTotpSecrets, // they used to be stored in clear
+    CreateUsernamePepper,
+    BlindIndexUsernames, // usernames used to be stored in clear
+    VarbinaryTmpSessionIds, // they used to be zero padded
+    PurgeDeletedAccounts, // once their grace period is over
     DropDatabase,
 }
 
@@ -35,6 +43,153 @@ fn main() -> eyre::Result<()> {
             let mut f = std::fs::File::create(common::consts::SECRET_KEY_PATH)?;
             f.write_all(&secret_key)?;
         }
+        Command::CreateTotpKey => {
+            let totp_key: [u8; 32] = rand::thread_rng().gen(); // 256bits
+            let mut f = std::fs::File::create(common::consts::TOTP_KEY_PATH)?;
+            f.write_all(&totp_key)?;
+        }
+        Command::RotateTotpKey => {
+            let mut f = File::open(common::consts::TOTP_KEY_PATH)?;
+            let mut old_totp_key = [0u8; 32];
+            let size = f.read(&mut old_totp_key)?;
+            eyre::ensure!(size == old_totp_key.len(), "failed to read totp_key");
+
+            // the new key is persisted before touching the DB so that it can't be lost if something goes wrong
+            let new_totp_key: [u8; 32] = rand::thread_rng().gen(); // 256bits
+            let new_totp_key_path = format!("{}.new", common::consts::TOTP_KEY_PATH);
+            let mut f = std::fs::File::create(&new_totp_key_path)?;
+            f.write_all(&new_totp_key)?;
+
+            let f = async {
+                let db = DbPool::new().await?;
+                let mut conn = db.acquire();
+                let count = match conn.tx().await?.reseal_totp_secrets(&old_totp_key, &new_totp_key).await {
+                    Ok(count) => count,
+                    Err(e) => {
+                        conn.rollback().await?;
+                        return Err(e.into());
+                    }
+                };
+                conn.commit().await?;
+                println!("resealed {} TOTP secrets", count);
+                Ok::<_, eyre::Report>(())
+            };
+            tokio::runtime::Builder::new_multi_thread().enable_all().build()?.block_on(f)?;
+
+            std::fs::rename(&new_totp_key_path, common::consts::TOTP_KEY_PATH)?;
+        }
+        Command::SealTotpSecrets => {
+            let mut f = File::open(common::consts::TOTP_KEY_PATH)?;
+            let mut totp_key = [0u8; 32];
+            let size = f.read(&mut totp_key)?;
+            eyre::ensure!(size == totp_key.len(), "failed to read totp_key");
+
+            let f = async {
+                let db = DbPool::new().await?;
+                db.acquire().std().await?.add_secret_totp_secret_column().await?;
+
+                let mut conn = db.acquire();
+                let count = match conn.tx().await?.seal_totp_secrets(&totp_key).await {
+                    Ok(count) => count,
+                    Err(e) => {
+                        conn.rollback().await?;
+                        return Err(e.into());
+                    }
+                };
+                conn.commit().await?;
+                println!("sealed {} TOTP secrets", count);
+
+                // only once the sealed secrets are commited
+                db.acquire().std().await?.drop_totp_secret_column().await?;
+                Ok::<_, eyre::Report>(())
+            };
+            tokio::runtime::Builder::new_multi_thread().enable_all().build()?.block_on(f)?;
+        }
+        Command::CreateUsernamePepper => {
+            let username_pepper: [u8; 32] = rand::thread_rng().gen(); // 256bits
+            let mut f = std::fs::File::create(common::consts::USERNAME_PEPPER_PATH)?;
+            f.write_all(&username_pepper)?;
+        }
+        Command::BlindIndexUsernames => {
+            let mut f = File::open(common::consts::USERNAME_PEPPER_PATH)?;
+            let mut username_pepper = [0u8; 32];
+            let size = f.read(&mut username_pepper)?;
+            eyre::ensure!(size == username_pepper.len(), "failed to read username_pepper");
+
+            let f = async {
+                let db = DbPool::new().await?;
+                let mut conn = db.acquire();
+                let count = match conn.tx().await?.blind_index_usernames(&username_pepper).await {
+                    Ok(count) => count,
+                    Err(e) => {
+                        conn.rollback().await?;
+                        return Err(e.into());
+                    }
+                };
+                conn.commit().await?;
+                println!("blind indexed {} usernames", count);
+                Ok::<_, eyre::Report>(())
+            };
+            tokio::runtime::Builder::new_multi_thread().enable_all().build()?.block_on(f)?;
+        }
+        Command::VarbinaryTmpSessionIds => {
+            let f = async {
+                let db = DbPool::new().await?;
+                db.acquire().std().await?.make_tmp_session_id_varbinary().await?;
+
+                let mut conn = db.acquire();
+                let count = match conn.tx().await?.varbinary_tmp_session_ids().await {
+                    Ok(count) => count,
+                    Err(e) => {
+                        conn.rollback().await?;
+                        return Err(e.into());
+                    }
+                };
+                conn.commit().await?;
+                println!("dropped {} pending tmp rows", count);
+                Ok::<_, eyre::Report>(())
+            };
+            tokio::runtime::Builder::new_multi_thread().enable_all().build()?.block_on(f)?;
+        }
+        Command::PurgeDeletedAccounts => {
+            let f = async {
+                let db = DbPool::new().await?;
+                let mut conn = db.acquire();
+                let notifier = notifier::from_config(&Config::load().await?.notifier)?;
+                let purge = async {
+                    let tx = conn.tx().await?;
+                    let user_ids = tx.get_users_to_purge(SystemClock.now()).await?;
+                    let mut purged = Vec::with_capacity(user_ids.len());
+                    for user_id in user_ids {
+                        // the contact email is about to be deleted
+                        let contact_email = tx.get_user_contact_email(&user_id).await?;
+                        tx.delete_user(&user_id).await?;
+                        purged.push((user_id, contact_email));
+                    }
+                    Ok::<_, common::api::Error>(purged)
+                };
+                let purged = match purge.await {
+                    Ok(purged) => purged,
+                    Err(e) => {
+                        conn.rollback().await?;
+                        return Err(e.into());
+                    }
+                };
+                conn.commit().await?;
+
+                for (user_id, contact_email) in &purged {
+                    println!("purged account {}", bs58::encode(user_id.as_slice()).into_string());
+                    if let Some(to) = contact_email {
+                        if let Err(e) = notifier.notify(to, &Notification::AccountDeleted).await {
+                            eprintln!("failed to notify the deletion of {}: {:?}", bs58::encode(user_id.as_slice()).into_string(), e);
+                        }
+                    }
+                }
+                println!("purged {} accounts", purged.len());
+                Ok::<_, eyre::Report>(())
+            };
+            tokio::runtime::Builder::new_multi_thread().enable_all().build()?.block_on(f)?;
+        }
         Command::DropDatabase => {
             todo!()
             //let db = server::db::Db::new()?;
@@ -7,6 +7,43 @@ pub struct Config {
     pub session_token_logged_duration_sec: u32,
     pub session_token_auto_logout_duration_sec: u32,
     pub session_token_uber_duration_sec: u32,
+    pub session_proof_max_skew_sec: u32, // requests signed longer ago, or that far in the future, are refused
+    pub sessions_cleanup_max_interval_sec: u32, // the cleanup task otherwise wakes up when the next revocation expires
+    pub trusted_device_duration_sec: u32, // logins from a trusted device skip the second factor for that long
+    pub account_deletion_grace_period_sec: u32, // 0 deletes accounts right away, otherwise they're erased by `admin purge-deleted-accounts`
+    pub server_state_duration_sec: u32, // validity of the secret_server_state given by NewCredentials and LoginStart
+    pub login_throttle_username_free_attempts: u32, // logins started without being successfully finished, allowed before being delayed
+    pub login_throttle_ip_free_attempts: u32,
+    pub login_throttle_base_delay_sec: u32, // doubles with each attempt past the free ones
+    pub login_throttle_max_delay_sec: u32,
+    pub login_throttle_forget_after_sec: u32, // attempts are forgotten after this long without any new one
+    pub second_factor_throttle_free_attempts: u32, // consecutive wrong second factors allowed before locking the account out
+    pub second_factor_throttle_lockout_sec: u32,
+    pub second_factor_throttle_forget_after_sec: u32,
+    pub recovery_delay_sec: u32, // time given to the user to cancel a recovery login
+    pub recovery_validity_sec: u32, // time given to complete the recovery login once the delay has passed
+    pub totp_enrollment_duration_sec: u32,
+    pub backup_codes_count: u32,
+    pub webauthn_rp_id: String, // usually the domain name, must be the same for the whole life of the credentials
+    pub webauthn_origin: String,
+    pub webauthn_challenge_duration_sec: u32,
+    pub contact_email_code_duration_sec: u32,
+    pub notifier: NotifierConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum NotifierConfig {
+    Smtp {
+        host: String,
+        port: u16,
+        username: String,
+        password: String,
+        from: String,
+    },
+    File {
+        path: String,
+    },
 }
 
 impl Config {
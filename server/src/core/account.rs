@@ -0,0 +1,43 @@
+use common::api::{self, DeleteAccount, DeleteAccountRet, RpcTrait, UserId, session_token::{Clearance, SessionToken}};
+use tracing::{Instrument, info, info_span};
+
+use crate::{db::DbConn, notifier::Notification, request_dispatcher::Req, state::State};
+
+impl State {
+    pub async fn delete_account(&self, args: &DeleteAccount, conn: &mut DbConn<'_>) -> api::Result<<DeleteAccount as RpcTrait>::Ret> {
+        let SessionToken{user_id, .. } = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::Uber).await?;
+
+        async {
+            let purge_at = if self.config.account_deletion_grace_period_sec == 0 {
+                // the contact email is about to be deleted
+                self.notify(conn, &user_id, Notification::AccountDeleted).await?;
+                conn.tx().await?.delete_user(&user_id).await?;
+                info!("ok - deleted");
+                None
+            } else {
+                // the credentials are kept so that the user can still log in to cancel the deletion
+                let purge_at = self.clock.now() + self.config.account_deletion_grace_period_sec as i64;
+                conn.tx().await?.set_pending_deletion(&user_id, purge_at).await?;
+                conn.tx().await?.delete_user_sessions(&user_id).await?;
+                conn.tx().await?.delete_user_trusted_devices(&user_id).await?;
+                self.notify(conn, &user_id, Notification::AccountDeletionPending { purge_at }).await?;
+                info!("ok - deletion pending");
+                Some(purge_at)
+            };
+
+            Ok(DeleteAccountRet {
+                purge_at,
+            })
+        }.instrument(info_span!("id", user_id = %bs58::encode(user_id.as_slice()).into_string())).await
+    }
+
+    // to be called once a login is complete, second factor included, like the deletion required
+    pub async fn account_deletion_cancel(&self, conn: &mut DbConn<'_>, user_id: &UserId, req: &Req) -> api::Result<bool> {
+        let canceled = conn.tx().await?.delete_pending_deletion(user_id).await?;
+        if canceled {
+            info!("account deletion canceled");
+            self.notify(conn, user_id, Notification::AccountDeletionCanceled { ip: req.ip }).await?;
+        }
+        Ok(canceled)
+    }
+}
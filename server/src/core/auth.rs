@@ -1,13 +1,25 @@
-use common::{api::{self, AddUser, AddUserRet, Credentials, GetExportKeys, GetExportKeysRet, GetUserPrivateData, GetUserPrivateDataRet, LoginFinish, LoginFinishRet, LoginStart, LoginStartRet, MasterKey, NewCredentials, NewCredentialsRet, RotateMasterKey, RotateMasterKeyRet, RpcTrait, SecretServerState, SetCredentials, SetTotp, SetUserPrivateData, UserId, Username, session_token::{Clearance, SessionToken}}, consts::{OPAQUE_S_ID, OPAQUE_S_ID_RECOVERY}, crypto::crypto_boxes::{AeadBox, SecretBox}};
+use common::{api::{self, AddUser, AddUserRet, BeginTotpEnrollment, BeginTotpEnrollmentRet, BeginWebauthnAssertion, BeginWebauthnAssertionRet, BeginWebauthnRegistration, BeginWebauthnRegistrationRet, CancelRecovery, ChangePasswordFinish, ChangePasswordStart, ChangePasswordStartRet, ConfirmTotpEnrollment, Credentials, FinishWebauthnRegistration, GenerateBackupCodes, GenerateBackupCodesRet, GetBackupCodesCount, GetBackupCodesCountRet, GetExportKeys, GetExportKeysRet, GetUserPrivateData, GetUserPrivateDataRet, LoginFinish, LoginFinishRet, LoginStart, LoginStartRet, MasterKey, NewCredentials, NewCredentialsRet, ReauthenticateForUberFinish, ReauthenticateForUberFinishRet, ReauthenticateForUberStart, ReauthenticateForUberStartRet, RotateMasterKey, RotateMasterKeyRet, RpcTrait, SecondFactor, SessionId, SetCredentials, SetUserPrivateData, Totp, TotpAlgo, TotpSecret, UnsetTotp, UnsetWebauthn, UserId, Username, VerifySecondFactor, VerifySecondFactorRet, WebauthnChallenge, WebauthnCreationOptions, WebauthnRequestOptions, session_token::{Clearance, SessionToken}}, consts::{OPAQUE_S_ID, OPAQUE_S_ID_RECOVERY, TOTP_ISSUER}, crypto::{crypto_boxes::{AeadBox, Seal, SecretBox}, totp}, username};
 use tracing::{Instrument, debug, info, info_span};
 
-use crate::{db::{DbConn, sql::TxConn}, opaque::{self, OpaqueState}, state::State};
+use crate::{db::{DbConn, sql::{self, TxConn}}, notifier::Notification, opaque::{self, OpaqueState}, request_dispatcher::Req, state::State, webauthn};
 use crate::db::sql::Queryable;
 use serde::{Serialize, Deserialize};
+use hmac::{Hmac, Mac};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use sha2::Sha256;
+
+const TMP_FIELD_TOTP_ENROLLMENT: &str = "totp_enrollment";
+const TMP_FIELD_WEBAUTHN_REGISTRATION: &str = "webauthn_registration";
+const TMP_FIELD_WEBAUTHN_ASSERTION: &str = "webauthn_assertion";
+const TMP_FIELD_LOGIN_NONCE: &str = "login_nonce";
+const TMP_FIELD_REAUTHENTICATION_NONCE: &str = "reauthentication_nonce";
+const TMP_FIELD_CHANGE_PASSWORD_NONCE: &str = "change_password_nonce";
 
 #[derive(Serialize, Deserialize, Debug)]
 struct ServerCredentialsState {
     username: Username,
+    recovery: bool, // the credentials can only be set as this kind
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -16,10 +28,29 @@ struct ServerLoginState {
     user_id: UserId,
     secret_master_key: SecretBox<MasterKey>,
     version_master_key: u32,
+    recovery: bool,
+    nonce: [u8; 32], // consumed by LoginFinish so that a login can't be replayed
+    username: Username, // needed for throttling
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ServerReauthenticationState {
+    opaque_state: OpaqueState,
+    session_id: SessionId, // the state can't be used by another session
+    nonce: [u8; 32], // consumed by ReauthenticateForUberFinish
+    username: Username, // needed for throttling
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ServerChangePasswordState {
+    opaque_state: OpaqueState,
+    session_id: SessionId, // the state can't be used by another session
+    nonce: [u8; 32], // consumed by ChangePasswordFinish
+    username: Username, // checked by the login, the new password is registered under it
 }
 
 impl State {
-    pub async fn add_user(&self, args: &AddUser, conn: &mut DbConn<'_>) -> api::Result<<AddUser as RpcTrait>::Ret> {
+    pub async fn add_user(&self, args: &AddUser, req: &Req, conn: &mut DbConn<'_>) -> api::Result<<AddUser as RpcTrait>::Ret> {
         let user_id = UserId::gen();
         
         async {
@@ -38,7 +69,7 @@ impl State {
             // save private data
             tx.set_user_private_data(&user_id, &args.secret_private_data).await?;
 
-            let authed_session_token = self.session_token_new_sealed(user_id.clone(), version_master_key, false, true, false)?;
+            let authed_session_token = self.session_token_new_sealed(conn.tx().await?, req, &args.device_label, &args.device_public_key, user_id.clone(), version_master_key, false, false, true, false).await?;
 
             info!("ok");
             
@@ -49,8 +80,12 @@ impl State {
     }
 
     pub async fn new_credentials(&self, args: &NewCredentials, _conn: &mut DbConn<'_>) -> api::Result<<NewCredentials as RpcTrait>::Ret> {
+        if !args.recovery {
+            username::validate(args.username.as_slice())?;
+        }
+
         let opaque_msg = opaque::registration_start(&self.opaque_setup, &args.opaque_msg, &args.username)?;
-        let secret_server_state: SecretServerState = AeadBox::seal(&self.secret_key[..], &ServerCredentialsState{username: args.username.clone()}, &())?.into(); // TODO add TTL
+        let secret_server_state = self.server_state_seal(NewCredentials::DISPLAY_NAME, &ServerCredentialsState{username: args.username.clone(), recovery: args.recovery})?;
 
         debug!("ok");
         Ok( NewCredentialsRet {
@@ -60,13 +95,24 @@ impl State {
     }
 
     async fn set_credentials_impl(&self, conn: &mut TxConn, new: bool, credentials: &Credentials, recovery: bool, user_id: &UserId) -> api::Result<()> {
-        let ServerCredentialsState { username } = AeadBox::<ServerCredentialsState, ()>::unseal(&self.secret_key, credentials.secret_server_state.as_slice())?.0;
+        let ServerCredentialsState { username, recovery: state_recovery } = self.server_state_unseal(NewCredentials::DISPLAY_NAME, &credentials.secret_server_state)?;
+
+        // otherwise an unvalidated username could be set as a normal one
+        if state_recovery != recovery {
+            return Err(api::Error::InvalidUsername);
+        }
         let opaque_password = opaque::registration_finish(&credentials.opaque_msg)?;
+        let blind_username = self.blind_username(&username)?;
 
         if new {
-            conn.new_credentials(recovery, &user_id, &username, &opaque_password, &credentials.secret_master_key, &credentials.secret_export_key).await?;
+            conn.new_credentials(recovery, &user_id, &blind_username, &opaque_password, &credentials.secret_master_key, &credentials.secret_export_key).await?;
         } else {
-            conn.set_credentials(recovery, &user_id, &username, &opaque_password, &credentials.secret_master_key, &credentials.secret_export_key).await?;
+            conn.set_credentials(recovery, &user_id, &blind_username, &opaque_password, &credentials.secret_master_key, &credentials.secret_export_key).await?;
+        }
+
+        // a username looking like another user's is refused like a taken one. recovery usernames aren't chosen
+        if !recovery {
+            conn.set_user_username_skeletons(user_id, &self.blind_username_skeletons(&username)?).await?;
         }
         Ok(())
     }
@@ -78,6 +124,7 @@ impl State {
 
         async {
             self.set_credentials_impl(conn.tx().await?, false, &args.credentials, args.recovery, &session_token.user_id).await?;
+            self.notify(conn, &session_token.user_id, Notification::CredentialsChanged { recovery: args.recovery }).await?;
 
             debug!("ok");
             
@@ -85,6 +132,79 @@ impl State {
         }.instrument(info_span!("id", %user_id)).await
     }
 
+    pub async fn change_password_start(&self, args: &ChangePasswordStart, req: &Req, conn: &mut DbConn<'_>) -> api::Result<<ChangePasswordStart as RpcTrait>::Ret> {
+        let SessionToken{user_id, session_id, recovery, .. } = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::LoggedIn).await?;
+
+        async {
+            // a forgotten password is reset with the recovery key through SetCredentials
+            if recovery {
+                return Err(api::Error::InvalidSessionToken);
+            }
+
+            // counted as a failure until ChangePasswordFinish proves the password
+            self.login_throttle_start(conn, false, &args.username, req.ip).await?;
+
+            // credentials of another user are answered like unknown ones, the password check will fail
+            let opaque_password = match conn.tx().await?.get_credentials_from_username(false, &self.blind_username(&args.username)?).await {
+                Ok((credentials_user_id, opaque_password, ..)) if credentials_user_id.as_slice() == user_id.as_slice() => opaque_password,
+                Ok(_) | Err(api::Error::NotFound) => {
+                    debug!("username doesn't match the session");
+                    self.fake_login_credentials(false, &args.username)?.1
+                }
+                Err(e) => return Err(e),
+            };
+
+            let (opaque_state, opaque_msg) = opaque::login_start(&self.opaque_setup, &args.opaque_msg, &args.username, &opaque_password, &OPAQUE_S_ID)?;
+            let opaque_msg_registration = opaque::registration_start(&self.opaque_setup, &args.opaque_msg_registration, &args.username)?;
+
+            let nonce = rand::thread_rng().gen::<[u8; 32]>();
+            let expiration = self.clock.now() + self.config.server_state_duration_sec as i64;
+            conn.tx().await?.save_tmp(&nonce, &req.ip.to_string(), expiration, TMP_FIELD_CHANGE_PASSWORD_NONCE, &[]).await?;
+
+            let secret_server_state = self.server_state_seal(ChangePasswordStart::DISPLAY_NAME, &ServerChangePasswordState{opaque_state, session_id, nonce, username: args.username.clone()})?;
+
+            debug!("ok");
+            Ok(ChangePasswordStartRet {
+                secret_server_state,
+                opaque_msg,
+                opaque_msg_registration,
+            })
+        }.instrument(info_span!("id", user_id = %bs58::encode(user_id.as_slice()).into_string())).await
+    }
+
+    pub async fn change_password_finish(&self, args: &ChangePasswordFinish, req: &Req, conn: &mut DbConn<'_>) -> api::Result<<ChangePasswordFinish as RpcTrait>::Ret> {
+        let SessionToken{user_id, session_id: token_session_id, .. } = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::LoggedIn).await?;
+        let ServerChangePasswordState {opaque_state, session_id, nonce, username} = self.server_state_unseal(ChangePasswordStart::DISPLAY_NAME, &args.secret_server_state)?;
+
+        async {
+            if session_id.as_slice() != token_session_id.as_slice() {
+                return Err(api::Error::InvalidServerState);
+            }
+
+            conn.tx().await?.restore_tmp(&nonce, TMP_FIELD_CHANGE_PASSWORD_NONCE, self.clock.now()).await.map_err(|e| match e {
+                api::Error::NotFound => api::Error::InvalidServerState,
+                e => e,
+            })?;
+
+            // a failure was already counted by ChangePasswordStart
+            opaque::login_finish(&opaque_state, &args.opaque_msg)?;
+            self.login_throttle_success(conn, false, &username, req.ip).await?;
+
+            // the username and the recovery credentials are left untouched
+            let opaque_password = opaque::registration_finish(&args.opaque_msg_registration)?;
+            let blind_username = self.blind_username(&username)?;
+            conn.tx().await?.set_credentials(false, &user_id, &blind_username, &opaque_password, &args.secret_master_key, &args.secret_export_key).await?;
+
+            // whoever knew the old password may hold other sessions or trusted devices
+            conn.tx().await?.delete_user_sessions_except(&user_id, &token_session_id).await?;
+            conn.tx().await?.delete_user_trusted_devices(&user_id).await?;
+            self.notify(conn, &user_id, Notification::CredentialsChanged { recovery: false }).await?;
+
+            debug!("ok");
+            Ok(())
+        }.instrument(info_span!("id", user_id = %bs58::encode(user_id.as_slice()).into_string())).await
+    }
+
     pub async fn get_export_keys(&self, args: &GetExportKeys, conn: &mut DbConn<'_>) -> api::Result<<GetExportKeys as RpcTrait>::Ret> {
         // get user's user_id and check that token has uber rights
         let session_token = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::Uber).await?;
@@ -118,6 +238,10 @@ impl State {
                 &args.secret_export_key,
                 &args.secret_master_key_recovery,
                 &args.secret_export_key_recovery).await?;
+            // other sessions' tokens and trusted devices' tokens are now invalid
+            conn.tx().await?.delete_user_sessions_except(&session_token.user_id, &session_token.session_id).await?;
+            conn.tx().await?.delete_user_trusted_devices(&session_token.user_id).await?;
+            self.notify(conn, &session_token.user_id, Notification::MasterKeyRotated).await?;
             debug!("ok");
             
             Ok(RotateMasterKeyRet{
@@ -126,14 +250,27 @@ impl State {
         }.instrument(info_span!("id", %user_id)).await
     }
 
-    pub async fn login_start(&self, args: &LoginStart, conn: &mut DbConn<'_>) -> api::Result<<LoginStart as RpcTrait>::Ret> {
-        let (user_id, opaque_password, secret_master_key) = conn.tx().await?.get_credentials_from_username(args.recovery, &args.username).await?;
+    pub async fn login_start(&self, args: &LoginStart, req: &Req, conn: &mut DbConn<'_>) -> api::Result<<LoginStart as RpcTrait>::Ret> {
+        // counted as a failure until LoginFinish proves the password
+        self.login_throttle_start(conn, args.recovery, &args.username, req.ip).await?;
+
+        let (user_id, opaque_password, secret_master_key, version_master_key) = match conn.tx().await?.get_credentials_from_username(args.recovery, &self.blind_username(&args.username)?).await {
+            Ok(credentials) => credentials,
+            Err(api::Error::NotFound) => {
+                debug!("unknown username");
+                self.fake_login_credentials(args.recovery, &args.username)?
+            }
+            Err(e) => return Err(e),
+        };
 
         async {
-            // TODO if recovery, alert user (by mail) and block request for a few days
-            let version_master_key = conn.tx().await?.get_user_version_master_key(&user_id).await?;
             let (opaque_state, opaque_msg) = opaque::login_start(&self.opaque_setup, &args.opaque_msg, &args.username, &opaque_password, if args.recovery { &OPAQUE_S_ID_RECOVERY } else { &OPAQUE_S_ID })?;
-            let secret_server_state: SecretServerState = AeadBox::seal(&self.secret_key[..], &ServerLoginState{opaque_state, user_id: user_id.clone(), secret_master_key, version_master_key}, &())?.into(); // TODO add TTL
+
+            let nonce = rand::thread_rng().gen::<[u8; 32]>();
+            let expiration = self.clock.now() + self.config.server_state_duration_sec as i64;
+            conn.tx().await?.save_tmp(&nonce, &req.ip.to_string(), expiration, TMP_FIELD_LOGIN_NONCE, &[]).await?;
+
+            let secret_server_state = self.server_state_seal(LoginStart::DISPLAY_NAME, &ServerLoginState{opaque_state, user_id: user_id.clone(), secret_master_key, version_master_key, recovery: args.recovery, nonce, username: args.username.clone()})?;
 
             info!("ok");
             Ok(LoginStartRet {
@@ -144,29 +281,277 @@ impl State {
     }
 
 
-    pub async fn login_finish(&self, args: &LoginFinish, conn: &mut DbConn<'_>) -> api::Result<<LoginFinish as RpcTrait>::Ret> {
-        let ServerLoginState {opaque_state, user_id, secret_master_key, version_master_key} = AeadBox::<ServerLoginState, ()>::unseal(&self.secret_key, args.secret_server_state.as_slice())?.0;
+    pub fn blind_username(&self, username: &Username) -> api::Result<Vec<u8>> {
+        sql::blind_username(&self.username_pepper, username)
+    }
+
+    // none for usernames which aren't utf-8, they were registered before being validated
+    fn blind_username_skeletons(&self, username: &Username) -> api::Result<Vec<Vec<u8>>> {
+        match std::str::from_utf8(username.as_slice()) {
+            Ok(username) => username::skeletons(username).iter().map(|s| sql::blind_username_skeleton(&self.username_pepper, s)).collect(),
+            Err(_) => Ok(Vec::new()),
+        }
+    }
+
+    // answering NotFound to LoginStart would let anyone enumerate usernames, so unknown users get a fake record instead.
+    // it's derived from the username so that repeated attempts get consistent answers, and LoginFinish then fails with InvalidPassword
+    fn fake_login_credentials(&self, recovery: bool, username: &Username) -> api::Result<(UserId, Vec<u8>, SecretBox<MasterKey>, u32)> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret_key).map_err(|e| eyre::eyre!(e))?;
+        mac.update(b"fake_opaque_record");
+        mac.update(&[recovery as u8]);
+        mac.update(username.as_slice());
+        let mut rng = ChaCha20Rng::from_seed(mac.finalize().into_bytes().into());
+
+        let user_id = UserId::from(rng.gen::<[u8; 16]>());
+        let opaque_password = opaque::fake_registration(&mut rng)?;
+        let secret_master_key = MasterKey::gen().seal(&rng.gen::<[u8; 32]>())?;
+
+        Ok((user_id, opaque_password, secret_master_key, 0))
+    }
+
+    // the first recovery login only starts the delay, during which normal logins can see and cancel the recovery.
+    // it's recorded outside of the transaction as the request then fails with RecoveryPending
+    async fn recovery_delay_check(&self, conn: &mut DbConn<'_>, user_id: &UserId, req: &Req) -> api::Result<()> {
+        if self.config.recovery_delay_sec == 0 {
+            return Ok(());
+        }
+
+        let now = self.clock.now();
+        match conn.std().await?.get_pending_recovery(user_id).await? {
+            Some(available_at) if now < available_at => {
+                Err(api::Error::RecoveryPending { retry_after: (available_at - now) as u32 })
+            }
+            Some(available_at) if now < available_at + self.config.recovery_validity_sec as i64 => {
+                conn.tx().await?.delete_pending_recovery(user_id).await?;
+                Ok(())
+            }
+            _ => {
+                info!("recovery pending");
+                let available_at = now + self.config.recovery_delay_sec as i64;
+                conn.std().await?.set_pending_recovery(user_id, available_at).await?;
+                self.notify_now(conn, user_id, Notification::RecoveryPending { ip: req.ip, available_at }).await?;
+                Err(api::Error::RecoveryPending { retry_after: self.config.recovery_delay_sec })
+            }
+        }
+    }
+
+    pub async fn cancel_recovery(&self, args: &CancelRecovery, conn: &mut DbConn<'_>) -> api::Result<<CancelRecovery as RpcTrait>::Ret> {
+        let SessionToken{user_id, .. } = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::LoggedIn).await?;
+
+        async {
+            conn.tx().await?.delete_pending_recovery(&user_id).await?;
+            debug!("ok");
+            Ok(())
+        }.instrument(info_span!("id", user_id = %bs58::encode(user_id.as_slice()).into_string())).await
+    }
+
+    pub async fn login_finish(&self, args: &LoginFinish, req: &Req, conn: &mut DbConn<'_>) -> api::Result<<LoginFinish as RpcTrait>::Ret> {
+        let ServerLoginState {opaque_state, user_id, secret_master_key, version_master_key, recovery, nonce, username} = self.server_state_unseal(LoginStart::DISPLAY_NAME, &args.secret_server_state)?;
 
         async {
-            // check password
+            // the row is locked until the end of the transaction, so concurrent replays will find it deleted
+            conn.tx().await?.restore_tmp(&nonce, TMP_FIELD_LOGIN_NONCE, self.clock.now()).await.map_err(|e| match e {
+                api::Error::NotFound => api::Error::InvalidServerState,
+                e => e,
+            })?;
+
+            // check password, a failure was already counted by LoginStart
             opaque::login_finish(&opaque_state, &args.opaque_msg)?;
+            self.login_throttle_success(conn, recovery, &username, req.ip).await?;
+
+            if !recovery {
+                conn.tx().await?.add_user_username_skeletons(&user_id, &self.blind_username_skeletons(&username)?).await?;
+            }
+
+            let pending_recovery = if recovery {
+                self.recovery_delay_check(conn, &user_id, req).await?;
+                None
+            } else {
+                conn.std().await?.get_pending_recovery(&user_id).await?
+            };
 
-            let totp = conn.std().await?.get_user_totp(&user_id).await?;
+            // recovery logins always need the second factor
+            let trusted_device = match &args.trusted_device_token {
+                Some(t) if !recovery => self.trusted_device_token_check(conn.tx().await?, t, &user_id, version_master_key).await?,
+                _ => false,
+            };
+            let need_second_factor = !trusted_device && self.has_second_factor(conn, &user_id).await?;
 
-            if totp.is_some() {
+            // otherwise it's left to VerifySecondFactor, the password alone doesn't log in
+            let account_deletion_canceled = !recovery && !need_second_factor && self.account_deletion_cancel(conn, &user_id, req).await?;
+
+            // the master key is withheld until the second factor is verified
+            let secret_master_key = if need_second_factor {
                 debug!("ok - need second factor");
+                None
             } else {
                 debug!("ok - logged in"); 
+                Some(secret_master_key)
+            };
+
+            // otherwise sent by VerifySecondFactor, the password alone doesn't log in
+            if !need_second_factor {
+                self.notify(conn, &user_id, login_notification(recovery, req)).await?;
             }
 
             Ok( LoginFinishRet {
-                authed_session_token: self.session_token_new_sealed(user_id.clone(), version_master_key, totp.is_some(), args.auto_logout, args.uber_clearance)?,
-                secret_master_key: Some(secret_master_key),
+                authed_session_token: self.session_token_new_sealed(conn.tx().await?, req, &args.device_label, &args.device_public_key, user_id.clone(), version_master_key, need_second_factor, recovery, args.auto_logout, args.uber_clearance).await?,
+                secret_master_key,
+                pending_recovery,
+                account_deletion_canceled,
             })
             
         }.instrument(info_span!("id", user_id = %bs58::encode(user_id.as_slice()).into_string())).await
     }
 
+    pub async fn verify_second_factor(&self, args: &VerifySecondFactor, req: &Req, conn: &mut DbConn<'_>) -> api::Result<<VerifySecondFactor as RpcTrait>::Ret> {
+        let mut session_token = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::NeedSecondFactor).await?;
+        let user_id = bs58::encode(session_token.user_id.as_slice()).into_string();
+
+        async {
+            self.check_second_factor(conn, &session_token.user_id, &args.second_factor).await?;
+
+            session_token.add_second_factor();
+
+            let secret_master_key = conn.tx().await?.get_secret_master_key(session_token.recovery, &session_token.user_id).await?;
+
+            let account_deletion_canceled = !session_token.recovery && self.account_deletion_cancel(conn, &session_token.user_id, req).await?;
+            self.notify(conn, &session_token.user_id, login_notification(session_token.recovery, req)).await?;
+
+            // they wouldn't be honored by recovery logins anyway
+            let trusted_device_token = if args.trust_device && !session_token.recovery {
+                Some(self.trusted_device_token_new_sealed(conn.tx().await?, &session_token).await?)
+            } else {
+                None
+            };
+
+            debug!("ok");
+            Ok( VerifySecondFactorRet {
+                authed_session_token: self.session_token_seal(&session_token)?,
+                secret_master_key,
+                trusted_device_token,
+                account_deletion_canceled,
+            })
+        }.instrument(info_span!("id", %user_id)).await
+    }
+
+    async fn has_second_factor(&self, conn: &mut DbConn<'_>, user_id: &UserId) -> api::Result<bool> {
+        let totp = conn.std().await?.get_user_totp(&self.totp_key, user_id).await?;
+        let webauthn_credential_ids = conn.tx().await?.get_user_webauthn_credential_ids(user_id).await?;
+        Ok(totp.is_some() || !webauthn_credential_ids.is_empty())
+    }
+
+    // fails with RateLimited once the second factor was wrong too many times in a row
+    async fn check_second_factor(&self, conn: &mut DbConn<'_>, user_id: &UserId, second_factor: &SecondFactor) -> api::Result<()> {
+        self.second_factor_throttle_check(conn, user_id).await?;
+
+        match self.check_second_factor_impl(conn, user_id, second_factor).await {
+            Ok(()) => self.second_factor_throttle_success(conn, user_id).await,
+            Err(api::Error::InvalidSecondFactor) => {
+                self.second_factor_throttle_failure(conn, user_id).await?;
+                Err(api::Error::InvalidSecondFactor)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn check_second_factor_impl(&self, conn: &mut DbConn<'_>, user_id: &UserId, second_factor: &SecondFactor) -> api::Result<()> {
+        match second_factor {
+            SecondFactor::Totp(totp_code) => {
+                // a TOTP code can only be used once, lock the user's row before reading its TOTP config
+                let last_counter = conn.tx().await?.get_user_totp_last_counter(user_id).await?;
+                let totp = conn.tx().await?.get_user_totp(&self.totp_key, user_id).await?.ok_or(api::Error::NotFound)?;
+
+                let counter = totp::check_totp_code(totp.secret.as_slice(), totp.digits, totp.algo.as_ref(), totp.period, totp_code, last_counter, self.clock.now() as u64)
+                    .map_err(|_| api::Error::InvalidSecondFactor)?;
+                conn.tx().await?.set_user_totp_last_counter(user_id, counter).await?;
+            }
+            SecondFactor::BackupCode(backup_code) => {
+                let hashed_code = self.hash_backup_code(user_id, backup_code)?;
+                conn.tx().await?.burn_user_backup_code(user_id, &hashed_code).await?;
+            }
+            SecondFactor::Webauthn(assertion) => {
+                let challenge = WebauthnChallenge::from_vec(conn.tx().await?.restore_tmp(user_id.as_slice(), TMP_FIELD_WEBAUTHN_ASSERTION, self.clock.now()).await?);
+                let (public_key, sign_count) = conn.tx().await?.get_user_webauthn_credential(user_id, &assertion.credential_id).await
+                    .map_err(|e| match e {
+                        api::Error::NotFound => api::Error::InvalidSecondFactor,
+                        e => e,
+                    })?;
+
+                let sign_count = webauthn::assertion_finish(&self.config.webauthn_rp_id, &self.config.webauthn_origin, &challenge, &public_key, sign_count, assertion)?;
+                conn.tx().await?.set_webauthn_credential_sign_count(&assertion.credential_id, sign_count).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn reauthenticate_for_uber_start(&self, args: &ReauthenticateForUberStart, req: &Req, conn: &mut DbConn<'_>) -> api::Result<<ReauthenticateForUberStart as RpcTrait>::Ret> {
+        let SessionToken{user_id, session_id, recovery, .. } = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::LoggedIn).await?;
+
+        async {
+            // counted as a failure until ReauthenticateForUberFinish proves the password
+            self.login_throttle_start(conn, recovery, &args.username, req.ip).await?;
+
+            // credentials of another user are answered like unknown ones, the password check will fail
+            let opaque_password = match conn.tx().await?.get_credentials_from_username(recovery, &self.blind_username(&args.username)?).await {
+                Ok((credentials_user_id, opaque_password, ..)) if credentials_user_id.as_slice() == user_id.as_slice() => opaque_password,
+                Ok(_) | Err(api::Error::NotFound) => {
+                    debug!("username doesn't match the session");
+                    self.fake_login_credentials(recovery, &args.username)?.1
+                }
+                Err(e) => return Err(e),
+            };
+
+            let (opaque_state, opaque_msg) = opaque::login_start(&self.opaque_setup, &args.opaque_msg, &args.username, &opaque_password, if recovery { &OPAQUE_S_ID_RECOVERY } else { &OPAQUE_S_ID })?;
+
+            let nonce = rand::thread_rng().gen::<[u8; 32]>();
+            let expiration = self.clock.now() + self.config.server_state_duration_sec as i64;
+            conn.tx().await?.save_tmp(&nonce, &req.ip.to_string(), expiration, TMP_FIELD_REAUTHENTICATION_NONCE, &[]).await?;
+
+            let secret_server_state = self.server_state_seal(ReauthenticateForUberStart::DISPLAY_NAME, &ServerReauthenticationState{opaque_state, session_id, nonce, username: args.username.clone()})?;
+
+            debug!("ok");
+            Ok(ReauthenticateForUberStartRet {
+                secret_server_state,
+                opaque_msg,
+            })
+        }.instrument(info_span!("id", user_id = %bs58::encode(user_id.as_slice()).into_string())).await
+    }
+
+    pub async fn reauthenticate_for_uber_finish(&self, args: &ReauthenticateForUberFinish, req: &Req, conn: &mut DbConn<'_>) -> api::Result<<ReauthenticateForUberFinish as RpcTrait>::Ret> {
+        let mut session_token = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::LoggedIn).await?;
+        let ServerReauthenticationState {opaque_state, session_id, nonce, username} = self.server_state_unseal(ReauthenticateForUberStart::DISPLAY_NAME, &args.secret_server_state)?;
+        let user_id = session_token.user_id.clone();
+
+        async {
+            if session_id.as_slice() != session_token.session_id.as_slice() {
+                return Err(api::Error::InvalidServerState);
+            }
+
+            conn.tx().await?.restore_tmp(&nonce, TMP_FIELD_REAUTHENTICATION_NONCE, self.clock.now()).await.map_err(|e| match e {
+                api::Error::NotFound => api::Error::InvalidServerState,
+                e => e,
+            })?;
+
+            // a failure was already counted by ReauthenticateForUberStart
+            opaque::login_finish(&opaque_state, &args.opaque_msg)?;
+            self.login_throttle_success(conn, session_token.recovery, &username, req.ip).await?;
+
+            if self.has_second_factor(conn, &user_id).await? {
+                let second_factor = args.second_factor.as_ref().ok_or(api::Error::InvalidSecondFactor)?;
+                self.check_second_factor(conn, &user_id, second_factor).await?;
+            }
+
+            // the session keeps its identity, only its clearance changes
+            session_token.add_uber(&*self.clock)?;
+
+            debug!("ok");
+            Ok(ReauthenticateForUberFinishRet {
+                authed_session_token: self.session_token_seal(&session_token)?,
+            })
+        }.instrument(info_span!("id", user_id = %bs58::encode(user_id.as_slice()).into_string())).await
+    }
+
     pub async fn get_user_private_data(&self, args: &GetUserPrivateData, conn: &mut DbConn<'_>) -> api::Result<<GetUserPrivateData as RpcTrait>::Ret> {
         let SessionToken{user_id, ..} = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::LoggedIn).await?;
 
@@ -192,13 +577,186 @@ impl State {
     }
 
 
-    pub async fn set_totp(&self, args: &SetTotp, conn: &mut DbConn<'_>) -> api::Result<<SetTotp as RpcTrait>::Ret> {
+    pub async fn begin_totp_enrollment(&self, args: &BeginTotpEnrollment, req: &Req, conn: &mut DbConn<'_>) -> api::Result<<BeginTotpEnrollment as RpcTrait>::Ret> {
+        let SessionToken{user_id, .. } = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::Uber).await?;
+
+        async {
+            let totp = Totp {
+                secret: TotpSecret::gen(),
+                digits: 6,
+                algo: TotpAlgo::Sha1, // the only one supported by most authenticators
+                period: 30,
+            };
+            let uri = totp::build_totp_uri(TOTP_ISSUER, &args.account, totp.secret.as_slice(), totp.digits, totp.algo.as_ref(), totp.period)?;
+
+            // the pending secret is sealed the same way as an activated one
+            let secret_totp = AeadBox::seal(&self.totp_key, &totp, &user_id)?;
+            let expiration = self.clock.now() + self.config.totp_enrollment_duration_sec as i64;
+            conn.tx().await?.save_tmp(user_id.as_slice(), &req.ip.to_string(), expiration, TMP_FIELD_TOTP_ENROLLMENT, &secret_totp).await?;
+
+            debug!("ok");
+            Ok(BeginTotpEnrollmentRet {
+                totp,
+                uri,
+            })
+        }.instrument(info_span!("id", user_id = %bs58::encode(user_id.as_slice()).into_string())).await
+    }
+
+    pub async fn confirm_totp_enrollment(&self, args: &ConfirmTotpEnrollment, conn: &mut DbConn<'_>) -> api::Result<<ConfirmTotpEnrollment as RpcTrait>::Ret> {
+        let SessionToken{user_id, .. } = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::Uber).await?;
+
+        async {
+            // if the code is invalid, the transaction is rollbacked and the pending secret is kept for another try
+            let secret_totp = conn.tx().await?.restore_tmp(user_id.as_slice(), TMP_FIELD_TOTP_ENROLLMENT, self.clock.now()).await?;
+            let (totp, sealed_user_id) = AeadBox::<Totp, UserId>::unseal(&self.totp_key, &secret_totp)?;
+            if sealed_user_id.as_slice() != user_id.as_slice() {
+                return Err(eyre::eyre!("pending TOTP secret doesn't belong to this user").into());
+            }
+
+            let counter = totp::check_totp_code(totp.secret.as_slice(), totp.digits, totp.algo.as_ref(), totp.period, &args.totp_code, None, self.clock.now() as u64)
+                .map_err(|_| api::Error::InvalidSecondFactor)?;
+
+            conn.tx().await?.set_user_totp(&self.totp_key, &user_id, &Some(totp)).await?;
+
+            // the code used to confirm the enrollment can't be reused to login
+            conn.tx().await?.set_user_totp_last_counter(&user_id, counter).await?;
+
+            self.notify(conn, &user_id, Notification::TotpEnabled).await?;
+            debug!("ok");
+            Ok(())
+        }.instrument(info_span!("id", user_id = %bs58::encode(user_id.as_slice()).into_string())).await
+    }
+
+    pub async fn unset_totp(&self, args: &UnsetTotp, conn: &mut DbConn<'_>) -> api::Result<<UnsetTotp as RpcTrait>::Ret> {
         let SessionToken{user_id, .. } = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::Uber).await?;
 
         async {
-            conn.tx().await?.set_user_totp(&user_id, &args.totp).await?;
+            conn.tx().await?.set_user_totp(&self.totp_key, &user_id, &None).await?;
+            self.notify(conn, &user_id, Notification::TotpDisabled).await?;
             debug!("ok");
             Ok(())
         }.instrument(info_span!("id", user_id = %bs58::encode(user_id.as_slice()).into_string())).await
     }
-}
\ No newline at end of file
+
+    pub async fn generate_backup_codes(&self, args: &GenerateBackupCodes, conn: &mut DbConn<'_>) -> api::Result<<GenerateBackupCodes as RpcTrait>::Ret> {
+        let SessionToken{user_id, .. } = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::Uber).await?;
+
+        async {
+            let backup_codes = (0..self.config.backup_codes_count)
+                .map(|_| bs58::encode(rand::thread_rng().gen::<[u8; 8]>()).into_string()) // 64bits
+                .collect::<Vec<_>>();
+
+            // only the hashes are stored, the plaintext codes are only returned once
+            let hashed_codes = backup_codes.iter()
+                .map(|c| self.hash_backup_code(&user_id, c))
+                .collect::<api::Result<Vec<_>>>()?;
+            conn.tx().await?.set_user_backup_codes(&user_id, &hashed_codes).await?;
+
+            debug!("ok");
+            Ok(GenerateBackupCodesRet {
+                backup_codes,
+            })
+        }.instrument(info_span!("id", user_id = %bs58::encode(user_id.as_slice()).into_string())).await
+    }
+
+    pub async fn get_backup_codes_count(&self, args: &GetBackupCodesCount, conn: &mut DbConn<'_>) -> api::Result<<GetBackupCodesCount as RpcTrait>::Ret> {
+        let SessionToken{user_id, .. } = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::LoggedIn).await?;
+
+        async {
+            let count = conn.tx().await?.get_user_backup_codes_count(&user_id).await?;
+
+            debug!("ok");
+            Ok(GetBackupCodesCountRet {
+                count,
+            })
+        }.instrument(info_span!("id", user_id = %bs58::encode(user_id.as_slice()).into_string())).await
+    }
+
+    // backup codes have enough entropy to not need a slow hash, but they are keyed so that a database dump isn't enough to brute-force them
+    fn hash_backup_code(&self, user_id: &UserId, backup_code: &str) -> api::Result<Vec<u8>> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.derive_secret_key(b"backup_code")?).map_err(|e| eyre::eyre!(e))?;
+        mac.update(user_id.as_slice());
+        mac.update(backup_code.trim().as_bytes());
+        Ok(mac.finalize().into_bytes().to_vec())
+    }
+
+    pub async fn begin_webauthn_registration(&self, args: &BeginWebauthnRegistration, req: &Req, conn: &mut DbConn<'_>) -> api::Result<<BeginWebauthnRegistration as RpcTrait>::Ret> {
+        let SessionToken{user_id, .. } = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::Uber).await?;
+
+        async {
+            let challenge = WebauthnChallenge::gen();
+            let expiration = self.clock.now() + self.config.webauthn_challenge_duration_sec as i64;
+            conn.tx().await?.save_tmp(user_id.as_slice(), &req.ip.to_string(), expiration, TMP_FIELD_WEBAUTHN_REGISTRATION, challenge.as_slice()).await?;
+
+            // prevents registering the same authenticator twice
+            let exclude_credentials = conn.tx().await?.get_user_webauthn_credential_ids(&user_id).await?;
+
+            debug!("ok");
+            Ok(BeginWebauthnRegistrationRet {
+                options: WebauthnCreationOptions {
+                    rp_id: self.config.webauthn_rp_id.clone(),
+                    user_handle: user_id.clone(),
+                    challenge,
+                    algorithms: webauthn::SUPPORTED_ALGORITHMS.to_vec(),
+                    exclude_credentials,
+                }
+            })
+        }.instrument(info_span!("id", user_id = %bs58::encode(user_id.as_slice()).into_string())).await
+    }
+
+    pub async fn finish_webauthn_registration(&self, args: &FinishWebauthnRegistration, conn: &mut DbConn<'_>) -> api::Result<<FinishWebauthnRegistration as RpcTrait>::Ret> {
+        let SessionToken{user_id, .. } = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::Uber).await?;
+
+        async {
+            let challenge = WebauthnChallenge::from_vec(conn.tx().await?.restore_tmp(user_id.as_slice(), TMP_FIELD_WEBAUTHN_REGISTRATION, self.clock.now()).await?);
+            let (public_key, sign_count) = webauthn::registration_finish(&self.config.webauthn_rp_id, &self.config.webauthn_origin, &challenge, &args.attestation)?;
+
+            conn.tx().await?.new_webauthn_credential(&user_id, &args.attestation.credential_id, &args.name, &public_key, sign_count).await?;
+
+            debug!("ok");
+            Ok(())
+        }.instrument(info_span!("id", user_id = %bs58::encode(user_id.as_slice()).into_string())).await
+    }
+
+    pub async fn unset_webauthn(&self, args: &UnsetWebauthn, conn: &mut DbConn<'_>) -> api::Result<<UnsetWebauthn as RpcTrait>::Ret> {
+        let SessionToken{user_id, .. } = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::Uber).await?;
+
+        async {
+            conn.tx().await?.delete_user_webauthn_credentials(&user_id).await?;
+            debug!("ok");
+            Ok(())
+        }.instrument(info_span!("id", user_id = %bs58::encode(user_id.as_slice()).into_string())).await
+    }
+
+    pub async fn begin_webauthn_assertion(&self, args: &BeginWebauthnAssertion, req: &Req, conn: &mut DbConn<'_>) -> api::Result<<BeginWebauthnAssertion as RpcTrait>::Ret> {
+        let SessionToken{user_id, .. } = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::NeedSecondFactor).await?;
+
+        async {
+            let allow_credentials = conn.tx().await?.get_user_webauthn_credential_ids(&user_id).await?;
+            if allow_credentials.is_empty() {
+                return Err(api::Error::NotFound);
+            }
+
+            let challenge = WebauthnChallenge::gen();
+            let expiration = self.clock.now() + self.config.webauthn_challenge_duration_sec as i64;
+            conn.tx().await?.save_tmp(user_id.as_slice(), &req.ip.to_string(), expiration, TMP_FIELD_WEBAUTHN_ASSERTION, challenge.as_slice()).await?;
+
+            debug!("ok");
+            Ok(BeginWebauthnAssertionRet {
+                options: WebauthnRequestOptions {
+                    rp_id: self.config.webauthn_rp_id.clone(),
+                    challenge,
+                    allow_credentials,
+                }
+            })
+        }.instrument(info_span!("id", user_id = %bs58::encode(user_id.as_slice()).into_string())).await
+    }
+}
+
+fn login_notification(recovery: bool, req: &Req) -> Notification {
+    if recovery {
+        Notification::RecoveryLogin { ip: req.ip }
+    } else {
+        Notification::NewLogin { ip: req.ip }
+    }
+}
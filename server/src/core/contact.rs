@@ -0,0 +1,76 @@
+use common::api::{self, RpcTrait, SetContactEmail, UserId, VerifyContactEmail, session_token::{Clearance, SessionToken}};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use sha2::Sha256;
+use tracing::{Instrument, debug, info_span};
+use validator::ValidateEmail;
+
+use crate::{db::DbConn, notifier::Notification, request_dispatcher::Req, state::State};
+use crate::db::sql::Queryable;
+
+const TMP_FIELD_CONTACT_EMAIL: &str = "contact_email";
+
+// stored in tmp until the code sent to the email is submitted
+#[derive(Serialize, Deserialize, Debug)]
+struct PendingContactEmail {
+    email: String,
+    hashed_code: Vec<u8>,
+}
+
+impl State {
+    pub async fn set_contact_email(&self, args: &SetContactEmail, req: &Req, conn: &mut DbConn<'_>) -> api::Result<<SetContactEmail as RpcTrait>::Ret> {
+        let SessionToken{user_id, .. } = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::Uber).await?;
+
+        async {
+            if !args.email.validate_email() {
+                return Err(api::Error::InvalidEmail);
+            }
+
+            let code = bs58::encode(rand::thread_rng().gen::<[u8; 8]>()).into_string(); // 64bits
+            let pending = PendingContactEmail {
+                email: args.email.clone(),
+                hashed_code: self.hash_contact_email_code(&user_id, &args.email, &code)?.finalize().into_bytes().to_vec(),
+            };
+
+            let expiration = self.clock.now() + self.config.contact_email_code_duration_sec as i64;
+            conn.tx().await?.save_tmp(user_id.as_slice(), &req.ip.to_string(), expiration, TMP_FIELD_CONTACT_EMAIL, &rmp_serde::to_vec(&pending).map_err(|e| eyre::eyre!(e))?).await?;
+
+            self.notify_address(conn, args.email.clone(), Notification::ContactEmailVerification { code });
+
+            debug!("ok");
+            Ok(())
+        }.instrument(info_span!("id", user_id = %bs58::encode(user_id.as_slice()).into_string())).await
+    }
+
+    pub async fn verify_contact_email(&self, args: &VerifyContactEmail, conn: &mut DbConn<'_>) -> api::Result<<VerifyContactEmail as RpcTrait>::Ret> {
+        let SessionToken{user_id, .. } = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::Uber).await?;
+
+        async {
+            // if the code is invalid, the transaction is rollbacked and the pending email is kept for another try
+            let pending: PendingContactEmail = rmp_serde::from_slice(&conn.tx().await?.restore_tmp(user_id.as_slice(), TMP_FIELD_CONTACT_EMAIL, self.clock.now()).await?)
+                .map_err(|e| eyre::eyre!(e))?;
+
+            self.hash_contact_email_code(&user_id, &pending.email, &args.code)?
+                .verify_slice(&pending.hashed_code)
+                .map_err(|_| api::Error::InvalidVerificationCode)?;
+
+            // warn the previous address, if any
+            self.notify(conn, &user_id, Notification::ContactEmailChanged).await?;
+            conn.tx().await?.set_user_contact_email(&user_id, &pending.email).await?;
+
+            debug!("ok");
+            Ok(())
+        }.instrument(info_span!("id", user_id = %bs58::encode(user_id.as_slice()).into_string())).await
+    }
+
+    // the email is part of the hash so that the code can't be used to verify another address
+    fn hash_contact_email_code(&self, user_id: &UserId, email: &str, code: &str) -> api::Result<Hmac<Sha256>> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.derive_secret_key(b"contact_email_code")?).map_err(|e| eyre::eyre!(e))?;
+        mac.update(user_id.as_slice());
+        mac.update(email.as_bytes());
+        mac.update(&[0]);
+        mac.update(code.trim().as_bytes());
+        Ok(mac)
+    }
+}
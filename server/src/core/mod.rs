@@ -1,2 +1,8 @@
 pub mod auth;
-mod session;
\ No newline at end of file
+mod account;
+mod session;
+mod trusted_device;
+mod server_state;
+pub mod throttle;
+mod notifications;
+mod contact;
\ No newline at end of file
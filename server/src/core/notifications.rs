@@ -0,0 +1,35 @@
+use std::sync::Arc;
+
+use common::api::{self, UserId};
+use tracing::{Instrument, error};
+
+use crate::{db::DbConn, notifier::{Notification, Notifier}, state::State};
+
+impl State {
+    // sent in the background once the request's transaction is commited, if the user has a verified contact email
+    pub async fn notify(&self, conn: &mut DbConn<'_>, user_id: &UserId, notification: Notification) -> api::Result<()> {
+        if let Some(to) = conn.tx().await?.get_user_contact_email(user_id).await? {
+            conn.on_commit(send(self.notifier.clone(), to, notification).in_current_span());
+        }
+        Ok(())
+    }
+
+    // to an address which isn't (yet) the user's contact email
+    pub fn notify_address(&self, conn: &mut DbConn<'_>, to: String, notification: Notification) {
+        conn.on_commit(send(self.notifier.clone(), to, notification).in_current_span());
+    }
+
+    // same as notify but sent even if the request fails
+    pub async fn notify_now(&self, conn: &mut DbConn<'_>, user_id: &UserId, notification: Notification) -> api::Result<()> {
+        if let Some(to) = conn.tx().await?.get_user_contact_email(user_id).await? {
+            tokio::spawn(send(self.notifier.clone(), to, notification).in_current_span());
+        }
+        Ok(())
+    }
+}
+
+async fn send(notifier: Arc<dyn Notifier>, to: String, notification: Notification) {
+    if let Err(e) = notifier.notify(&to, &notification).await {
+        error!("failed to send notification {:?}: {:?}", notification, e);
+    }
+}
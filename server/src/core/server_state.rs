@@ -0,0 +1,32 @@
+use common::{api::{self, SecretServerState}, crypto::crypto_boxes::AeadBox};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+
+use crate::state::State;
+
+// authenticated alongside every sealed server state
+#[derive(Serialize, Deserialize, Debug)]
+struct ServerStateAd {
+    purpose: String, // DISPLAY_NAME of the RPC which issued the state
+    expiration: i64,
+}
+
+impl State {
+    pub fn server_state_seal<T: Serialize>(&self, purpose: &str, state: &T) -> api::Result<SecretServerState> {
+        let ad = ServerStateAd {
+            purpose: purpose.to_owned(),
+            expiration: self.clock.now() + self.config.server_state_duration_sec as i64,
+        };
+
+        Ok(AeadBox::seal(&self.secret_key[..], state, &ad)?.into())
+    }
+
+    pub fn server_state_unseal<T: DeserializeOwned>(&self, purpose: &str, secret_server_state: &SecretServerState) -> api::Result<T> {
+        let (state, ad) = AeadBox::<T, ServerStateAd>::unseal(&self.secret_key[..], secret_server_state.as_slice())?;
+
+        if ad.purpose != purpose || ad.expiration <= self.clock.now() {
+            return Err(api::Error::InvalidServerState);
+        }
+
+        Ok(state)
+    }
+}
@@ -1,17 +1,43 @@
-use common::{api::{self, UserId, session_token::{Clearance, SessionToken}}, crypto::crypto_boxes::AuthBox};
+use std::{sync::Arc, time::Duration};
+
+use common::{api::{self, DevicePublicKey, ListSessions, ListSessionsRet, Logout, RefreshSession, RefreshSessionRet, RevokeSession, RpcTrait, UserId, session_token::{Clearance, SessionProof, SessionToken}}, crypto::crypto_boxes::AuthBox};
 use common::crypto::crypto_boxes::Auth;
+use tracing::{Instrument, debug, info_span};
 
-use crate::{db::sql::TxConn, state::State};
+use crate::{db::{DbConn, sql::TxConn}, request_dispatcher::{Req, log_error}, state::State};
 
 impl State {
-    pub fn session_token_new_sealed(&self, user_id: UserId, version_master_key: u32, lack_second_factor: bool, auto_logout: bool, uber: bool) -> eyre::Result<AuthBox<SessionToken>> {
-        SessionToken::new(user_id, version_master_key, lack_second_factor, auto_logout, uber).authenticate(&self.secret_key[..])
+    // also registers the session so that it can be listed and revoked
+    #[allow(clippy::too_many_arguments)]
+    pub async fn session_token_new_sealed(&self, conn: &mut TxConn, req: &Req, device_label: &str, device_public_key: &DevicePublicKey, user_id: UserId, version_master_key: u32, lack_second_factor: bool, recovery: bool, auto_logout: bool, uber: bool) -> api::Result<AuthBox<SessionToken>> {
+        if device_public_key.as_slice().len() != 32 {
+            return Err(api::Error::InvalidSessionProof);
+        }
+
+        let session_token = SessionToken::new(&*self.clock, user_id, version_master_key, device_public_key.clone(), lack_second_factor, recovery, auto_logout, uber);
+
+        let device_label = device_label.chars().take(64).collect::<String>();
+        conn.new_session(&session_token.session_id, &session_token.user_id, &device_label, &req.ip.to_string(), self.clock.now()).await?;
+
+        Ok(session_token.authenticate(&self.secret_key[..])?)
+    }
+
+    // must be checked before the RPC itself validates the token
+    pub async fn session_proof_check(&self, conn: &mut DbConn<'_>, auth_session_token: &AuthBox<SessionToken>, session_proof: Option<&SessionProof>, rpc: &[u8]) -> api::Result<()> {
+        let t = auth_session_token.get_verified(&self.secret_key[..]).map_err(|_| api::Error::InvalidSessionToken)?;
+        let session_proof = session_proof.ok_or(api::Error::InvalidSessionProof)?;
+
+        session_proof.verify(&*self.clock, self.config.session_proof_max_skew_sec, &t.device_public_key, rpc)?;
+
+        // the nonce only needs to be remembered for as long as the timestamp is acceptable.
+        // it's recorded outside of the transaction so that failed requests can't be replayed either
+        conn.std().await?.add_session_proof_nonce(&session_proof.nonce, session_proof.timestamp + self.config.session_proof_max_skew_sec as i64).await
     }
 
     pub async fn session_token_unseal_refreshed_and_validated(&self, conn: &mut TxConn, auth_session_token: &AuthBox<SessionToken>, required_clearance: Clearance) -> api::Result<SessionToken> {
         let mut t = auth_session_token.get_verified(&self.secret_key[..])?;
         
-        let adj_now = t.adjusted_now()?;
+        let adj_now = t.adjusted_now(&*self.clock)?;
 
         t.validate_at(
                 adj_now,
@@ -23,15 +49,122 @@ impl State {
 
         t.refresh_to(adj_now, self.config.session_token_uber_duration_sec);
 
-        if conn.get_user_version_master_key(&t.user_id).await? != t.version_master_key {
+        // the user might have been deleted
+        let version_master_key = conn.get_user_version_master_key(&t.user_id).await.map_err(|e| match e {
+            api::Error::NotFound => api::Error::InvalidSessionToken,
+            e => e,
+        })?;
+        if version_master_key != t.version_master_key {
+            return Err(api::Error::InvalidSessionToken);
+        }
+
+        if conn.is_session_revoked(&t.session_id).await? {
             return Err(api::Error::InvalidSessionToken);
         }
 
+        // fails if the session has been revoked
+        conn.touch_session(&t.session_id, &t.user_id, self.clock.now()).await?;
+
         Ok(t)
     }
 
+    // the token must have been validated beforehand
+    pub fn session_token_refreshed_sealed(&self, auth_session_token: &AuthBox<SessionToken>) -> api::Result<AuthBox<SessionToken>> {
+        let mut t = auth_session_token.get_verified(&self.secret_key[..])?;
+        t.refresh_to(t.adjusted_now(&*self.clock)?, self.config.session_token_uber_duration_sec);
+        Ok(self.session_token_seal(&t)?)
+    }
+
     pub fn session_token_seal(&self, session_token: &SessionToken) -> eyre::Result<AuthBox<SessionToken>> {
         session_token.authenticate(&self.secret_key[..])
     }
-}
 
+    pub async fn list_sessions(&self, args: &ListSessions, conn: &mut DbConn<'_>) -> api::Result<<ListSessions as RpcTrait>::Ret> {
+        let SessionToken{user_id, session_id, .. } = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::LoggedIn).await?;
+
+        async {
+            let mut sessions = conn.tx().await?.get_user_sessions(&user_id).await?;
+            for s in sessions.iter_mut() {
+                s.current = s.session_id.as_slice() == session_id.as_slice();
+            }
+
+            debug!("ok");
+            Ok(ListSessionsRet {
+                sessions,
+            })
+        }.instrument(info_span!("id", user_id = %bs58::encode(user_id.as_slice()).into_string())).await
+    }
+
+    pub async fn revoke_session(&self, args: &RevokeSession, conn: &mut DbConn<'_>) -> api::Result<<RevokeSession as RpcTrait>::Ret> {
+        let SessionToken{user_id, .. } = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::LoggedIn).await?;
+
+        async {
+            conn.tx().await?.delete_session(&args.session_id, &user_id).await?;
+
+            // the token's timestamp is unknown, so keep it revoked for as long as any token can live
+            let expires_at = self.clock.now() + self.session_token_max_duration();
+            conn.tx().await?.revoke_session(&args.session_id, expires_at).await?;
+
+            debug!("ok");
+            Ok(())
+        }.instrument(info_span!("id", user_id = %bs58::encode(user_id.as_slice()).into_string())).await
+    }
+
+    pub async fn logout(&self, args: &Logout, conn: &mut DbConn<'_>) -> api::Result<<Logout as RpcTrait>::Ret> {
+        let session_token = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::NeedSecondFactor).await?;
+
+        async {
+            let expires_at = session_token.get_expiration(self.config.session_token_one_factor_duration_sec, self.config.session_token_logged_duration_sec);
+            conn.tx().await?.revoke_session(&session_token.session_id, expires_at).await?;
+            conn.tx().await?.delete_session(&session_token.session_id, &session_token.user_id).await?;
+
+            debug!("ok");
+            Ok(())
+        }.instrument(info_span!("id", user_id = %bs58::encode(session_token.user_id.as_slice()).into_string())).await
+    }
+
+    pub async fn refresh_session(&self, args: &RefreshSession, conn: &mut DbConn<'_>) -> api::Result<<RefreshSession as RpcTrait>::Ret> {
+        let session_token = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::NeedSecondFactor).await?;
+
+        async {
+            debug!("ok");
+            Ok(RefreshSessionRet {
+                authed_session_token: self.session_token_seal(&session_token)?,
+            })
+        }.instrument(info_span!("id", user_id = %bs58::encode(session_token.user_id.as_slice()).into_string())).await
+    }
+
+    fn session_token_max_duration(&self) -> i64 {
+        self.config.session_token_one_factor_duration_sec.max(self.config.session_token_logged_duration_sec) as i64
+    }
+
+    // forgets revoked sessions once their tokens have expired, sessions that can't be used anymore and expired tmp rows.
+    // runs for the whole life of the server
+    pub async fn sessions_cleanup_task(self: Arc<Self>) {
+        loop {
+            let next_expiration = self.sessions_cleanup().await.unwrap_or_else(|e| {
+                log_error(&e);
+                None
+            });
+
+            let max_interval = self.config.sessions_cleanup_max_interval_sec as i64;
+            let interval = next_expiration.map_or(max_interval, |t| (t - self.clock.now()).clamp(1, max_interval));
+            tokio::time::sleep(Duration::from_secs(interval as u64)).await;
+        }
+    }
+
+    async fn sessions_cleanup(&self) -> api::Result<Option<i64>> {
+        let mut conn = self.db_pool.acquire();
+        let conn = conn.std().await?;
+        let now = self.clock.now();
+
+        let revoked = conn.delete_expired_revoked_sessions(now).await?;
+        let stale = conn.delete_sessions_created_before(now - self.session_token_max_duration()).await?;
+        let nonces = conn.delete_expired_session_proof_nonces(now).await?;
+        let trusted_devices = conn.delete_trusted_devices_created_before(now - self.config.trusted_device_duration_sec as i64).await?;
+        let tmp = conn.delete_expired_tmp(now).await?;
+        debug!(revoked, stale, nonces, trusted_devices, tmp, "sessions cleaned up");
+
+        conn.get_next_revoked_session_expiration().await
+    }
+}
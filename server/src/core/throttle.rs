@@ -0,0 +1,205 @@
+use std::net::IpAddr;
+
+use async_trait::async_trait;
+use common::api::{self, UserId, Username};
+use tracing::info;
+
+use crate::{config::Config, db::{DbConn, sql::NormalConn}, state::State};
+
+// stored in the `kind` column of `login_throttles`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ThrottleKind {
+    Username = 0,
+    RecoveryUsername = 1,
+    Ip = 2,
+    SecondFactor = 3, // the subject is the user_id
+}
+
+impl ThrottleKind {
+    fn from_recovery(recovery: bool) -> Self {
+        if recovery { Self::RecoveryUsername } else { Self::Username }
+    }
+}
+
+fn ip_subject(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+// where the counters are kept, they must survive the rollback of failed requests
+#[async_trait]
+pub trait ThrottleStore: Send {
+    async fn blocked_until(&mut self, kind: ThrottleKind, subject: &[u8]) -> api::Result<Option<i64>>;
+    // returns the new count, which starts over if the last attempt is older than `forget_before`
+    async fn add_attempt(&mut self, kind: ThrottleKind, subject: &[u8], now: i64, forget_before: i64) -> api::Result<u32>;
+    async fn remove_attempt(&mut self, kind: ThrottleKind, subject: &[u8]) -> api::Result<()>;
+    // never shortens an existing delay
+    async fn block_until(&mut self, kind: ThrottleKind, subject: &[u8], blocked_until: i64) -> api::Result<()>;
+    async fn reset(&mut self, kind: ThrottleKind, subject: &[u8]) -> api::Result<()>;
+}
+
+#[async_trait]
+impl ThrottleStore for NormalConn {
+    async fn blocked_until(&mut self, kind: ThrottleKind, subject: &[u8]) -> api::Result<Option<i64>> {
+        self.get_throttle_blocked_until(kind as u8, subject).await
+    }
+
+    async fn add_attempt(&mut self, kind: ThrottleKind, subject: &[u8], now: i64, forget_before: i64) -> api::Result<u32> {
+        self.add_throttle_attempt(kind as u8, subject, now, forget_before).await
+    }
+
+    async fn remove_attempt(&mut self, kind: ThrottleKind, subject: &[u8]) -> api::Result<()> {
+        self.remove_throttle_attempt(kind as u8, subject).await
+    }
+
+    async fn block_until(&mut self, kind: ThrottleKind, subject: &[u8], blocked_until: i64) -> api::Result<()> {
+        self.set_throttle_blocked_until(kind as u8, subject, blocked_until).await
+    }
+
+    async fn reset(&mut self, kind: ThrottleKind, subject: &[u8]) -> api::Result<()> {
+        self.delete_throttle(kind as u8, subject).await
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ThrottlePolicy {
+    pub username_free_attempts: u32,
+    pub ip_free_attempts: u32,
+    pub base_delay_sec: u32,
+    pub max_delay_sec: u32,
+    pub forget_after_sec: u32,
+}
+
+impl From<&Config> for ThrottlePolicy {
+    fn from(config: &Config) -> Self {
+        Self {
+            username_free_attempts: config.login_throttle_username_free_attempts,
+            ip_free_attempts: config.login_throttle_ip_free_attempts,
+            base_delay_sec: config.login_throttle_base_delay_sec,
+            max_delay_sec: config.login_throttle_max_delay_sec,
+            forget_after_sec: config.login_throttle_forget_after_sec,
+        }
+    }
+}
+
+impl ThrottlePolicy {
+    // fails with RateLimited if either the username or the ip is currently blocked
+    pub async fn check<S: ThrottleStore>(&self, store: &mut S, now: i64, recovery: bool, blind_username: &[u8], ip: IpAddr) -> api::Result<()> {
+        let blocked_until = std::cmp::max(
+            store.blocked_until(ThrottleKind::from_recovery(recovery), blind_username).await?,
+            store.blocked_until(ThrottleKind::Ip, &ip_subject(ip)).await?,
+        );
+
+        match blocked_until {
+            Some(blocked_until) if blocked_until > now => Err(api::Error::RateLimited { retry_after: (blocked_until - now) as u32 }),
+            _ => Ok(()),
+        }
+    }
+
+    // with OPAQUE a wrong password can be noticed by the client alone, which then never finishes the login.
+    // so every started login counts as a failure until it's finished successfully.
+    // the delay doubles with every attempt past the free ones
+    pub async fn start<S: ThrottleStore>(&self, store: &mut S, now: i64, recovery: bool, blind_username: &[u8], ip: IpAddr) -> api::Result<()> {
+        self.check(store, now, recovery, blind_username, ip).await?;
+        self.record(store, now, recovery, blind_username, ip).await
+    }
+
+    async fn record<S: ThrottleStore>(&self, store: &mut S, now: i64, recovery: bool, blind_username: &[u8], ip: IpAddr) -> api::Result<()> {
+        let forget_before = now - self.forget_after_sec as i64;
+        for (kind, subject, free_attempts) in [
+            (ThrottleKind::from_recovery(recovery), blind_username.to_vec(), self.username_free_attempts),
+            (ThrottleKind::Ip, ip_subject(ip), self.ip_free_attempts),
+        ] {
+            let attempts = store.add_attempt(kind, &subject, now, forget_before).await?;
+
+            if attempts > free_attempts {
+                let exponent = (attempts - free_attempts - 1).min(31);
+                let delay = (self.base_delay_sec as u64)
+                    .saturating_mul(1 << exponent)
+                    .min(self.max_delay_sec as u64);
+                info!("{:?} throttled for {}s after {} attempts", kind, delay, attempts);
+                store.block_until(kind, &subject, now + delay as i64).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // the username's counter is reset, but only this attempt is taken back from the ip's:
+    // an attacker could otherwise clear its ip's counter with its own account
+    pub async fn success<S: ThrottleStore>(&self, store: &mut S, recovery: bool, blind_username: &[u8], ip: IpAddr) -> api::Result<()> {
+        store.reset(ThrottleKind::from_recovery(recovery), blind_username).await?;
+        store.remove_attempt(ThrottleKind::Ip, &ip_subject(ip)).await
+    }
+}
+
+// a user_id is locked out for a while once its second factor was wrong too many times in a row.
+// unlike the password, a wrong second factor is always noticed by the server
+#[derive(Clone, Copy, Debug)]
+pub struct SecondFactorThrottlePolicy {
+    pub free_attempts: u32,
+    pub lockout_sec: u32,
+    pub forget_after_sec: u32,
+}
+
+impl From<&Config> for SecondFactorThrottlePolicy {
+    fn from(config: &Config) -> Self {
+        Self {
+            free_attempts: config.second_factor_throttle_free_attempts,
+            lockout_sec: config.second_factor_throttle_lockout_sec,
+            forget_after_sec: config.second_factor_throttle_forget_after_sec,
+        }
+    }
+}
+
+impl SecondFactorThrottlePolicy {
+    pub async fn check<S: ThrottleStore>(&self, store: &mut S, now: i64, user_id: &UserId) -> api::Result<()> {
+        match store.blocked_until(ThrottleKind::SecondFactor, user_id.as_slice()).await? {
+            Some(blocked_until) if blocked_until > now => Err(api::Error::RateLimited { retry_after: (blocked_until - now) as u32 }),
+            _ => Ok(()),
+        }
+    }
+
+    // every failure past the free ones locks the user_id out again
+    pub async fn failure<S: ThrottleStore>(&self, store: &mut S, now: i64, user_id: &UserId) -> api::Result<()> {
+        let failures = store.add_attempt(ThrottleKind::SecondFactor, user_id.as_slice(), now, now - self.forget_after_sec as i64).await?;
+        if failures >= self.free_attempts {
+            info!("second factor locked out for {}s after {} failures", self.lockout_sec, failures);
+            store.block_until(ThrottleKind::SecondFactor, user_id.as_slice(), now + self.lockout_sec as i64).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn success<S: ThrottleStore>(&self, store: &mut S, user_id: &UserId) -> api::Result<()> {
+        store.reset(ThrottleKind::SecondFactor, user_id.as_slice()).await
+    }
+}
+
+impl State {
+    // fails with RateLimited, otherwise to be called when the password check starts, before answering anything
+    pub async fn login_throttle_start(&self, conn: &mut DbConn<'_>, recovery: bool, username: &Username, ip: IpAddr) -> api::Result<()> {
+        let blind_username = self.blind_username(username)?;
+        ThrottlePolicy::from(&self.config).start(conn.std().await?, self.clock.now(), recovery, &blind_username, ip).await
+    }
+
+    // to be called once the password check succeeded
+    pub async fn login_throttle_success(&self, conn: &mut DbConn<'_>, recovery: bool, username: &Username, ip: IpAddr) -> api::Result<()> {
+        let blind_username = self.blind_username(username)?;
+        ThrottlePolicy::from(&self.config).success(conn.std().await?, recovery, &blind_username, ip).await
+    }
+
+    // the counters are kept outside of the transaction, which is rolled back when the second factor is wrong
+    pub async fn second_factor_throttle_check(&self, conn: &mut DbConn<'_>, user_id: &UserId) -> api::Result<()> {
+        SecondFactorThrottlePolicy::from(&self.config).check(conn.std().await?, self.clock.now(), user_id).await
+    }
+
+    pub async fn second_factor_throttle_failure(&self, conn: &mut DbConn<'_>, user_id: &UserId) -> api::Result<()> {
+        SecondFactorThrottlePolicy::from(&self.config).failure(conn.std().await?, self.clock.now(), user_id).await
+    }
+
+    pub async fn second_factor_throttle_success(&self, conn: &mut DbConn<'_>, user_id: &UserId) -> api::Result<()> {
+        SecondFactorThrottlePolicy::from(&self.config).success(conn.std().await?, user_id).await
+    }
+}
@@ -0,0 +1,68 @@
+use common::{api::{self, ListTrustedDevices, ListTrustedDevicesRet, RevokeTrustedDevice, RpcTrait, UserId, session_token::{Clearance, SessionToken}, trusted_device_token::TrustedDeviceToken}, crypto::crypto_boxes::{Seal, SecretBox}};
+use tracing::{Instrument, debug, info_span};
+
+use crate::{db::{DbConn, sql::TxConn}, state::State};
+
+impl State {
+    // also registers the device so that it can be listed and revoked
+    pub async fn trusted_device_token_new_sealed(&self, conn: &mut TxConn, session_token: &SessionToken) -> api::Result<SecretBox<TrustedDeviceToken>> {
+        let device_label = conn.get_session_device_label(&session_token.session_id).await?;
+
+        let trusted_device_token = TrustedDeviceToken::new(&*self.clock, session_token.user_id.clone(), session_token.version_master_key);
+        conn.new_trusted_device(&trusted_device_token.trusted_device_id, &trusted_device_token.user_id, &device_label, self.clock.now()).await?;
+
+        Ok(trusted_device_token.seal(&self.secret_key[..])?)
+    }
+
+    // a token that can't be used anymore isn't an error, the second factor is simply asked for again
+    pub async fn trusted_device_token_check(&self, conn: &mut TxConn, secret_trusted_device_token: &SecretBox<TrustedDeviceToken>, user_id: &UserId, version_master_key: u32) -> api::Result<bool> {
+        let t = match secret_trusted_device_token.unseal(&self.secret_key[..]) {
+            Ok(t) => t,
+            Err(_) => {
+                debug!("invalid trusted device token");
+                return Ok(false);
+            }
+        };
+
+        if t.user_id.as_slice() != user_id.as_slice()
+            || t.version_master_key != version_master_key
+            || !t.is_valid_at(self.clock.now(), self.config.trusted_device_duration_sec) {
+            debug!("trusted device token not valid anymore");
+            return Ok(false);
+        }
+
+        match conn.touch_trusted_device(&t.trusted_device_id, user_id, self.clock.now()).await {
+            Ok(()) => Ok(true),
+            Err(api::Error::NotFound) => {
+                debug!("trusted device revoked");
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn list_trusted_devices(&self, args: &ListTrustedDevices, conn: &mut DbConn<'_>) -> api::Result<<ListTrustedDevices as RpcTrait>::Ret> {
+        let SessionToken{user_id, .. } = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::LoggedIn).await?;
+
+        async {
+            let trusted_devices = conn.tx().await?.get_user_trusted_devices(&user_id).await?;
+
+            debug!("ok");
+            Ok(ListTrustedDevicesRet {
+                trusted_devices,
+            })
+        }.instrument(info_span!("id", user_id = %bs58::encode(user_id.as_slice()).into_string())).await
+    }
+
+    pub async fn revoke_trusted_device(&self, args: &RevokeTrustedDevice, conn: &mut DbConn<'_>) -> api::Result<<RevokeTrustedDevice as RpcTrait>::Ret> {
+        let SessionToken{user_id, .. } = self.session_token_unseal_refreshed_and_validated(conn.tx().await?, &args.authed_session_token, Clearance::LoggedIn).await?;
+
+        async {
+            // the token itself stays valid, but it's useless without its registry entry
+            conn.tx().await?.delete_trusted_device(&args.trusted_device_id, &user_id).await?;
+
+            debug!("ok");
+            Ok(())
+        }.instrument(info_span!("id", user_id = %bs58::encode(user_id.as_slice()).into_string())).await
+    }
+}
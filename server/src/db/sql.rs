@@ -1,10 +1,14 @@
 use std::time::Duration;
+use std::future::Future;
 
-use common::{api::{self, ExportKey, MasterKey, Totp, TotpAlgo, TotpSecret, UserId, Username, private_data::PrivateData}, crypto::crypto_boxes::SecretBox};
+use common::{api::{self, ExportKey, MasterKey, SessionId, SessionInfo, Totp, TotpAlgo, TotpSecret, TrustedDeviceId, TrustedDeviceInfo, UserId, Username, WebauthnCredentialId, private_data::PrivateData}, crypto::crypto_boxes::{AeadBox, SecretBox}};
 use sqlx::{Database, Executor, MySql, Pool, Row, Transaction, mysql::{MySqlConnectOptions, MySqlDatabaseError, MySqlPoolOptions, MySqlRow}, pool::PoolConnection};
 use async_trait::async_trait;
+use futures_util::future::BoxFuture;
 use tracing::error;
 use std::str::FromStr;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 
 // making things generic over the Db implementation doesn't seem very useful at this point, but NewType are nices
 #[derive(Debug)]
@@ -16,11 +20,22 @@ pub struct TxConn (Transaction<'static, MySql>);
 #[derive(Debug)]
 pub struct NormalConn (PoolConnection<MySql>);
 
-#[derive(Debug)]
 pub struct DbConn<'pool> {
     pool: &'pool Pool<MySql>,
     tx: Option<TxConn>,
     normal: Option<NormalConn>,
+    on_commit: Vec<BoxFuture<'static, ()>>,
+}
+
+impl std::fmt::Debug for DbConn<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DbConn")
+            .field("pool", &self.pool)
+            .field("tx", &self.tx)
+            .field("normal", &self.normal)
+            .field("on_commit", &self.on_commit.len())
+            .finish()
+    }
 }
 
 impl<'pool> DbConn<'pool> {
@@ -29,6 +44,7 @@ impl<'pool> DbConn<'pool> {
             pool,
             tx: None,
             normal: None,
+            on_commit: Vec::new(),
         }
     }
 
@@ -36,9 +52,17 @@ impl<'pool> DbConn<'pool> {
         if let Some(tx) = self.tx.take() {
             tx.0.commit().await.map_err(|e| api::Error::ServerSideError(e.into()))?;
         }
+        for f in self.on_commit.drain(..) {
+            tokio::spawn(f);
+        }
         Ok(())
     }
 
+    // spawns `f` in the background once the transaction is commited, it's dropped on rollback
+    pub fn on_commit(&mut self, f: impl Future<Output = ()> + Send + 'static) {
+        self.on_commit.push(Box::pin(f));
+    }
+
     pub async fn rollback(mut self) -> api::Result<()> {
         if let Some(tx) = self.tx.take() {
             tx.0.rollback().await.map_err(|e| api::Error::ServerSideError(e.into()))?;
@@ -101,7 +125,11 @@ impl DbPool {
     }
 
     pub async fn new() -> eyre::Result<Self> {
-        
+        Self::open(common::consts::DATABASE_NAME).await
+    }
+
+    // creates and initializes the database if needed, the tests use their own
+    pub async fn open(database_name: &str) -> eyre::Result<Self> {
         let options = MySqlConnectOptions::new()
             .host("localhost")
             .port(4000)
@@ -112,13 +140,13 @@ impl DbPool {
             .connect_with(options.clone())
             .await?;
 
-        sqlx::query(&format!("create database if not exists `{}`", common::consts::DATABASE_NAME)).execute(&pool).await?;
+        sqlx::query(&format!("create database if not exists `{}`", database_name)).execute(&pool).await?;
         drop(pool);
 
         let pool = MySqlPoolOptions::new()
             // .connect_timeout(Duration::from_secs(1))
             .connect_with(
-                options.database(common::consts::DATABASE_NAME)
+                options.database(database_name)
             )
             .await?;
 
@@ -153,7 +181,7 @@ impl DbPool {
 
         conn.execute("
             create table if not exists `tmp` (
-                `session_id` binary(32) not null,
+                `session_id` varbinary(32) not null,
                 `ip` varbinary(16) not null,
                 `expiration` timestamp not null,
                 `field` varchar(32) not null, 
@@ -167,10 +195,12 @@ impl DbPool {
                 `user_id`             binary(16)      not null,
                 `version_master_key`  int unsigned    not null, -- needed to guarantee data coherency, but also used to invalidate all session tokens
                 `secret_private_data` varbinary(1024)         , -- sealed with master_key
-                `totp_secret`         varbinary(32)           ,
+                `secret_totp_secret`  varbinary(256)          , -- sealed with the server's totp_key, user_id as associated data
                 `totp_digits`         tinyint unsigned        , -- u8
                 `totp_algo`           varchar(16)             ,
                 `totp_period`         int unsigned            , -- u32
+                `totp_last_counter`   bigint unsigned         , -- u64, last accepted HOTP counter, used to refuse replayed codes
+                `contact_email`       varchar(254)            , -- only set once verified, used for security notifications
                 primary key (`user_id`)
             )
         ").await?;
@@ -178,7 +208,7 @@ impl DbPool {
         conn.execute("
             create table if not exists `credentials` (
                 `recovery`                tinyint unsigned not null,
-                `username`                varbinary(32)    not null,
+                `username`                varbinary(32)    not null, -- blind index, see blind_username
                 `opaque_password`         varbinary(1024)  not null,
                 `secret_master_key`       varbinary(256)   not null, -- sealed with export_key
                 `secret_export_key`       varbinary(256)   not null, -- sealed with master_key, useful when rotating master_key
@@ -188,6 +218,133 @@ impl DbPool {
             )
     ").await?;
 
+        conn.execute("
+            create table if not exists `backup_codes` (
+                `user_id`                 binary(16)       not null,
+                `hashed_code`             binary(32)       not null, -- HMAC-SHA256 keyed with the server's secret_key
+                primary key (`user_id`, `hashed_code`)
+            )
+        ").await?;
+
+        conn.execute("
+            create table if not exists `webauthn_credentials` (
+                `credential_id`           varbinary(1023)  not null,
+                `user_id`                 binary(16)       not null,
+                `name`                    varchar(64)      not null,
+                `public_key`              varbinary(1024)  not null, -- COSE_Key
+                `sign_count`              int unsigned     not null, -- u32, used to detect cloned authenticators
+                primary key (`credential_id`),
+                index `index-user_id` (`user_id`)
+            )
+        ").await?;
+
+        conn.execute("
+            create table if not exists `sessions` (
+                `session_id`              binary(16)       not null,
+                `user_id`                 binary(16)       not null,
+                `device_label`            varchar(64)      not null, -- given by the client
+                `ip`                      varbinary(16)    not null, -- at login
+                `created`                 timestamp        not null,
+                `last_seen`               timestamp        not null,
+                primary key (`session_id`),
+                index `index-user_id` (`user_id`)
+            )
+        ").await?;
+
+        conn.execute("
+            create table if not exists `revoked_sessions` (
+                `session_id`              binary(16)       not null,
+                `expires_at`              timestamp        not null, -- the session's tokens are invalid past that time anyway
+                primary key (`session_id`),
+                index `index-expires_at` (`expires_at`)
+            )
+        ").await?;
+
+        conn.execute("
+            create table if not exists `session_proof_nonces` (
+                `nonce`                   binary(16)       not null,
+                `expires_at`              timestamp        not null, -- the proof's timestamp isn't accepted anymore past that time
+                primary key (`nonce`),
+                index `index-expires_at` (`expires_at`)
+            )
+        ").await?;
+
+        conn.execute("
+            create table if not exists `trusted_devices` (
+                `trusted_device_id`       binary(16)       not null,
+                `user_id`                 binary(16)       not null,
+                `device_label`            varchar(64)      not null, -- of the session in which the device was trusted
+                `created`                 timestamp        not null,
+                `last_used`               timestamp        not null,
+                primary key (`trusted_device_id`),
+                index `index-user_id` (`user_id`)
+            )
+        ").await?;
+
+        conn.execute("
+            create table if not exists `pending_recoveries` (
+                `user_id`                 binary(16)       not null,
+                `available_at`            timestamp        not null, -- recovery logins are refused until then
+                primary key (`user_id`)
+            )
+        ").await?;
+
+        conn.execute("
+            create table if not exists `pending_deletions` (
+                `user_id`                 binary(16)       not null,
+                `purge_at`                timestamp        not null, -- a normal login cancels the deletion until then
+                primary key (`user_id`),
+                index `index-purge_at` (`purge_at`)
+            )
+        ").await?;
+
+        conn.execute("
+            create table if not exists `migrations` (
+                `name`                    varchar(64)      not null,
+                `applied`                 timestamp        not null,
+                primary key (`name`)
+            )
+        ").await?;
+
+        // a new database doesn't need to be migrated
+        sqlx::query("insert ignore into `migrations` select ?, now() from dual where not exists (select 1 from `credentials`)")
+            .bind(MIGRATION_BLIND_INDEXED_USERNAMES)
+            .execute(&mut *conn).await?;
+
+        // neither does one whose `users` table was created without the clear `totp_secret` column
+        sqlx::query("insert ignore into `migrations` select ?, now() from dual where not exists (
+                select 1 from information_schema.columns where `table_schema` = database() and `table_name` = 'users' and `column_name` = 'totp_secret'
+            )")
+            .bind(MIGRATION_SEALED_TOTP_SECRETS)
+            .execute(&mut *conn).await?;
+
+        // nor one whose `tmp` table was created with a padded `session_id`
+        sqlx::query("insert ignore into `migrations` select ?, now() from dual where not exists (
+                select 1 from information_schema.columns where `table_schema` = database() and `table_name` = 'tmp' and `column_name` = 'session_id' and `data_type` = 'binary'
+            )")
+            .bind(MIGRATION_VARBINARY_TMP_SESSION_IDS)
+            .execute(&mut *conn).await?;
+
+        conn.execute("
+            create table if not exists `username_skeletons` (
+                `skeleton`                varbinary(32)    not null, -- blind indexed, see common::username::skeletons
+                `user_id`                 binary(16)       not null,
+                primary key (`skeleton`),
+                index `index-user_id` (`user_id`)
+            )
+        ").await?;
+
+        conn.execute("
+            create table if not exists `login_throttles` (
+                `kind`                    tinyint unsigned not null, -- see core::throttle::ThrottleKind
+                `subject`                 varbinary(32)    not null, -- blind indexed username, ip or user_id
+                `failures`                int unsigned     not null, -- consecutive logins started and not successfully finished, or wrong second factors
+                `last_failure`            timestamp        not null, -- start of the last of them
+                `blocked_until`           timestamp        not null,
+                primary key (`kind`, `subject`)
+            )
+        ").await?;
+
         Ok(())
     }
 }
@@ -196,11 +353,271 @@ impl DbPool {
 // queries that are only defined on a transactionnal connection
 impl TxConn {
     // #[tracing::instrument]
-    pub async fn restore_tmp(&mut self, session_id: &[u8], field: &str) -> api::Result<Vec<u8>> {        
-        let row: MySqlRow = sqlx::query("select `data` from `tmp` where `session_id` = ? and `field` = ?")
+    pub async fn new_session(&mut self, session_id: &SessionId, user_id: &UserId, device_label: &str, ip: &str, now: i64) -> api::Result<()> {
+        sqlx::query("insert into `sessions` values (?, ?, ?, INET6_ATON(?), FROM_UNIXTIME(?), FROM_UNIXTIME(?))")
+            .bind(session_id.as_slice())
+            .bind(user_id.as_slice())
+            .bind(device_label)
+            .bind(ip)
+            .bind(now)
+            .bind(now)
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        Ok(())
+    }
+
+    // #[tracing::instrument]
+    pub async fn touch_session(&mut self, session_id: &SessionId, user_id: &UserId, now: i64) -> api::Result<()> {
+        sqlx::query("select 1 from `sessions` where `session_id` = ? and `user_id` = ?")
+            .bind(session_id.as_slice())
+            .bind(user_id.as_slice())
+            .fetch_one(self.conn()).await.map_err(|e| {
+                match e {
+                    sqlx::Error::RowNotFound => api::Error::InvalidSessionToken,
+                    _ => api::Error::ServerSideError(e.into()),
+                }
+            })?;
+
+        sqlx::query("update `sessions` set `last_seen` = FROM_UNIXTIME(?) where `session_id` = ?")
+            .bind(now)
+            .bind(session_id.as_slice())
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        Ok(())
+    }
+
+    // #[tracing::instrument]
+    pub async fn get_user_sessions(&mut self, user_id: &UserId) -> api::Result<Vec<SessionInfo>> {
+        let rows = sqlx::query("select `session_id`, `device_label`, INET6_NTOA(`ip`), unix_timestamp(`created`), unix_timestamp(`last_seen`) from `sessions` where `user_id` = ? order by `last_seen` desc")
+            .bind(user_id.as_slice())
+            .fetch_all(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        rows.iter().map(|row| Ok(SessionInfo {
+            session_id: SessionId::from_vec(row.try_get(0)?),
+            device_label: row.try_get(1)?,
+            ip: row.try_get(2)?,
+            created: row.try_get(3)?,
+            last_seen: row.try_get(4)?,
+            current: false,
+        })).collect::<Result<_, sqlx::Error>>().map_err(|e| api::Error::ServerSideError(e.into()))
+    }
+
+    // #[tracing::instrument]
+    pub async fn revoke_session(&mut self, session_id: &SessionId, expires_at: i64) -> api::Result<()> {
+        sqlx::query("insert ignore into `revoked_sessions` values (?, FROM_UNIXTIME(?))")
+            .bind(session_id.as_slice())
+            .bind(expires_at)
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        Ok(())
+    }
+
+    // #[tracing::instrument]
+    pub async fn is_session_revoked(&mut self, session_id: &SessionId) -> api::Result<bool> {
+        let row = sqlx::query("select 1 from `revoked_sessions` where `session_id` = ?")
+            .bind(session_id.as_slice())
+            .fetch_optional(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        Ok(row.is_some())
+    }
+
+    // #[tracing::instrument]
+    pub async fn delete_user_sessions_except(&mut self, user_id: &UserId, session_id: &SessionId) -> api::Result<()> {
+        sqlx::query("delete from `sessions` where `user_id` = ? and `session_id` != ?")
+            .bind(user_id.as_slice())
+            .bind(session_id.as_slice())
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        Ok(())
+    }
+
+    // #[tracing::instrument]
+    pub async fn delete_session(&mut self, session_id: &SessionId, user_id: &UserId) -> api::Result<()> {
+        let res = sqlx::query("delete from `sessions` where `session_id` = ? and `user_id` = ?")
+            .bind(session_id.as_slice())
+            .bind(user_id.as_slice())
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        if res.rows_affected() != 1 {
+            return Err(api::Error::NotFound);
+        }
+        Ok(())
+    }
+
+    // #[tracing::instrument]
+    pub async fn get_session_device_label(&mut self, session_id: &SessionId) -> api::Result<String> {
+        let row = sqlx::query("select `device_label` from `sessions` where `session_id` = ?")
+            .bind(session_id.as_slice())
+            .fetch_one(self.conn()).await.map_err(|e| {
+                match e {
+                    sqlx::Error::RowNotFound => api::Error::NotFound,
+                    _ => api::Error::ServerSideError(e.into()),
+                }
+            })?;
+
+        row.try_get(0).map_err(|e| api::Error::ServerSideError(e.into()))
+    }
+
+    // #[tracing::instrument]
+    pub async fn new_trusted_device(&mut self, trusted_device_id: &TrustedDeviceId, user_id: &UserId, device_label: &str, now: i64) -> api::Result<()> {
+        sqlx::query("insert into `trusted_devices` values (?, ?, ?, FROM_UNIXTIME(?), FROM_UNIXTIME(?))")
+            .bind(trusted_device_id.as_slice())
+            .bind(user_id.as_slice())
+            .bind(device_label)
+            .bind(now)
+            .bind(now)
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        Ok(())
+    }
+
+    // #[tracing::instrument]
+    pub async fn touch_trusted_device(&mut self, trusted_device_id: &TrustedDeviceId, user_id: &UserId, now: i64) -> api::Result<()> {
+        let res = sqlx::query("update `trusted_devices` set `last_used` = FROM_UNIXTIME(?) where `trusted_device_id` = ? and `user_id` = ?")
+            .bind(now)
+            .bind(trusted_device_id.as_slice())
+            .bind(user_id.as_slice())
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        // revoked
+        if res.rows_affected() != 1 {
+            return Err(api::Error::NotFound);
+        }
+        Ok(())
+    }
+
+    // #[tracing::instrument]
+    pub async fn get_user_trusted_devices(&mut self, user_id: &UserId) -> api::Result<Vec<TrustedDeviceInfo>> {
+        let rows = sqlx::query("select `trusted_device_id`, `device_label`, unix_timestamp(`created`), unix_timestamp(`last_used`) from `trusted_devices` where `user_id` = ? order by `last_used` desc")
+            .bind(user_id.as_slice())
+            .fetch_all(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        rows.iter().map(|row| Ok(TrustedDeviceInfo {
+            trusted_device_id: TrustedDeviceId::from_vec(row.try_get(0)?),
+            device_label: row.try_get(1)?,
+            created: row.try_get(2)?,
+            last_used: row.try_get(3)?,
+        })).collect::<Result<_, sqlx::Error>>().map_err(|e| api::Error::ServerSideError(e.into()))
+    }
+
+    // #[tracing::instrument]
+    pub async fn delete_trusted_device(&mut self, trusted_device_id: &TrustedDeviceId, user_id: &UserId) -> api::Result<()> {
+        let res = sqlx::query("delete from `trusted_devices` where `trusted_device_id` = ? and `user_id` = ?")
+            .bind(trusted_device_id.as_slice())
+            .bind(user_id.as_slice())
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        if res.rows_affected() != 1 {
+            return Err(api::Error::NotFound);
+        }
+        Ok(())
+    }
+
+    // #[tracing::instrument]
+    pub async fn delete_user_trusted_devices(&mut self, user_id: &UserId) -> api::Result<()> {
+        sqlx::query("delete from `trusted_devices` where `user_id` = ?")
+            .bind(user_id.as_slice())
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        Ok(())
+    }
+
+    // #[tracing::instrument]
+    pub async fn delete_user_sessions(&mut self, user_id: &UserId) -> api::Result<()> {
+        sqlx::query("delete from `sessions` where `user_id` = ?")
+            .bind(user_id.as_slice())
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        Ok(())
+    }
+
+    // everything the user owns. the master key is only ever stored sealed in `credentials`,
+    // so any copy of the private data left elsewhere (backups, logs) can't be decrypted anymore
+    // #[tracing::instrument]
+    pub async fn delete_user(&mut self, user_id: &UserId) -> api::Result<()> {
+        for table in ["credentials", "username_skeletons", "backup_codes", "webauthn_credentials", "sessions", "trusted_devices", "pending_recoveries", "pending_deletions"] {
+            sqlx::query(&format!("delete from `{}` where `user_id` = ?", table))
+                .bind(user_id.as_slice())
+                .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        }
+
+        // pending TOTP enrollments and webauthn challenges are keyed by user_id
+        sqlx::query("delete from `tmp` where `session_id` = ?")
+            .bind(user_id.as_slice())
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        let res = sqlx::query("delete from `users` where `user_id` = ?")
+            .bind(user_id.as_slice())
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        if res.rows_affected() != 1 {
+            return Err(api::Error::NotFound);
+        }
+        Ok(())
+    }
+
+    // #[tracing::instrument]
+    pub async fn set_pending_deletion(&mut self, user_id: &UserId, purge_at: i64) -> api::Result<()> {
+        sqlx::query("replace into `pending_deletions` values (?, FROM_UNIXTIME(?))")
+            .bind(user_id.as_slice())
+            .bind(purge_at)
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        Ok(())
+    }
+
+    // returns whether a deletion was pending
+    // #[tracing::instrument]
+    pub async fn delete_pending_deletion(&mut self, user_id: &UserId) -> api::Result<bool> {
+        let res = sqlx::query("delete from `pending_deletions` where `user_id` = ?")
+            .bind(user_id.as_slice())
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        Ok(res.rows_affected() == 1)
+    }
+
+    // #[tracing::instrument]
+    pub async fn get_users_to_purge(&mut self, now: i64) -> api::Result<Vec<UserId>> {
+        let rows = sqlx::query("select `user_id` from `pending_deletions` where `purge_at` <= FROM_UNIXTIME(?) for update")
+            .bind(now)
+            .fetch_all(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        rows.iter().map(|row| Ok(UserId::from_vec(row.try_get(0)?))).collect::<Result<_, sqlx::Error>>().map_err(|e| api::Error::ServerSideError(e.into()))
+    }
+
+    // #[tracing::instrument]
+    pub async fn set_user_contact_email(&mut self, user_id: &UserId, contact_email: &str) -> api::Result<()> {
+        sqlx::query("update `users` set `contact_email` = ? where `user_id` = ?")
+            .bind(contact_email)
+            .bind(user_id.as_slice())
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        Ok(())
+    }
+
+    // #[tracing::instrument]
+    pub async fn get_user_contact_email(&mut self, user_id: &UserId) -> api::Result<Option<String>> {
+        let row = sqlx::query("select `contact_email` from `users` where `user_id` = ?")
+            .bind(user_id.as_slice())
+            .fetch_one(self.conn()).await.map_err(|e| {
+                match e {
+                    sqlx::Error::RowNotFound => api::Error::NotFound,
+                    _ => api::Error::ServerSideError(e.into()),
+                }
+            })?;
+
+        row.try_get(0).map_err(|e| api::Error::ServerSideError(e.into()))
+    }
+
+    // #[tracing::instrument]
+    pub async fn delete_pending_recovery(&mut self, user_id: &UserId) -> api::Result<()> {
+        sqlx::query("delete from `pending_recoveries` where `user_id` = ?")
+            .bind(user_id.as_slice())
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        Ok(())
+    }
+
+    // #[tracing::instrument]
+    pub async fn restore_tmp(&mut self, session_id: &[u8], field: &str, now: i64) -> api::Result<Vec<u8>> {        
+        let row: MySqlRow = sqlx::query("select `data` from `tmp` where `session_id` = ? and `field` = ? and `expiration` > FROM_UNIXTIME(?) for update")
             .bind(session_id)
             .bind(field)
-            .fetch_one(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+            .bind(now)
+            .fetch_one(self.conn()).await.map_err(|e| {
+                match e {
+                    sqlx::Error::RowNotFound => api::Error::NotFound,
+                    _ => api::Error::ServerSideError(e.into()),
+                }
+            })?;
         let state: Vec<u8> = row.try_get(0).map_err(|e| api::Error::ServerSideError(e.into()))?;
 
         sqlx::query("delete from `tmp` where `session_id` = ? and `field` = ?")
@@ -270,10 +687,10 @@ impl TxConn {
 
     #[allow(clippy::too_many_arguments)]
     // #[tracing::instrument]
-    pub async fn new_credentials(&mut self, recovery: bool, user_id: &UserId, username: &Username, opaque_password: &[u8], secret_master_key: &SecretBox<MasterKey>, secret_export_key: &SecretBox<ExportKey>) -> api::Result<()> {
+    pub async fn new_credentials(&mut self, recovery: bool, user_id: &UserId, blind_username: &[u8], opaque_password: &[u8], secret_master_key: &SecretBox<MasterKey>, secret_export_key: &SecretBox<ExportKey>) -> api::Result<()> {
         sqlx::query("insert into `credentials` (`recovery`, `username`, `opaque_password`, `secret_master_key`, `secret_export_key`, `user_id`) values (?, ?, ?, ?, ?, ?)")
         .bind(if recovery {1} else {0})
-        .bind(username.as_slice())
+        .bind(blind_username)
         .bind(opaque_password)
         .bind(secret_master_key.as_slice())
         .bind(secret_export_key.as_slice())
@@ -290,11 +707,48 @@ impl TxConn {
         Ok(())
     }
 
+    // replaces the skeletons of the user's username, fails with Conflict if another user's username looks alike
+    // #[tracing::instrument]
+    pub async fn set_user_username_skeletons(&mut self, user_id: &UserId, blind_skeletons: &[Vec<u8>]) -> api::Result<()> {
+        sqlx::query("delete from `username_skeletons` where `user_id` = ?")
+            .bind(user_id.as_slice())
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        for blind_skeleton in blind_skeletons {
+            sqlx::query("insert into `username_skeletons` values (?, ?)")
+                .bind(blind_skeleton)
+                .bind(user_id.as_slice())
+                .execute(self.conn()).await.map_err(|e| {
+                    match e {
+                        sqlx::Error::Database(e)
+                            if e.as_error().downcast_ref::<MySqlDatabaseError>().map(|e| e.number()) == Some(1062)
+                            => api::Error::Conflict, // another username looks alike
+                        _ => api::Error::ServerSideError(e.into()),
+                    }
+                })?;
+        }
+
+        Ok(())
+    }
+
+    // usernames set before their skeletons were recorded get them at their next login, unless another one already looks alike
+    // #[tracing::instrument]
+    pub async fn add_user_username_skeletons(&mut self, user_id: &UserId, blind_skeletons: &[Vec<u8>]) -> api::Result<()> {
+        for blind_skeleton in blind_skeletons {
+            sqlx::query("insert ignore into `username_skeletons` values (?, ?)")
+                .bind(blind_skeleton)
+                .bind(user_id.as_slice())
+                .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        }
+
+        Ok(())
+    }
+
     #[allow(clippy::too_many_arguments)]
     // #[tracing::instrument]
-    pub async fn set_credentials(&mut self, recovery: bool,  user_id: &UserId, username: &Username, opaque_password: &[u8], secret_master_key: &SecretBox<MasterKey>, secret_export_key: &SecretBox<ExportKey>) -> api::Result<()> {
+    pub async fn set_credentials(&mut self, recovery: bool,  user_id: &UserId, blind_username: &[u8], opaque_password: &[u8], secret_master_key: &SecretBox<MasterKey>, secret_export_key: &SecretBox<ExportKey>) -> api::Result<()> {
         sqlx::query("update `credentials` set `username` = ?, `opaque_password` = ?, `secret_master_key` = ?, `secret_export_key` = ? where `recovery` = ? and `user_id` = ?")
-        .bind(username.as_slice())
+        .bind(blind_username)
         .bind(opaque_password)
         .bind(secret_master_key.as_slice())
         .bind(secret_export_key.as_slice())
@@ -348,6 +802,7 @@ impl TxConn {
         .bind(user_id.as_slice())
         .fetch_one(self.conn()).await.map_err(|e| {
             match e {
+                sqlx::Error::RowNotFound => api::Error::NotFound,
                 _ => api::Error::ServerSideError(e.into()),
             }
         })?;
@@ -385,9 +840,28 @@ impl TxConn {
     }
 
     // #[tracing::instrument]
-    pub async fn set_user_totp(&mut self, user_id: &UserId, totp: &Option<Totp>) -> api::Result<()> {
-        sqlx::query("update `users` set `totp_secret` = ?, `totp_digits` = ?, `totp_algo` = ?, `totp_period` = ? where `user_id` = ?")
-            .bind(totp.as_ref().map(|t| t.secret.as_slice()))
+    pub async fn get_secret_master_key(&mut self, recovery: bool, user_id: &UserId) -> api::Result<SecretBox<MasterKey>> {
+        let row: MySqlRow = sqlx::query("select `secret_master_key` from `credentials` where `recovery` = ? and `user_id` = ?")
+        .bind(if recovery {1} else {0})
+        .bind(user_id.as_slice())
+        .fetch_one(self.conn()).await.map_err(|e| {
+            match e {
+                sqlx::Error::RowNotFound => api::Error::NotFound,
+                _ => api::Error::ServerSideError(e.into()),
+            }
+        })?;
+
+        Ok(
+            SecretBox::<MasterKey>::from_vec(row.try_get(0).map_err(|e| api::Error::ServerSideError(e.into()))?),
+        )
+    }
+
+    // #[tracing::instrument]
+    pub async fn set_user_totp(&mut self, totp_key: &[u8], user_id: &UserId, totp: &Option<Totp>) -> api::Result<()> {
+        let secret_totp_secret = totp.as_ref().map(|t| seal_totp_secret(totp_key, user_id, &t.secret)).transpose()?;
+
+        sqlx::query("update `users` set `secret_totp_secret` = ?, `totp_digits` = ?, `totp_algo` = ?, `totp_period` = ?, `totp_last_counter` = null where `user_id` = ?")
+            .bind(secret_totp_secret)
             .bind(totp.as_ref().map(|t| t.digits))
             .bind(totp.as_ref().map(|t| t.algo.as_ref()))
             .bind(totp.as_ref().map(|t| t.period))
@@ -400,6 +874,294 @@ impl TxConn {
 
         Ok(())
     }
+
+    // "select ... for update" serializes concurrent submissions of the same TOTP code
+    // #[tracing::instrument]
+    pub async fn get_user_totp_last_counter(&mut self, user_id: &UserId) -> api::Result<Option<u64>> {
+        let row: MySqlRow = sqlx::query("select `totp_last_counter` from `users` where `user_id` = ? for update")
+        .bind(user_id.as_slice())
+        .fetch_one(self.conn()).await.map_err(|e| {
+            match e {
+                sqlx::Error::RowNotFound => api::Error::NotFound,
+                _ => api::Error::ServerSideError(e.into()),
+            }
+        })?;
+
+        row.try_get(0).map_err(|e| api::Error::ServerSideError(e.into()))
+    }
+
+    // the counter is only ever allowed to increase, so a code can't be accepted twice even if the row lock wasn't honored
+    // #[tracing::instrument]
+    pub async fn set_user_totp_last_counter(&mut self, user_id: &UserId, counter: u64) -> api::Result<()> {
+        let res = sqlx::query("update `users` set `totp_last_counter` = ? where `user_id` = ? and (`totp_last_counter` is null or `totp_last_counter` < ?)")
+            .bind(counter)
+            .bind(user_id.as_slice())
+            .bind(counter)
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        if res.rows_affected() != 1 {
+            return Err(api::Error::InvalidSecondFactor);
+        }
+
+        Ok(())
+    }
+
+    // replaces all the user's backup codes
+    // #[tracing::instrument]
+    pub async fn set_user_backup_codes(&mut self, user_id: &UserId, hashed_codes: &[Vec<u8>]) -> api::Result<()> {
+        sqlx::query("delete from `backup_codes` where `user_id` = ?")
+            .bind(user_id.as_slice())
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        for hashed_code in hashed_codes {
+            sqlx::query("insert into `backup_codes` (`user_id`, `hashed_code`) values (?, ?)")
+                .bind(user_id.as_slice())
+                .bind(hashed_code.as_slice())
+                .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        }
+
+        Ok(())
+    }
+
+    // a backup code can only be used once: it is deleted atomically when it is checked
+    // #[tracing::instrument]
+    pub async fn burn_user_backup_code(&mut self, user_id: &UserId, hashed_code: &[u8]) -> api::Result<()> {
+        let res = sqlx::query("delete from `backup_codes` where `user_id` = ? and `hashed_code` = ?")
+            .bind(user_id.as_slice())
+            .bind(hashed_code)
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        if res.rows_affected() != 1 {
+            return Err(api::Error::InvalidSecondFactor);
+        }
+
+        Ok(())
+    }
+
+    // #[tracing::instrument]
+    pub async fn get_user_backup_codes_count(&mut self, user_id: &UserId) -> api::Result<u32> {
+        let row: MySqlRow = sqlx::query("select count(*) from `backup_codes` where `user_id` = ?")
+            .bind(user_id.as_slice())
+            .fetch_one(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        let count: i64 = row.try_get(0).map_err(|e| api::Error::ServerSideError(e.into()))?;
+        Ok(count as u32)
+    }
+
+    // #[tracing::instrument]
+    pub async fn new_webauthn_credential(&mut self, user_id: &UserId, credential_id: &WebauthnCredentialId, name: &str, public_key: &[u8], sign_count: u32) -> api::Result<()> {
+        sqlx::query("insert into `webauthn_credentials` (`credential_id`, `user_id`, `name`, `public_key`, `sign_count`) values (?, ?, ?, ?, ?)")
+            .bind(credential_id.as_slice())
+            .bind(user_id.as_slice())
+            .bind(name)
+            .bind(public_key)
+            .bind(sign_count)
+            .execute(self.conn()).await.map_err(|e| {
+                match e {
+                    sqlx::Error::Database(e)
+                        if e.as_error().downcast_ref::<MySqlDatabaseError>().map(|e| e.number()) == Some(1062)
+                        => api::Error::Conflict, // the credential is already registered
+                    _ => api::Error::ServerSideError(e.into()),
+                }
+            })?;
+
+        Ok(())
+    }
+
+    // #[tracing::instrument]
+    pub async fn get_user_webauthn_credential_ids(&mut self, user_id: &UserId) -> api::Result<Vec<WebauthnCredentialId>> {
+        let rows: Vec<MySqlRow> = sqlx::query("select `credential_id` from `webauthn_credentials` where `user_id` = ?")
+            .bind(user_id.as_slice())
+            .fetch_all(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        rows.iter()
+            .map(|row| Ok(WebauthnCredentialId::from_vec(row.try_get(0).map_err(|e| api::Error::ServerSideError(e.into()))?)))
+            .collect()
+    }
+
+    // "select ... for update" serializes concurrent assertions so that the signature counter can't be reused
+    // #[tracing::instrument]
+    pub async fn get_user_webauthn_credential(&mut self, user_id: &UserId, credential_id: &WebauthnCredentialId) -> api::Result<(Vec<u8>, u32)> {
+        let row: MySqlRow = sqlx::query("select `public_key`, `sign_count` from `webauthn_credentials` where `credential_id` = ? and `user_id` = ? for update")
+            .bind(credential_id.as_slice())
+            .bind(user_id.as_slice())
+            .fetch_one(self.conn()).await.map_err(|e| {
+                match e {
+                    sqlx::Error::RowNotFound => api::Error::NotFound,
+                    _ => api::Error::ServerSideError(e.into()),
+                }
+            })?;
+
+        Ok((
+            row.try_get(0).map_err(|e| api::Error::ServerSideError(e.into()))?,
+            row.try_get(1).map_err(|e| api::Error::ServerSideError(e.into()))?,
+        ))
+    }
+
+    // #[tracing::instrument]
+    pub async fn set_webauthn_credential_sign_count(&mut self, credential_id: &WebauthnCredentialId, sign_count: u32) -> api::Result<()> {
+        sqlx::query("update `webauthn_credentials` set `sign_count` = ? where `credential_id` = ?")
+            .bind(sign_count)
+            .bind(credential_id.as_slice())
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        Ok(())
+    }
+
+    // #[tracing::instrument]
+    pub async fn delete_user_webauthn_credentials(&mut self, user_id: &UserId) -> api::Result<()> {
+        sqlx::query("delete from `webauthn_credentials` where `user_id` = ?")
+            .bind(user_id.as_slice())
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        Ok(())
+    }
+
+    // used when rotating the totp_key, returns the number of resealed secrets
+    // #[tracing::instrument]
+    pub async fn reseal_totp_secrets(&mut self, old_totp_key: &[u8], new_totp_key: &[u8]) -> api::Result<u64> {
+        let rows: Vec<MySqlRow> = sqlx::query("select `user_id`, `secret_totp_secret` from `users` where `secret_totp_secret` is not null for update")
+            .fetch_all(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        for row in &rows {
+            let user_id = UserId::from_vec(row.try_get(0).map_err(|e| api::Error::ServerSideError(e.into()))?);
+            let secret_totp_secret: Vec<u8> = row.try_get(1).map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+            let secret = unseal_totp_secret(old_totp_key, &user_id, &secret_totp_secret)?;
+
+            sqlx::query("update `users` set `secret_totp_secret` = ? where `user_id` = ?")
+                .bind(seal_totp_secret(new_totp_key, &user_id, &secret)?)
+                .bind(user_id.as_slice())
+                .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        }
+
+        Ok(rows.len() as u64)
+    }
+
+    // usernames used to be stored in clear, done once by `admin blind-index-usernames`
+    // #[tracing::instrument]
+    pub async fn blind_index_usernames(&mut self, username_pepper: &[u8]) -> api::Result<u64> {
+        let applied = sqlx::query("select 1 from `migrations` where `name` = ? for update")
+            .bind(MIGRATION_BLIND_INDEXED_USERNAMES)
+            .fetch_optional(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        // hashing them twice would lock everyone out
+        if applied.is_some() {
+            return Err(api::Error::Conflict);
+        }
+
+        let rows: Vec<MySqlRow> = sqlx::query("select `recovery`, `username` from `credentials` for update")
+            .fetch_all(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        for row in &rows {
+            let recovery: u8 = row.try_get(0).map_err(|e| api::Error::ServerSideError(e.into()))?;
+            let username = Username::from_vec(row.try_get(1).map_err(|e| api::Error::ServerSideError(e.into()))?);
+
+            sqlx::query("update `credentials` set `username` = ? where `recovery` = ? and `username` = ?")
+                .bind(blind_username(username_pepper, &username)?)
+                .bind(recovery)
+                .bind(username.as_slice())
+                .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        }
+
+        sqlx::query("insert into `migrations` values (?, now())")
+            .bind(MIGRATION_BLIND_INDEXED_USERNAMES)
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        Ok(rows.len() as u64)
+    }
+
+    // TOTP secrets used to be stored in clear, done once by `admin seal-totp-secrets`.
+    // the `secret_totp_secret` column must have been added beforehand, see NormalConn::add_secret_totp_secret_column
+    // #[tracing::instrument]
+    pub async fn seal_totp_secrets(&mut self, totp_key: &[u8]) -> api::Result<u64> {
+        let applied = sqlx::query("select 1 from `migrations` where `name` = ? for update")
+            .bind(MIGRATION_SEALED_TOTP_SECRETS)
+            .fetch_optional(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        if applied.is_some() {
+            return Err(api::Error::Conflict);
+        }
+
+        let rows: Vec<MySqlRow> = sqlx::query("select `user_id`, `totp_secret` from `users` where `totp_secret` is not null for update")
+            .fetch_all(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        for row in &rows {
+            let user_id = UserId::from_vec(row.try_get(0).map_err(|e| api::Error::ServerSideError(e.into()))?);
+            let secret = TotpSecret::from_vec(row.try_get(1).map_err(|e| api::Error::ServerSideError(e.into()))?);
+
+            sqlx::query("update `users` set `secret_totp_secret` = ?, `totp_secret` = null where `user_id` = ?")
+                .bind(seal_totp_secret(totp_key, &user_id, &secret)?)
+                .bind(user_id.as_slice())
+                .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        }
+
+        sqlx::query("insert into `migrations` values (?, now())")
+            .bind(MIGRATION_SEALED_TOTP_SECRETS)
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        Ok(rows.len() as u64)
+    }
+
+    // `tmp.session_id` used to be a zero padded binary(32), done once by `admin varbinary-tmp-session-ids`.
+    // the column must have been changed beforehand, see NormalConn::make_tmp_session_id_varbinary
+    // #[tracing::instrument]
+    pub async fn varbinary_tmp_session_ids(&mut self) -> api::Result<u64> {
+        let applied = sqlx::query("select 1 from `migrations` where `name` = ? for update")
+            .bind(MIGRATION_VARBINARY_TMP_SESSION_IDS)
+            .fetch_optional(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        if applied.is_some() {
+            return Err(api::Error::Conflict);
+        }
+
+        // the padding is kept by the conversion so these rows can't be found anymore,
+        // they only hold logins and enrollments in progress which the clients just start over
+        let res = sqlx::query("delete from `tmp`")
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        sqlx::query("insert into `migrations` values (?, now())")
+            .bind(MIGRATION_VARBINARY_TMP_SESSION_IDS)
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        Ok(res.rows_affected())
+    }
+}
+
+pub const MIGRATION_BLIND_INDEXED_USERNAMES: &str = "blind_indexed_usernames";
+pub const MIGRATION_SEALED_TOTP_SECRETS: &str = "sealed_totp_secrets";
+pub const MIGRATION_VARBINARY_TMP_SESSION_IDS: &str = "varbinary_tmp_session_ids";
+
+// usernames are only stored as this keyed hash, so that a database dump doesn't leak the list of users.
+// the pepper is kept out of the database, and the hash is deterministic so that it can still be looked up
+pub fn blind_username(username_pepper: &[u8], username: &Username) -> api::Result<Vec<u8>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(username_pepper).map_err(|e| eyre::eyre!(e))?;
+    mac.update(username.as_slice());
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+// the skeletons of usernames are kept the same way, under their own label
+pub fn blind_username_skeleton(username_pepper: &[u8], skeleton: &str) -> api::Result<Vec<u8>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(username_pepper).map_err(|e| eyre::eyre!(e))?;
+    mac.update(b"skeleton\0"); // usernames can't contain a NUL
+    mac.update(skeleton.as_bytes());
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+// TOTP secrets are sealed at rest so that a database dump isn't enough to bypass the second factor.
+// the user_id is used as associated data so that a sealed secret can't be moved to another user's row
+fn seal_totp_secret(totp_key: &[u8], user_id: &UserId, secret: &TotpSecret) -> api::Result<Vec<u8>> {
+    Ok(AeadBox::seal(totp_key, secret, user_id)?)
+}
+
+fn unseal_totp_secret(totp_key: &[u8], user_id: &UserId, secret_totp_secret: &[u8]) -> api::Result<TotpSecret> {
+    let (secret, sealed_user_id) = AeadBox::<TotpSecret, UserId>::unseal(totp_key, secret_totp_secret)?;
+
+    if sealed_user_id.as_slice() != user_id.as_slice() {
+        return Err(eyre::eyre!("sealed TOTP secret doesn't belong to this user").into());
+    }
+
+    Ok(secret)
 }
 
 // queries that are defined on any kind of connection (transactionnal or not)
@@ -410,7 +1172,7 @@ pub trait Queryable: std::fmt::Debug + Send {
     // #[tracing::instrument]
     async fn save_tmp(&mut self, session_id: &[u8], ip: &str, expiration: i64, field: &str, data: &[u8]) -> api::Result<()> {
         
-        sqlx::query("replace into `tmp` values (?, INET_ATON(?), FROM_UNIXTIME(?), ?, ?)")
+        sqlx::query("replace into `tmp` values (?, INET6_ATON(?), FROM_UNIXTIME(?), ?, ?)")
             .bind(session_id)
             .bind(ip)
             .bind(expiration)
@@ -421,10 +1183,11 @@ pub trait Queryable: std::fmt::Debug + Send {
     }
 
     // #[tracing::instrument]
-    async fn get_credentials_from_username(&mut self, recovery: bool, username: &Username) -> api::Result<(UserId, Vec<u8>, SecretBox<MasterKey>)> {
-        let row = sqlx::query("select `user_id`, `opaque_password`, `secret_master_key` from `credentials` where `recovery` = ? and `username` = ?")
+    async fn get_credentials_from_username(&mut self, recovery: bool, blind_username: &[u8]) -> api::Result<(UserId, Vec<u8>, SecretBox<MasterKey>, u32)> {
+        // a single query, so that unknown usernames don't answer noticeably faster
+        let row = sqlx::query("select `c`.`user_id`, `c`.`opaque_password`, `c`.`secret_master_key`, `u`.`version_master_key` from `credentials` as `c` join `users` as `u` on `u`.`user_id` = `c`.`user_id` where `c`.`recovery` = ? and `c`.`username` = ?")
             .bind(if recovery {1} else {0})    
-            .bind(username.as_slice())
+            .bind(blind_username)
             .fetch_one(self.conn()).await.map_err(|e| {
                 match e {
                     sqlx::Error::RowNotFound => api::Error::NotFound,
@@ -436,12 +1199,13 @@ pub trait Queryable: std::fmt::Debug + Send {
             UserId::from_vec(row.try_get(0).map_err(|e| api::Error::ServerSideError(e.into()))?),
             row.try_get(1).map_err(|e| api::Error::ServerSideError(e.into()))?,
             SecretBox::<MasterKey>::from_vec(row.try_get(2).map_err(|e| api::Error::ServerSideError(e.into()))?),
+            row.try_get(3).map_err(|e| api::Error::ServerSideError(e.into()))?,
         ))
     }
 
     // #[tracing::instrument]
-    async fn get_user_totp(&mut self, user_id: &UserId) -> api::Result<Option<Totp>> {
-        let row = sqlx::query("select `totp_secret`, `totp_digits`, `totp_algo`, `totp_period` from `users` where `user_id` = ?")
+    async fn get_user_totp(&mut self, totp_key: &[u8], user_id: &UserId) -> api::Result<Option<Totp>> {
+        let row = sqlx::query("select `secret_totp_secret`, `totp_digits`, `totp_algo`, `totp_period` from `users` where `user_id` = ?")
             .bind(user_id.as_slice())
             .fetch_one(self.conn()).await.map_err(|e| {
                 match e {
@@ -449,9 +1213,9 @@ pub trait Queryable: std::fmt::Debug + Send {
                 }
             })?;
 
-        let r = if let Some(secret) = row.try_get(0).map_err(|e| api::Error::ServerSideError(e.into()))? {
+        let r = if let Some(secret_totp_secret) = row.try_get::<Option<Vec<u8>>, _>(0).map_err(|e| api::Error::ServerSideError(e.into()))? {
             Some(Totp {
-                secret: TotpSecret::from_vec(secret),
+                secret: unseal_totp_secret(totp_key, user_id, &secret_totp_secret)?,
                 digits: row.try_get(1).map_err(|e| api::Error::ServerSideError(e.into()))?,
                 algo:   TotpAlgo::from_str(row.try_get(2).map_err(|e| api::Error::ServerSideError(e.into()))?).map_err(|e| api::Error::ServerSideError(e.into()))?,
                 period: row.try_get(3).map_err(|e| api::Error::ServerSideError(e.into()))?,
@@ -477,3 +1241,197 @@ impl<'pool> Queryable for NormalConn {
         &mut self.0
     }
 }
+
+// login throttling must survive the rollback of failed requests, so it's only done outside of transactions
+impl NormalConn {
+    // #[tracing::instrument]
+    pub async fn get_throttle_blocked_until(&mut self, kind: u8, subject: &[u8]) -> api::Result<Option<i64>> {
+        let row = sqlx::query("select unix_timestamp(`blocked_until`) from `login_throttles` where `kind` = ? and `subject` = ?")
+            .bind(kind)
+            .bind(subject)
+            .fetch_optional(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        row.map(|row| row.try_get::<i64, _>(0)).transpose().map_err(|e| api::Error::ServerSideError(e.into()))
+    }
+
+    // #[tracing::instrument]
+    pub async fn add_throttle_attempt(&mut self, kind: u8, subject: &[u8], now: i64, forget_before: i64) -> api::Result<u32> {
+        // the counter starts over when the last attempt is old enough.
+        // assignments are evaluated from left to right so `failures` must be updated before `last_failure`
+        sqlx::query("insert into `login_throttles` values (?, ?, 1, FROM_UNIXTIME(?), FROM_UNIXTIME(?))
+                on duplicate key update
+                    `failures` = if(`last_failure` < FROM_UNIXTIME(?), 1, `failures` + 1),
+                    `last_failure` = FROM_UNIXTIME(?)")
+            .bind(kind)
+            .bind(subject)
+            .bind(now)
+            .bind(now)
+            .bind(forget_before)
+            .bind(now)
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        let row = sqlx::query("select `failures` from `login_throttles` where `kind` = ? and `subject` = ?")
+            .bind(kind)
+            .bind(subject)
+            .fetch_one(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        row.try_get(0).map_err(|e| api::Error::ServerSideError(e.into()))
+    }
+
+    // #[tracing::instrument]
+    pub async fn set_throttle_blocked_until(&mut self, kind: u8, subject: &[u8], blocked_until: i64) -> api::Result<()> {
+        // concurrent failures must not shorten the delay
+        sqlx::query("update `login_throttles` set `blocked_until` = greatest(`blocked_until`, FROM_UNIXTIME(?)) where `kind` = ? and `subject` = ?")
+            .bind(blocked_until)
+            .bind(kind)
+            .bind(subject)
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        Ok(())
+    }
+
+    // #[tracing::instrument]
+    pub async fn get_pending_recovery(&mut self, user_id: &UserId) -> api::Result<Option<i64>> {
+        let row = sqlx::query("select unix_timestamp(`available_at`) from `pending_recoveries` where `user_id` = ?")
+            .bind(user_id.as_slice())
+            .fetch_optional(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        row.map(|row| row.try_get::<i64, _>(0)).transpose().map_err(|e| api::Error::ServerSideError(e.into()))
+    }
+
+    // #[tracing::instrument]
+    pub async fn set_pending_recovery(&mut self, user_id: &UserId, available_at: i64) -> api::Result<()> {
+        sqlx::query("replace into `pending_recoveries` values (?, FROM_UNIXTIME(?))")
+            .bind(user_id.as_slice())
+            .bind(available_at)
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        Ok(())
+    }
+
+    // #[tracing::instrument]
+    pub async fn delete_expired_revoked_sessions(&mut self, now: i64) -> api::Result<u64> {
+        let res = sqlx::query("delete from `revoked_sessions` where `expires_at` <= FROM_UNIXTIME(?)")
+            .bind(now)
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        Ok(res.rows_affected())
+    }
+
+    // #[tracing::instrument]
+    pub async fn get_next_revoked_session_expiration(&mut self) -> api::Result<Option<i64>> {
+        let row = sqlx::query("select unix_timestamp(min(`expires_at`)) from `revoked_sessions`")
+            .fetch_one(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        row.try_get::<Option<i64>, _>(0).map_err(|e| api::Error::ServerSideError(e.into()))
+    }
+
+    // #[tracing::instrument]
+    pub async fn add_session_proof_nonce(&mut self, nonce: &[u8], expires_at: i64) -> api::Result<()> {
+        let res = sqlx::query("insert ignore into `session_proof_nonces` values (?, FROM_UNIXTIME(?))")
+            .bind(nonce)
+            .bind(expires_at)
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+
+        // already used
+        if res.rows_affected() != 1 {
+            return Err(api::Error::InvalidSessionProof);
+        }
+        Ok(())
+    }
+
+    // #[tracing::instrument]
+    pub async fn delete_expired_session_proof_nonces(&mut self, now: i64) -> api::Result<u64> {
+        let res = sqlx::query("delete from `session_proof_nonces` where `expires_at` <= FROM_UNIXTIME(?)")
+            .bind(now)
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        Ok(res.rows_affected())
+    }
+
+    // server states and challenges which were never finished
+    // #[tracing::instrument]
+    pub async fn delete_expired_tmp(&mut self, now: i64) -> api::Result<u64> {
+        let res = sqlx::query("delete from `tmp` where `expiration` <= FROM_UNIXTIME(?)")
+            .bind(now)
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        Ok(res.rows_affected())
+    }
+
+    // #[tracing::instrument]
+    pub async fn delete_sessions_created_before(&mut self, created_before: i64) -> api::Result<u64> {
+        let res = sqlx::query("delete from `sessions` where `created` < FROM_UNIXTIME(?)")
+            .bind(created_before)
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        Ok(res.rows_affected())
+    }
+
+    // #[tracing::instrument]
+    pub async fn delete_trusted_devices_created_before(&mut self, created_before: i64) -> api::Result<u64> {
+        let res = sqlx::query("delete from `trusted_devices` where `created` < FROM_UNIXTIME(?)")
+            .bind(created_before)
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        Ok(res.rows_affected())
+    }
+
+    // mysql implicitly commits around `alter table`, so the schema changes of a migration are done outside of its transaction.
+    // they can be run again, `create table if not exists` doesn't change existing tables
+    // #[tracing::instrument]
+    async fn has_users_column(&mut self, column: &str) -> api::Result<bool> {
+        let row = sqlx::query("select 1 from information_schema.columns where `table_schema` = database() and `table_name` = 'users' and `column_name` = ?")
+            .bind(column)
+            .fetch_optional(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        Ok(row.is_some())
+    }
+
+    // #[tracing::instrument]
+    pub async fn add_secret_totp_secret_column(&mut self) -> api::Result<()> {
+        if !self.has_users_column("secret_totp_secret").await? {
+            sqlx::query("alter table `users` add column `secret_totp_secret` varbinary(256) after `secret_private_data`")
+                .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        }
+        Ok(())
+    }
+
+    // #[tracing::instrument]
+    pub async fn drop_totp_secret_column(&mut self) -> api::Result<()> {
+        if self.has_users_column("totp_secret").await? {
+            sqlx::query("alter table `users` drop column `totp_secret`")
+                .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        }
+        Ok(())
+    }
+
+    // #[tracing::instrument]
+    pub async fn make_tmp_session_id_varbinary(&mut self) -> api::Result<()> {
+        let row = sqlx::query("select 1 from information_schema.columns where `table_schema` = database() and `table_name` = 'tmp' and `column_name` = 'session_id' and `data_type` = 'binary'")
+            .fetch_optional(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        if row.is_some() {
+            sqlx::query("alter table `tmp` modify `session_id` varbinary(32) not null")
+                .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        }
+        Ok(())
+    }
+
+    // #[tracing::instrument]
+    pub async fn is_migration_applied(&mut self, name: &str) -> api::Result<bool> {
+        let row = sqlx::query("select 1 from `migrations` where `name` = ?")
+            .bind(name)
+            .fetch_optional(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        Ok(row.is_some())
+    }
+
+    // #[tracing::instrument]
+    pub async fn remove_throttle_attempt(&mut self, kind: u8, subject: &[u8]) -> api::Result<()> {
+        sqlx::query("update `login_throttles` set `failures` = `failures` - 1 where `kind` = ? and `subject` = ? and `failures` > 0")
+            .bind(kind)
+            .bind(subject)
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        Ok(())
+    }
+
+    // #[tracing::instrument]
+    pub async fn delete_throttle(&mut self, kind: u8, subject: &[u8]) -> api::Result<()> {
+        sqlx::query("delete from `login_throttles` where `kind` = ? and `subject` = ?")
+            .bind(kind)
+            .bind(subject)
+            .execute(self.conn()).await.map_err(|e| api::Error::ServerSideError(e.into()))?;
+        Ok(())
+    }
+}
@@ -8,6 +8,8 @@ use crate::state::State;
 pub async fn run(state: State) -> eyre::Result<()> {
     let state = Arc::new(state);
 
+    tokio::spawn(state.clone().sessions_cleanup_task());
+
     let filter = warp::post()
         .and(warp::path!("api"))
         .and(warp::body::content_length_limit(1024 * 16)) // 16k
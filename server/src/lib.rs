@@ -6,6 +6,8 @@ pub mod state;
 pub mod db;
 pub mod config;
 mod opaque;
+pub mod webauthn;
+pub mod notifier;
 
 pub mod http_server;
 
@@ -0,0 +1,157 @@
+use std::{net::IpAddr, path::PathBuf, sync::{Arc, Mutex}};
+
+use async_trait::async_trait;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor, message::Mailbox, transport::smtp::authentication::Credentials};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::config::NotifierConfig;
+
+// security related events sent to the user's verified contact email
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Notification {
+    NewLogin { ip: IpAddr },
+    RecoveryPending { ip: IpAddr, available_at: i64 },
+    RecoveryLogin { ip: IpAddr },
+    CredentialsChanged { recovery: bool },
+    MasterKeyRotated,
+    TotpEnabled,
+    TotpDisabled,
+    ContactEmailVerification { code: String },
+    ContactEmailChanged,
+    AccountDeletionPending { purge_at: i64 },
+    AccountDeletionCanceled { ip: IpAddr },
+    AccountDeleted,
+}
+
+impl Notification {
+    pub fn subject(&self) -> &'static str {
+        match self {
+            Notification::NewLogin { .. } => "New login to your account",
+            Notification::RecoveryPending { .. } => "Someone started the recovery of your account",
+            Notification::RecoveryLogin { .. } => "Your account has been recovered",
+            Notification::CredentialsChanged { recovery: false } => "Your username or password has been changed",
+            Notification::CredentialsChanged { recovery: true } => "Your recovery key has been changed",
+            Notification::MasterKeyRotated => "Your master key has been rotated",
+            Notification::TotpEnabled => "Two-factor authentication has been enabled",
+            Notification::TotpDisabled => "Two-factor authentication has been disabled",
+            Notification::ContactEmailVerification { .. } => "Verify your contact email",
+            Notification::ContactEmailChanged => "Your contact email has been changed",
+            Notification::AccountDeletionPending { .. } => "Your account is going to be deleted",
+            Notification::AccountDeletionCanceled { .. } => "The deletion of your account has been canceled",
+            Notification::AccountDeleted => "Your account has been deleted",
+        }
+    }
+
+    pub fn body(&self) -> String {
+        match self {
+            Notification::NewLogin { ip } => format!("Someone logged in to your account from {}.", ip),
+            Notification::RecoveryPending { ip, available_at } => format!(
+                "Someone logged in with your recovery key from {}. If it wasn't you, log in normally before {} to cancel the recovery.",
+                ip, chrono::DateTime::from_timestamp(*available_at, 0).map(|d| d.to_rfc2822()).unwrap_or_default()),
+            Notification::RecoveryLogin { ip } => format!("Someone logged in with your recovery key from {}.", ip),
+            Notification::ContactEmailVerification { code } => format!("Your verification code is: {}", code),
+            Notification::ContactEmailChanged => "Security notifications will now be sent to another address.".to_owned(),
+            Notification::AccountDeletionPending { purge_at } => format!(
+                "Your account will be deleted on {}. Log in before then to cancel the deletion.",
+                chrono::DateTime::from_timestamp(*purge_at, 0).map(|d| d.to_rfc2822()).unwrap_or_default()),
+            Notification::AccountDeletionCanceled { ip } => format!("Someone logged in to your account from {}, which canceled its deletion.", ip),
+            n => format!("{}.", n.subject()),
+        }
+    }
+}
+
+#[async_trait]
+pub trait Notifier: std::fmt::Debug + Send + Sync {
+    async fn notify(&self, to: &str, notification: &Notification) -> eyre::Result<()>;
+}
+
+pub fn from_config(config: &NotifierConfig) -> eyre::Result<Arc<dyn Notifier>> {
+    Ok(match config {
+        NotifierConfig::Smtp { host, port, username, password, from } => Arc::new(SmtpNotifier::new(host, *port, username, password, from)?),
+        NotifierConfig::File { path } => Arc::new(FileNotifier::new(path.into())),
+    })
+}
+
+pub struct SmtpNotifier {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl std::fmt::Debug for SmtpNotifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SmtpNotifier").field("from", &self.from).finish()
+    }
+}
+
+impl SmtpNotifier {
+    pub fn new(host: &str, port: u16, username: &str, password: &str, from: &str) -> eyre::Result<Self> {
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::relay(host)?
+            .port(port)
+            .credentials(Credentials::new(username.to_owned(), password.to_owned()))
+            .build();
+
+        Ok(Self {
+            transport,
+            from: from.parse()?,
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for SmtpNotifier {
+    async fn notify(&self, to: &str, notification: &Notification) -> eyre::Result<()> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to.parse()?)
+            .subject(notification.subject())
+            .body(notification.body())?;
+
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+// appends one JSON line per notification, useful for local development
+#[derive(Debug)]
+pub struct FileNotifier {
+    path: PathBuf,
+}
+
+impl FileNotifier {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+#[async_trait]
+impl Notifier for FileNotifier {
+    async fn notify(&self, to: &str, notification: &Notification) -> eyre::Result<()> {
+        let mut line = serde_json::to_vec(&(to, notification))?;
+        line.push(b'\n');
+
+        let mut f = tokio::fs::OpenOptions::new().create(true).append(true).open(&self.path).await?;
+        f.write_all(&line).await?;
+        Ok(())
+    }
+}
+
+// keeps every notification, used by the tests
+#[derive(Debug, Default)]
+pub struct MemoryNotifier {
+    sent: Mutex<Vec<(String, Notification)>>,
+}
+
+impl MemoryNotifier {
+    pub fn sent(&self) -> Vec<(String, Notification)> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Notifier for MemoryNotifier {
+    async fn notify(&self, to: &str, notification: &Notification) -> eyre::Result<()> {
+        self.sent.lock().unwrap().push((to.to_owned(), notification.clone()));
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
 use common::{api::{self, OpaqueClientFinishMsg, OpaqueClientStartMsg, OpaqueServerStartMsg, Username, newtypes::Bytes}, crypto::opaque::OpaqueConf};
-use opaque_ke::{CredentialFinalization, CredentialRequest, Identifiers, RegistrationRequest, RegistrationUpload, ServerLogin, ServerLoginStartParameters, ServerRegistration, ServerSetup};
+use opaque_ke::{CipherSuite, CredentialFinalization, CredentialRequest, Identifiers, RegistrationRequest, RegistrationUpload, ServerLogin, ServerLoginStartParameters, ServerRegistration, ServerSetup, keypair::KeyPair};
+use rand_core::{CryptoRng, RngCore};
 
 pub enum _OpaqueState {}
 pub type OpaqueState = Bytes<_OpaqueState>;
@@ -22,6 +23,31 @@ pub fn registration_finish(msg: &OpaqueClientFinishMsg) -> api::Result<Vec<u8>>
     Ok(password.serialize().to_vec())
 }
 
+// builds a record which can't be told apart from a real one without knowing its password, which nobody does.
+// the rng should be seeded deterministically so that the same user always gets the same record
+pub fn fake_registration<R: RngCore + CryptoRng>(rng: &mut R) -> api::Result<Vec<u8>> {
+    // client's static public key, derived from a canonical and non-zero ristretto255 scalar
+    let mut private_key = [0u8; 32];
+    rng.fill_bytes(&mut private_key);
+    private_key[31] &= 0x0f;
+    private_key[0] |= 0x01;
+    let keypair = KeyPair::<<OpaqueConf as CipherSuite>::KeGroup>::from_private_key_slice(&private_key)
+        .map_err(|e| {eyre::eyre!("failed to derive fake opaque keypair: {:?}", e)})?;
+
+    // masking key (SHA-512), envelope nonce and envelope MAC (SHA-512)
+    let mut rest = [0u8; 64 + 32 + 64];
+    rng.fill_bytes(&mut rest);
+
+    let mut password = keypair.public().serialize().to_vec();
+    password.extend_from_slice(&rest);
+
+    // make sure that it will be accepted by login_start
+    ServerRegistration::<OpaqueConf>::deserialize(&password)
+        .map_err(|e| {eyre::eyre!("failed to instantiate fake opaque password: {:?}", e)})?;
+
+    Ok(password)
+}
+
 pub fn login_start(server_setup: &ServerSetup<OpaqueConf>, msg: &OpaqueClientStartMsg, username: &Username, password: &[u8], server_id: &[u8]) -> api::Result<(OpaqueState, OpaqueServerStartMsg)> {
     let mut rng = rand_core::OsRng;
 
@@ -1,7 +1,7 @@
 use std::net::IpAddr;
 
 use eyre::eyre;
-use common::api::{self, Rpc};
+use common::api::{self, RequestHeader, ResponseHeader, Rpc};
 use futures_util::TryFutureExt;
 use tracing::{Instrument, error, info, info_span};
 use common::api::RpcTrait;
@@ -18,17 +18,31 @@ pub fn log_error(e: &api::Error) {
 // TODO call a generic method instead
 pub async fn rpc(state: &State, req: &Req, body: &[u8]) -> api::Result<Vec<u8>> {
     // deserialize request
-    let c: api::Rpc = rmp_serde::from_slice(&body).map_err(|e| eyre!(e))?;
+    let mut rpc = body;
+    let header: RequestHeader = rmp_serde::decode::from_read(&mut rpc).map_err(|e| eyre!(e))?; // leaves the serialized `Rpc` in `rpc`, as signed by the client
+    let c: Rpc = rmp_serde::from_slice(rpc).map_err(|e| eyre!(e))?;
+
+    // kept to be refreshed once the RPC has validated it
+    let authed_session_token = if header.refresh_session_token { c.get_authed_session_token().cloned() } else { None };
 
     // acquire a set a lazily constructed connection and transaction from the pool
     let mut conn = state.db_pool.acquire();
 
+    // session tokens can only be used by the device they were issued to
+    if let Some(t) = c.get_authed_session_token() {
+        if let Err(e) = state.session_proof_check(&mut conn, t, header.session_proof.as_ref(), rpc).instrument(info_span!("rpc", %req.ip, req.port)).await {
+            log_error(&e);
+            conn.rollback().await?;
+            return with_header(&ResponseHeader::default(), rmp_serde::encode::to_vec_named(&api::Result::<()>::Err(e)).map_err(|e| eyre!(e))?);
+        }
+    }
+
     // used later to commit or rollback DBConn
     let mut got_error = false;
 
     // this dispatch is verbose, convoluted and repetitive but factoring this requires even more complex polymorphism which is not worth it
-    let resp = async { match c {
-        Rpc::AddUser(args) => rmp_serde::encode::to_vec_named(&state.add_user(&args, &mut conn)
+    let resp: api::Result<Vec<u8>> = async { match c {
+        Rpc::AddUser(args) => rmp_serde::encode::to_vec_named(&state.add_user(&args, req, &mut conn)
             .inspect_err(|e| {log_error(e); got_error = true})
             .instrument(info_span!(api::AddUser::DISPLAY_NAME))
             .await),
@@ -43,6 +57,16 @@ pub async fn rpc(state: &State, req: &Req, body: &[u8]) -> api::Result<Vec<u8>>
             .instrument(info_span!(api::SetCredentials::DISPLAY_NAME))
             .await),
 
+        Rpc::ChangePasswordStart(args) => rmp_serde::encode::to_vec_named(&state.change_password_start(&args, req, &mut conn)
+            .inspect_err(|e| {log_error(e); got_error = true})
+            .instrument(info_span!(api::ChangePasswordStart::DISPLAY_NAME))
+            .await),
+
+        Rpc::ChangePasswordFinish(args) => rmp_serde::encode::to_vec_named(&state.change_password_finish(&args, req, &mut conn)
+            .inspect_err(|e| {log_error(e); got_error = true})
+            .instrument(info_span!(api::ChangePasswordFinish::DISPLAY_NAME))
+            .await),
+
         Rpc::GetExportKeys(args) => rmp_serde::encode::to_vec_named(&state.get_export_keys(&args, &mut conn)
             .inspect_err(|e| {log_error(e); got_error = true})
             .instrument(info_span!(api::GetExportKeys::DISPLAY_NAME))
@@ -53,12 +77,12 @@ pub async fn rpc(state: &State, req: &Req, body: &[u8]) -> api::Result<Vec<u8>>
             .instrument(info_span!(api::RotateMasterKey::DISPLAY_NAME))
             .await),
         
-        Rpc::LoginStart(args) => rmp_serde::encode::to_vec_named(&state.login_start(&args, &mut conn)
+        Rpc::LoginStart(args) => rmp_serde::encode::to_vec_named(&state.login_start(&args, req, &mut conn)
             .inspect_err(|e| {log_error(e); got_error = true})
             .instrument(info_span!(api::LoginStart::DISPLAY_NAME))
             .await),
 
-        Rpc::LoginFinish(args) => rmp_serde::encode::to_vec_named(&state.login_finish(&args, &mut conn)
+        Rpc::LoginFinish(args) => rmp_serde::encode::to_vec_named(&state.login_finish(&args, req, &mut conn)
             .inspect_err(|e| {log_error(e); got_error = true})
             .instrument(info_span!(api::LoginFinish::DISPLAY_NAME))
             .await),
@@ -73,12 +97,123 @@ pub async fn rpc(state: &State, req: &Req, body: &[u8]) -> api::Result<Vec<u8>>
             .instrument(info_span!(api::SetUserPrivateData::DISPLAY_NAME))
             .await),
 
-        Rpc::SetTotp(args) => rmp_serde::encode::to_vec_named(&state.set_totp(&args, &mut conn)
+        Rpc::BeginTotpEnrollment(args) => rmp_serde::encode::to_vec_named(&state.begin_totp_enrollment(&args, req, &mut conn)
+            .inspect_err(|e| {log_error(e); got_error = true})
+            .instrument(info_span!(api::BeginTotpEnrollment::DISPLAY_NAME))
+            .await),
+
+        Rpc::ConfirmTotpEnrollment(args) => rmp_serde::encode::to_vec_named(&state.confirm_totp_enrollment(&args, &mut conn)
+            .inspect_err(|e| {log_error(e); got_error = true})
+            .instrument(info_span!(api::ConfirmTotpEnrollment::DISPLAY_NAME))
+            .await),
+
+        Rpc::UnsetTotp(args) => rmp_serde::encode::to_vec_named(&state.unset_totp(&args, &mut conn)
+            .inspect_err(|e| {log_error(e); got_error = true})
+            .instrument(info_span!(api::UnsetTotp::DISPLAY_NAME))
+            .await),
+
+        Rpc::GenerateBackupCodes(args) => rmp_serde::encode::to_vec_named(&state.generate_backup_codes(&args, &mut conn)
+            .inspect_err(|e| {log_error(e); got_error = true})
+            .instrument(info_span!(api::GenerateBackupCodes::DISPLAY_NAME))
+            .await),
+
+        Rpc::GetBackupCodesCount(args) => rmp_serde::encode::to_vec_named(&state.get_backup_codes_count(&args, &mut conn)
+            .inspect_err(|e| {log_error(e); got_error = true})
+            .instrument(info_span!(api::GetBackupCodesCount::DISPLAY_NAME))
+            .await),
+
+        Rpc::BeginWebauthnRegistration(args) => rmp_serde::encode::to_vec_named(&state.begin_webauthn_registration(&args, req, &mut conn)
             .inspect_err(|e| {log_error(e); got_error = true})
-            .instrument(info_span!(api::SetTotp::DISPLAY_NAME))
+            .instrument(info_span!(api::BeginWebauthnRegistration::DISPLAY_NAME))
+            .await),
+
+        Rpc::FinishWebauthnRegistration(args) => rmp_serde::encode::to_vec_named(&state.finish_webauthn_registration(&args, &mut conn)
+            .inspect_err(|e| {log_error(e); got_error = true})
+            .instrument(info_span!(api::FinishWebauthnRegistration::DISPLAY_NAME))
+            .await),
+
+        Rpc::UnsetWebauthn(args) => rmp_serde::encode::to_vec_named(&state.unset_webauthn(&args, &mut conn)
+            .inspect_err(|e| {log_error(e); got_error = true})
+            .instrument(info_span!(api::UnsetWebauthn::DISPLAY_NAME))
+            .await),
+
+        Rpc::BeginWebauthnAssertion(args) => rmp_serde::encode::to_vec_named(&state.begin_webauthn_assertion(&args, req, &mut conn)
+            .inspect_err(|e| {log_error(e); got_error = true})
+            .instrument(info_span!(api::BeginWebauthnAssertion::DISPLAY_NAME))
+            .await),
+
+        Rpc::VerifySecondFactor(args) => rmp_serde::encode::to_vec_named(&state.verify_second_factor(&args, req, &mut conn)
+            .inspect_err(|e| {log_error(e); got_error = true})
+            .instrument(info_span!(api::VerifySecondFactor::DISPLAY_NAME))
+            .await),
+
+        Rpc::CancelRecovery(args) => rmp_serde::encode::to_vec_named(&state.cancel_recovery(&args, &mut conn)
+            .inspect_err(|e| {log_error(e); got_error = true})
+            .instrument(info_span!(api::CancelRecovery::DISPLAY_NAME))
+            .await),
+
+        Rpc::SetContactEmail(args) => rmp_serde::encode::to_vec_named(&state.set_contact_email(&args, req, &mut conn)
+            .inspect_err(|e| {log_error(e); got_error = true})
+            .instrument(info_span!(api::SetContactEmail::DISPLAY_NAME))
+            .await),
+
+        Rpc::VerifyContactEmail(args) => rmp_serde::encode::to_vec_named(&state.verify_contact_email(&args, &mut conn)
+            .inspect_err(|e| {log_error(e); got_error = true})
+            .instrument(info_span!(api::VerifyContactEmail::DISPLAY_NAME))
+            .await),
+
+        Rpc::ListSessions(args) => rmp_serde::encode::to_vec_named(&state.list_sessions(&args, &mut conn)
+            .inspect_err(|e| {log_error(e); got_error = true})
+            .instrument(info_span!(api::ListSessions::DISPLAY_NAME))
+            .await),
+
+        Rpc::RevokeSession(args) => rmp_serde::encode::to_vec_named(&state.revoke_session(&args, &mut conn)
+            .inspect_err(|e| {log_error(e); got_error = true})
+            .instrument(info_span!(api::RevokeSession::DISPLAY_NAME))
+            .await),
+
+        Rpc::Logout(args) => rmp_serde::encode::to_vec_named(&state.logout(&args, &mut conn)
+            .inspect_err(|e| {log_error(e); got_error = true})
+            .instrument(info_span!(api::Logout::DISPLAY_NAME))
+            .await),
+
+        Rpc::RefreshSession(args) => rmp_serde::encode::to_vec_named(&state.refresh_session(&args, &mut conn)
+            .inspect_err(|e| {log_error(e); got_error = true})
+            .instrument(info_span!(api::RefreshSession::DISPLAY_NAME))
+            .await),
+
+        Rpc::ReauthenticateForUberStart(args) => rmp_serde::encode::to_vec_named(&state.reauthenticate_for_uber_start(&args, req, &mut conn)
+            .inspect_err(|e| {log_error(e); got_error = true})
+            .instrument(info_span!(api::ReauthenticateForUberStart::DISPLAY_NAME))
+            .await),
+
+        Rpc::ReauthenticateForUberFinish(args) => rmp_serde::encode::to_vec_named(&state.reauthenticate_for_uber_finish(&args, req, &mut conn)
+            .inspect_err(|e| {log_error(e); got_error = true})
+            .instrument(info_span!(api::ReauthenticateForUberFinish::DISPLAY_NAME))
+            .await),
+
+        Rpc::ListTrustedDevices(args) => rmp_serde::encode::to_vec_named(&state.list_trusted_devices(&args, &mut conn)
+            .inspect_err(|e| {log_error(e); got_error = true})
+            .instrument(info_span!(api::ListTrustedDevices::DISPLAY_NAME))
+            .await),
+
+        Rpc::RevokeTrustedDevice(args) => rmp_serde::encode::to_vec_named(&state.revoke_trusted_device(&args, &mut conn)
+            .inspect_err(|e| {log_error(e); got_error = true})
+            .instrument(info_span!(api::RevokeTrustedDevice::DISPLAY_NAME))
+            .await),
+
+        Rpc::DeleteAccount(args) => rmp_serde::encode::to_vec_named(&state.delete_account(&args, &mut conn)
+            .inspect_err(|e| {log_error(e); got_error = true})
+            .instrument(info_span!(api::DeleteAccount::DISPLAY_NAME))
             .await),
     }}.instrument(info_span!("rpc", %req.ip, req.port)).await.map_err(|e| eyre!(e).into());
 
+    // best-effort, the RPC itself succeeded
+    let refreshed_session_token = match authed_session_token {
+        Some(t) if !got_error => state.session_token_refreshed_sealed(&t).inspect_err(log_error).ok(),
+        _ => None,
+    };
+
     // commit or rollback to DbConn
     if got_error {
         conn.rollback().await?;
@@ -86,7 +221,13 @@ pub async fn rpc(state: &State, req: &Req, body: &[u8]) -> api::Result<Vec<u8>>
         conn.commit().await?;
     }
 
-    resp
+    with_header(&ResponseHeader { refreshed_session_token }, resp?)
+}
+
+fn with_header(header: &ResponseHeader, resp: Vec<u8>) -> api::Result<Vec<u8>> {
+    let mut resp_with_header = rmp_serde::encode::to_vec_named(header).map_err(|e| eyre!(e))?;
+    resp_with_header.extend(resp);
+    Ok(resp_with_header)
 }
 
 pub struct Req {
@@ -1,16 +1,23 @@
-use std::{fs::File, io::Read};
-use common::crypto::opaque::OpaqueConf;
+use std::{fs::File, io::Read, sync::Arc};
+use common::{api, clock::{Clock, SystemClock}, crypto::opaque::OpaqueConf};
 use eyre::WrapErr;
+use hmac::{Hmac, Mac};
 use opaque_ke::ServerSetup;
-use crate::db::DbPool;
+use crate::db::{DbPool, sql::{MIGRATION_BLIND_INDEXED_USERNAMES, MIGRATION_SEALED_TOTP_SECRETS, MIGRATION_VARBINARY_TMP_SESSION_IDS}};
 use crate::config::Config;
+use crate::notifier::{self, Notifier};
+use sha2::Sha256;
 
 #[derive(Debug)]
 pub struct State {
     pub opaque_setup: ServerSetup<OpaqueConf>,
     pub secret_key: [u8; 32],
+    pub totp_key: [u8; 32],
+    pub username_pepper: [u8; 32],
     pub config: Config,
     pub db_pool: DbPool,
+    pub notifier: Arc<dyn Notifier>,
+    pub clock: Arc<dyn Clock>,
 }
 
 impl State {
@@ -27,17 +34,52 @@ impl State {
         let size = f.read(&mut secret_key)?;
         eyre::ensure!(size == secret_key.len(), "failed to read secret_key");
 
+        // load totp key
+        let mut f = File::open(common::consts::TOTP_KEY_PATH)?;
+        let mut totp_key = [0u8; 32];
+        let size = f.read(&mut totp_key)?;
+        eyre::ensure!(size == totp_key.len(), "failed to read totp_key");
+
+        // load username pepper
+        let mut f = File::open(common::consts::USERNAME_PEPPER_PATH)?;
+        let mut username_pepper = [0u8; 32];
+        let size = f.read(&mut username_pepper)?;
+        eyre::ensure!(size == username_pepper.len(), "failed to read username_pepper");
+
         // load config
         let config = Config::load().await?;
 
+        // setup notifications
+        let notifier = notifier::from_config(&config.notifier)?;
+
         // connect to DB
         let db = DbPool::new().await.wrap_err("failed to connect and initialize DB")?;
+        eyre::ensure!(
+            db.acquire().std().await?.is_migration_applied(MIGRATION_BLIND_INDEXED_USERNAMES).await?,
+            "usernames are still stored in clear, run `admin blind-index-usernames`");
+        eyre::ensure!(
+            db.acquire().std().await?.is_migration_applied(MIGRATION_SEALED_TOTP_SECRETS).await?,
+            "TOTP secrets are still stored in clear, run `admin seal-totp-secrets`");
+        eyre::ensure!(
+            db.acquire().std().await?.is_migration_applied(MIGRATION_VARBINARY_TMP_SESSION_IDS).await?,
+            "tmp session ids are still padded, run `admin varbinary-tmp-session-ids`");
 
         Ok(Self {
             opaque_setup,
             secret_key,
+            totp_key,
+            username_pepper,
             config,
             db_pool: db,
+            notifier,
+            clock: Arc::new(SystemClock),
         })
     }
+
+    // a key for a single use of the secret_key, so that a MAC made for one purpose is never valid for another
+    pub fn derive_secret_key(&self, label: &[u8]) -> api::Result<[u8; 32]> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret_key).map_err(|e| eyre::eyre!(e))?;
+        mac.update(label);
+        Ok(mac.finalize().into_bytes().into())
+    }
 }
\ No newline at end of file
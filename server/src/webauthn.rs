@@ -0,0 +1,200 @@
+use std::convert::{TryFrom, TryInto};
+
+use ciborium::Value;
+use common::api::{self, WebauthnAssertion, WebauthnAttestation, WebauthnChallenge};
+use data_encoding::BASE64URL_NOPAD;
+use eyre::eyre;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+// from https://www.iana.org/assignments/cose/cose.xhtml
+pub const COSE_ALG_ES256: i64 = -7;
+pub const COSE_ALG_EDDSA: i64 = -8;
+pub const SUPPORTED_ALGORITHMS: [i64; 2] = [COSE_ALG_ES256, COSE_ALG_EDDSA];
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+// from https://www.w3.org/TR/webauthn-2/#dictionary-client-data
+#[derive(Deserialize, Debug)]
+struct CollectedClientData {
+    #[serde(rename = "type")]
+    type_: String,
+    challenge: String,
+    origin: String,
+}
+
+// we don't ask for attestation so `fmt` and `attStmt` are ignored
+#[derive(Deserialize, Debug)]
+struct AttestationObject {
+    #[serde(rename = "authData", with = "serde_bytes")]
+    auth_data: Vec<u8>,
+}
+
+// from https://www.w3.org/TR/webauthn-2/#sctn-authenticator-data
+struct AuthenticatorData<'a> {
+    rp_id_hash: &'a [u8],
+    flags: u8,
+    sign_count: u32,
+    attested_credential: Option<(&'a [u8], &'a [u8])>, // credential_id, COSE public key
+}
+
+enum PublicKey {
+    Es256(p256::ecdsa::VerifyingKey),
+    EdDsa(ed25519_dalek::VerifyingKey),
+}
+
+// returns the credential's COSE public key and its initial signature counter
+pub fn registration_finish(rp_id: &str, origin: &str, challenge: &WebauthnChallenge, attestation: &WebauthnAttestation) -> api::Result<(Vec<u8>, u32)> {
+    check_client_data(attestation.client_data_json.as_slice(), "webauthn.create", origin, challenge)?;
+
+    let attestation_object: AttestationObject = ciborium::de::from_reader(attestation.attestation_object.as_slice())
+        .map_err(|e| eyre!("failed to deserialize attestation object: {}", e))?;
+    let auth_data = parse_authenticator_data(&attestation_object.auth_data)?;
+    check_authenticator_data(&auth_data, rp_id)?;
+
+    let (credential_id, public_key) = auth_data.attested_credential
+        .ok_or_else(|| eyre!("attested credential data is missing"))?;
+    if credential_id != attestation.credential_id.as_slice() {
+        return Err(eyre!("credential_id doesn't match attested credential data").into());
+    }
+
+    // make sure that we'll be able to use this key later
+    parse_cose_key(public_key)?;
+
+    Ok((public_key.to_vec(), auth_data.sign_count))
+}
+
+// returns the new signature counter of the credential
+pub fn assertion_finish(rp_id: &str, origin: &str, challenge: &WebauthnChallenge, public_key: &[u8], sign_count: u32, assertion: &WebauthnAssertion) -> api::Result<u32> {
+    check_client_data(assertion.client_data_json.as_slice(), "webauthn.get", origin, challenge)?;
+
+    let auth_data = parse_authenticator_data(assertion.authenticator_data.as_slice())?;
+    check_authenticator_data(&auth_data, rp_id)?;
+
+    // the signature covers the authenticator data and the hash of the client data
+    let mut signed_data = assertion.authenticator_data.as_slice().to_vec();
+    signed_data.extend_from_slice(&Sha256::digest(assertion.client_data_json.as_slice()));
+
+    match parse_cose_key(public_key)? {
+        PublicKey::Es256(key) => {
+            use p256::ecdsa::signature::Verifier;
+            let signature = p256::ecdsa::Signature::from_der(assertion.signature.as_slice()).map_err(|_| api::Error::InvalidSecondFactor)?;
+            key.verify(&signed_data, &signature).map_err(|_| api::Error::InvalidSecondFactor)?;
+        }
+        PublicKey::EdDsa(key) => {
+            let signature = ed25519_dalek::Signature::from_slice(assertion.signature.as_slice()).map_err(|_| api::Error::InvalidSecondFactor)?;
+            key.verify_strict(&signed_data, &signature).map_err(|_| api::Error::InvalidSecondFactor)?;
+        }
+    }
+
+    // authenticators which don't implement a signature counter always return 0.
+    // otherwise, a counter which didn't increase means that the authenticator might have been cloned
+    if (auth_data.sign_count != 0 || sign_count != 0) && auth_data.sign_count <= sign_count {
+        return Err(api::Error::InvalidSecondFactor);
+    }
+
+    Ok(auth_data.sign_count)
+}
+
+fn check_client_data(client_data_json: &[u8], type_: &str, origin: &str, challenge: &WebauthnChallenge) -> api::Result<()> {
+    let client_data: CollectedClientData = serde_json::from_slice(client_data_json)
+        .map_err(|e| eyre!("failed to deserialize client data: {}", e))?;
+
+    if client_data.type_ != type_
+        || client_data.origin != origin
+        || client_data.challenge != BASE64URL_NOPAD.encode(challenge.as_slice()) {
+        return Err(api::Error::InvalidSecondFactor);
+    }
+
+    Ok(())
+}
+
+fn check_authenticator_data(auth_data: &AuthenticatorData, rp_id: &str) -> api::Result<()> {
+    if auth_data.rp_id_hash != Sha256::digest(rp_id.as_bytes()).as_slice()
+        || auth_data.flags & FLAG_USER_PRESENT == 0 {
+        return Err(api::Error::InvalidSecondFactor);
+    }
+
+    Ok(())
+}
+
+fn parse_authenticator_data(auth_data: &[u8]) -> api::Result<AuthenticatorData<'_>> {
+    if auth_data.len() < 37 {
+        return Err(eyre!("authenticator data is too short").into());
+    }
+    let (rp_id_hash, rest) = auth_data.split_at(32);
+    let flags = rest[0];
+    let sign_count = u32::from_be_bytes(rest[1..5].try_into().unwrap());
+    let rest = &rest[5..];
+
+    let attested_credential = if flags & FLAG_ATTESTED_CREDENTIAL_DATA != 0 {
+        // skip the AAGUID
+        if rest.len() < 18 {
+            return Err(eyre!("attested credential data is too short").into());
+        }
+        let credential_id_len = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+        let rest = &rest[18..];
+        if rest.len() < credential_id_len {
+            return Err(eyre!("attested credential data is too short").into());
+        }
+        let (credential_id, public_key) = rest.split_at(credential_id_len);
+
+        // the public key is followed by the extensions, if any, so we need to find where it ends
+        let mut remaining = public_key;
+        let _: Value = ciborium::de::from_reader(&mut remaining)
+            .map_err(|e| eyre!("failed to deserialize credential public key: {}", e))?;
+        let public_key = &public_key[..public_key.len() - remaining.len()];
+
+        Some((credential_id, public_key))
+    } else {
+        None
+    };
+
+    Ok(AuthenticatorData {
+        rp_id_hash,
+        flags,
+        sign_count,
+        attested_credential,
+    })
+}
+
+// from RFC8152
+fn parse_cose_key(cose_key: &[u8]) -> api::Result<PublicKey> {
+    let cose_key = match ciborium::de::from_reader(cose_key).map_err(|e| eyre!("failed to deserialize COSE key: {}", e))? {
+        Value::Map(m) => m,
+        _ => return Err(eyre!("COSE key is not a map").into()),
+    };
+
+    let get = |label: i64| cose_key.iter()
+        .find(|(k, _)| k.as_integer().and_then(|k| i64::try_from(k).ok()) == Some(label))
+        .map(|(_, v)| v);
+    let get_int = |label: i64| get(label)
+        .and_then(|v| v.as_integer())
+        .and_then(|v| i64::try_from(v).ok())
+        .ok_or_else(|| eyre!("COSE key parameter {} is missing or invalid", label));
+    let get_bytes = |label: i64| get(label)
+        .and_then(|v| v.as_bytes())
+        .ok_or_else(|| eyre!("COSE key parameter {} is missing or invalid", label));
+
+    // kty = 1, alg = 3, crv = -1, x = -2, y = -3
+    Ok( match (get_int(1)?, get_int(3)?, get_int(-1)?) {
+        (2, COSE_ALG_ES256, 1) => { // EC2, ES256, P-256
+            let (x, y) = (get_bytes(-2)?, get_bytes(-3)?);
+            if x.len() != 32 || y.len() != 32 {
+                return Err(eyre!("invalid P-256 coordinates length").into());
+            }
+            let point = p256::EncodedPoint::from_affine_coordinates(
+                p256::FieldBytes::from_slice(x),
+                p256::FieldBytes::from_slice(y),
+                false,
+            );
+            PublicKey::Es256(p256::ecdsa::VerifyingKey::from_encoded_point(&point).map_err(|e| eyre!("invalid P-256 key: {}", e))?)
+        }
+        (1, COSE_ALG_EDDSA, 6) => { // OKP, EdDSA, Ed25519
+            let x: &[u8; 32] = get_bytes(-2)?.as_slice().try_into().map_err(|_| eyre!("invalid Ed25519 key length"))?;
+            PublicKey::EdDsa(ed25519_dalek::VerifyingKey::from_bytes(x).map_err(|e| eyre!("invalid Ed25519 key: {}", e))?)
+        }
+        (kty, alg, crv) => return Err(eyre!("unsupported COSE key: kty={} alg={} crv={}", kty, alg, crv).into()),
+    })
+}
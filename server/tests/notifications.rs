@@ -0,0 +1,227 @@
+use std::{future::Future, net::{IpAddr, Ipv4Addr}, sync::Arc};
+
+use client_common::opaque;
+use common::{api::{self, AddUser, AddUserRet, Credentials, LoginFinish, LoginStart, LoginStartRet, MasterKey, NewCredentials, NewCredentialsRet, UserId, Username, private_data::PrivateData}, clock::SystemClock, consts::{OPAQUE_S_ID, OPAQUE_S_ID_RECOVERY}, crypto::{crypto_boxes::Seal, opaque::OpaqueConf}};
+use opaque_ke::ServerSetup;
+use rand::Rng;
+use server::{db::{DbConn, DbPool}, notifier::{MemoryNotifier, Notification}, request_dispatcher::Req, state::State};
+
+// these tests need the database the server uses, on localhost:4000
+const DATABASE_NAME: &str = "cachou_test";
+
+const CONTACT_EMAIL: &str = "alice@example.com";
+
+struct Env {
+    state: State,
+    notifier: Arc<MemoryNotifier>,
+    req: Req,
+}
+
+async fn env() -> Env {
+    let notifier = Arc::new(MemoryNotifier::default());
+    let mut rng = rand::thread_rng();
+
+    let state = State {
+        opaque_setup: ServerSetup::<OpaqueConf>::new(&mut rand_core::OsRng),
+        secret_key: rng.gen(),
+        totp_key: rng.gen(),
+        username_pepper: rng.gen(),
+        config: toml::from_str(include_str!("../config.toml")).unwrap(),
+        db_pool: DbPool::open(DATABASE_NAME).await.unwrap(),
+        notifier: notifier.clone(),
+        clock: Arc::new(SystemClock),
+    };
+
+    Env {
+        state,
+        notifier,
+        // the login throttling counters would otherwise carry over between runs
+        req: Req { ip: IpAddr::V4(Ipv4Addr::from(rng.gen::<u32>())), port: 1234 },
+    }
+}
+
+fn block_on<F: Future>(f: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(f)
+}
+
+// lets the notifications spawned by the commit be sent
+async fn settle() {
+    for _ in 0..10 {
+        tokio::task::yield_now().await;
+    }
+}
+
+// ends the request like request_dispatcher::rpc does
+async fn end<T>(conn: DbConn<'_>, res: api::Result<T>) -> api::Result<T> {
+    if res.is_ok() {
+        conn.commit().await.unwrap();
+    } else {
+        conn.rollback().await.unwrap();
+    }
+    res
+}
+
+async fn new_user(state: &State, contact_email: Option<&str>) -> UserId {
+    let user_id = UserId::gen();
+    let mut conn = state.db_pool.acquire();
+    conn.tx().await.unwrap().new_user(&user_id, 0).await.unwrap();
+    if let Some(contact_email) = contact_email {
+        conn.tx().await.unwrap().set_user_contact_email(&user_id, contact_email).await.unwrap();
+    }
+    conn.commit().await.unwrap();
+    user_id
+}
+
+#[test]
+#[ignore = "needs a database on localhost:4000"]
+fn notify_is_sent_once_commited() {
+    block_on(async {
+        let env = env().await;
+        let user_id = new_user(&env.state, Some(CONTACT_EMAIL)).await;
+
+        let mut conn = env.state.db_pool.acquire();
+        env.state.notify(&mut conn, &user_id, Notification::TotpEnabled).await.unwrap();
+        settle().await;
+        assert!(env.notifier.sent().is_empty());
+
+        conn.commit().await.unwrap();
+        settle().await;
+        assert_eq!(env.notifier.sent(), vec![(CONTACT_EMAIL.to_owned(), Notification::TotpEnabled)]);
+    });
+}
+
+#[test]
+#[ignore = "needs a database on localhost:4000"]
+fn notify_is_dropped_on_rollback() {
+    block_on(async {
+        let env = env().await;
+        let user_id = new_user(&env.state, Some(CONTACT_EMAIL)).await;
+
+        let mut conn = env.state.db_pool.acquire();
+        env.state.notify(&mut conn, &user_id, Notification::TotpEnabled).await.unwrap();
+        conn.rollback().await.unwrap();
+        settle().await;
+        assert!(env.notifier.sent().is_empty());
+    });
+}
+
+#[test]
+#[ignore = "needs a database on localhost:4000"]
+fn notify_now_is_sent_even_if_the_request_fails() {
+    block_on(async {
+        let env = env().await;
+        let user_id = new_user(&env.state, Some(CONTACT_EMAIL)).await;
+
+        let mut conn = env.state.db_pool.acquire();
+        env.state.notify_now(&mut conn, &user_id, Notification::MasterKeyRotated).await.unwrap();
+        conn.rollback().await.unwrap();
+        settle().await;
+        assert_eq!(env.notifier.sent(), vec![(CONTACT_EMAIL.to_owned(), Notification::MasterKeyRotated)]);
+    });
+}
+
+#[test]
+#[ignore = "needs a database on localhost:4000"]
+fn nothing_is_sent_without_a_contact_email() {
+    block_on(async {
+        let env = env().await;
+        let user_id = new_user(&env.state, None).await;
+
+        let mut conn = env.state.db_pool.acquire();
+        env.state.notify(&mut conn, &user_id, Notification::TotpEnabled).await.unwrap();
+        env.state.notify_now(&mut conn, &user_id, Notification::MasterKeyRotated).await.unwrap();
+        conn.commit().await.unwrap();
+        settle().await;
+        assert!(env.notifier.sent().is_empty());
+    });
+}
+
+async fn credentials(env: &Env, master_key: &MasterKey, username: &Username, password: &[u8], recovery: bool) -> Credentials {
+    let (opaque_state, opaque_msg) = opaque::registration_start(password).unwrap();
+
+    let mut conn = env.state.db_pool.acquire();
+    let res = env.state.new_credentials(&NewCredentials { opaque_msg, username: username.clone(), recovery }, &mut conn).await;
+    let NewCredentialsRet { secret_server_state, opaque_msg } = end(conn, res).await.unwrap();
+
+    let (opaque_msg, export_key) = opaque::registration_finish(&opaque_state, &opaque_msg, username, if recovery { &OPAQUE_S_ID_RECOVERY } else { &OPAQUE_S_ID }).unwrap();
+
+    Credentials {
+        secret_server_state,
+        opaque_msg,
+        secret_master_key: master_key.seal(export_key.as_slice()).unwrap(),
+        secret_export_key: export_key.seal(master_key.as_slice()).unwrap(),
+    }
+}
+
+fn device_public_key() -> api::DevicePublicKey {
+    ed25519_dalek::SigningKey::generate(&mut rand::thread_rng()).verifying_key().to_bytes().to_vec().into()
+}
+
+async fn add_user(env: &Env, username: &Username, password: &[u8]) -> UserId {
+    let master_key = MasterKey::gen();
+    let private_data = PrivateData {
+        ident_signing_key: ed25519_dalek::SigningKey::generate(&mut rand::thread_rng()),
+        username: None,
+    };
+
+    let args = AddUser {
+        credentials: credentials(env, &master_key, username, password, false).await,
+        credentials_recovery: credentials(env, &master_key, &Username::from_vec(rand::thread_rng().gen::<[u8; 32]>().to_vec()), b"recovery password", true).await,
+        secret_private_data: private_data.seal(master_key.as_slice()).unwrap(),
+        device_label: "test".to_owned(),
+        device_public_key: device_public_key(),
+    };
+
+    let mut conn = env.state.db_pool.acquire();
+    let res = env.state.add_user(&args, &env.req, &mut conn).await;
+    let AddUserRet { authed_session_token } = end(conn, res).await.unwrap();
+    authed_session_token.get_unverified().unwrap().user_id
+}
+
+async fn login(env: &Env, username: &Username, password: &[u8]) -> api::Result<()> {
+    let (opaque_state, opaque_msg) = opaque::login_start(password)?;
+
+    let mut conn = env.state.db_pool.acquire();
+    let res = env.state.login_start(&LoginStart { recovery: false, username: username.clone(), opaque_msg }, &env.req, &mut conn).await;
+    let LoginStartRet { secret_server_state, opaque_msg } = end(conn, res).await?;
+
+    // a wrong password is noticed here, LoginFinish is then never sent
+    let (opaque_msg, _) = opaque::login_finish(&opaque_state, &opaque_msg, username, &OPAQUE_S_ID)?;
+
+    let args = LoginFinish {
+        secret_server_state,
+        opaque_msg,
+        uber_clearance: false,
+        auto_logout: false,
+        device_label: "test".to_owned(),
+        device_public_key: device_public_key(),
+        trusted_device_token: None,
+    };
+    let mut conn = env.state.db_pool.acquire();
+    let res = env.state.login_finish(&args, &env.req, &mut conn).await;
+    end(conn, res).await.map(|_| ())
+}
+
+#[test]
+#[ignore = "needs a database on localhost:4000"]
+fn login_sends_new_login() {
+    block_on(async {
+        let env = env().await;
+        let username = Username::from_vec(format!("user{:08x}", rand::thread_rng().gen::<u32>()).into_bytes());
+
+        // no contact email yet
+        let user_id = add_user(&env, &username, b"password").await;
+        login(&env, &username, b"password").await.unwrap();
+        settle().await;
+        assert!(env.notifier.sent().is_empty());
+
+        let mut conn = env.state.db_pool.acquire();
+        conn.tx().await.unwrap().set_user_contact_email(&user_id, CONTACT_EMAIL).await.unwrap();
+        conn.commit().await.unwrap();
+
+        assert!(login(&env, &username, b"wrong password").await.is_err());
+        login(&env, &username, b"password").await.unwrap();
+        settle().await;
+        assert_eq!(env.notifier.sent(), vec![(CONTACT_EMAIL.to_owned(), Notification::NewLogin { ip: env.req.ip })]);
+    });
+}
@@ -0,0 +1,188 @@
+use std::{collections::HashMap, future::Future, net::{IpAddr, Ipv4Addr}};
+
+use async_trait::async_trait;
+use common::api::{self, UserId};
+use server::core::throttle::{SecondFactorThrottlePolicy, ThrottleKind, ThrottlePolicy, ThrottleStore};
+
+const T0: i64 = 1_600_000_000;
+
+const POLICY: ThrottlePolicy = ThrottlePolicy {
+    username_free_attempts: 5,
+    ip_free_attempts: 20,
+    base_delay_sec: 1,
+    max_delay_sec: 3600,
+    forget_after_sec: 86400,
+};
+
+const SECOND_FACTOR_POLICY: SecondFactorThrottlePolicy = SecondFactorThrottlePolicy {
+    free_attempts: 5,
+    lockout_sec: 900,
+    forget_after_sec: 86400,
+};
+
+const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+#[derive(Default)]
+struct Row {
+    attempts: u32,
+    last_attempt: i64,
+    blocked_until: i64,
+}
+
+// behaves like the `login_throttles` queries of NormalConn
+#[derive(Default)]
+struct MemoryStore(HashMap<(ThrottleKind, Vec<u8>), Row>);
+
+#[async_trait]
+impl ThrottleStore for MemoryStore {
+    async fn blocked_until(&mut self, kind: ThrottleKind, subject: &[u8]) -> api::Result<Option<i64>> {
+        Ok(self.0.get(&(kind, subject.to_vec())).map(|row| row.blocked_until))
+    }
+
+    async fn add_attempt(&mut self, kind: ThrottleKind, subject: &[u8], now: i64, forget_before: i64) -> api::Result<u32> {
+        let row = self.0.entry((kind, subject.to_vec())).or_default();
+        row.attempts = if row.last_attempt < forget_before { 1 } else { row.attempts + 1 };
+        row.last_attempt = now;
+        Ok(row.attempts)
+    }
+
+    async fn remove_attempt(&mut self, kind: ThrottleKind, subject: &[u8]) -> api::Result<()> {
+        if let Some(row) = self.0.get_mut(&(kind, subject.to_vec())) {
+            row.attempts = row.attempts.saturating_sub(1);
+        }
+        Ok(())
+    }
+
+    async fn block_until(&mut self, kind: ThrottleKind, subject: &[u8], blocked_until: i64) -> api::Result<()> {
+        if let Some(row) = self.0.get_mut(&(kind, subject.to_vec())) {
+            row.blocked_until = row.blocked_until.max(blocked_until);
+        }
+        Ok(())
+    }
+
+    async fn reset(&mut self, kind: ThrottleKind, subject: &[u8]) -> api::Result<()> {
+        self.0.remove(&(kind, subject.to_vec()));
+        Ok(())
+    }
+}
+
+fn block_on<F: Future>(f: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(f)
+}
+
+fn retry_after(res: api::Result<()>) -> Option<u32> {
+    match res {
+        Ok(()) => None,
+        Err(api::Error::RateLimited { retry_after }) => Some(retry_after),
+        Err(e) => panic!("unexpected error: {:?}", e),
+    }
+}
+
+#[test]
+fn unfinished_logins_end_rate_limited() {
+    block_on(async {
+        let mut store = MemoryStore::default();
+
+        // a client noticing a wrong password never sends LoginFinish, only LoginStart is seen
+        for i in 0..=POLICY.username_free_attempts {
+            assert_eq!(retry_after(POLICY.start(&mut store, T0, false, b"alice", IP).await), None, "attempt {}", i);
+        }
+        assert_eq!(retry_after(POLICY.start(&mut store, T0, false, b"alice", IP).await), Some(1));
+
+        // the delay doubles with each attempt once it has passed
+        assert_eq!(retry_after(POLICY.start(&mut store, T0 + 1, false, b"alice", IP).await), None);
+        assert_eq!(retry_after(POLICY.start(&mut store, T0 + 1, false, b"alice", IP).await), Some(2));
+
+        // recovery credentials are counted apart
+        assert_eq!(retry_after(POLICY.start(&mut store, T0 + 1, true, b"alice", IP).await), None);
+    });
+}
+
+#[test]
+fn finished_logins_are_not_limited() {
+    block_on(async {
+        let mut store = MemoryStore::default();
+
+        for i in 0..100 {
+            assert_eq!(retry_after(POLICY.start(&mut store, T0, false, b"alice", IP).await), None, "attempt {}", i);
+            POLICY.success(&mut store, false, b"alice", IP).await.unwrap();
+        }
+    });
+}
+
+#[test]
+fn ip_is_limited_across_usernames() {
+    block_on(async {
+        let mut store = MemoryStore::default();
+
+        for i in 0..=POLICY.ip_free_attempts {
+            let username = format!("user{}", i);
+            assert_eq!(retry_after(POLICY.start(&mut store, T0, false, username.as_bytes(), IP).await), None, "attempt {}", i);
+        }
+        assert_eq!(retry_after(POLICY.start(&mut store, T0, false, b"another", IP).await), Some(1));
+    });
+}
+
+#[test]
+fn own_account_doesnt_clear_ip() {
+    block_on(async {
+        let mut store = MemoryStore::default();
+
+        // one attempt short of the limit, leaving room for the attacker's own logins
+        for i in 1..POLICY.ip_free_attempts {
+            let username = format!("victim{}", i);
+            POLICY.start(&mut store, T0, false, username.as_bytes(), IP).await.unwrap();
+        }
+
+        // successful logins only take back their own attempt
+        for _ in 0..10 {
+            POLICY.start(&mut store, T0, false, b"attacker", IP).await.unwrap();
+            POLICY.success(&mut store, false, b"attacker", IP).await.unwrap();
+        }
+
+        for username in [&b"victim20"[..], b"victim21"] {
+            POLICY.start(&mut store, T0, false, username, IP).await.unwrap();
+        }
+        assert_eq!(retry_after(POLICY.start(&mut store, T0, false, b"victim22", IP).await), Some(1));
+    });
+}
+
+#[test]
+fn wrong_second_factors_lock_out() {
+    block_on(async {
+        let mut store = MemoryStore::default();
+        let user_id = UserId::from([1; 16]);
+
+        for i in 1..SECOND_FACTOR_POLICY.free_attempts {
+            SECOND_FACTOR_POLICY.check(&mut store, T0, &user_id).await.unwrap();
+            SECOND_FACTOR_POLICY.failure(&mut store, T0, &user_id).await.unwrap();
+            assert_eq!(retry_after(SECOND_FACTOR_POLICY.check(&mut store, T0, &user_id).await), None, "failure {}", i);
+        }
+        SECOND_FACTOR_POLICY.failure(&mut store, T0, &user_id).await.unwrap();
+        assert_eq!(retry_after(SECOND_FACTOR_POLICY.check(&mut store, T0, &user_id).await), Some(900));
+
+        // another user isn't affected
+        assert_eq!(retry_after(SECOND_FACTOR_POLICY.check(&mut store, T0, &UserId::from([2; 16])).await), None);
+
+        // once the lockout is over, a single failure locks the user out again
+        assert_eq!(retry_after(SECOND_FACTOR_POLICY.check(&mut store, T0 + 900, &user_id).await), None);
+        SECOND_FACTOR_POLICY.failure(&mut store, T0 + 900, &user_id).await.unwrap();
+        assert_eq!(retry_after(SECOND_FACTOR_POLICY.check(&mut store, T0 + 900, &user_id).await), Some(900));
+    });
+}
+
+#[test]
+fn right_second_factor_resets_failures() {
+    block_on(async {
+        let mut store = MemoryStore::default();
+        let user_id = UserId::from([1; 16]);
+
+        for _ in 0..10 {
+            for _ in 1..SECOND_FACTOR_POLICY.free_attempts {
+                SECOND_FACTOR_POLICY.failure(&mut store, T0, &user_id).await.unwrap();
+            }
+            SECOND_FACTOR_POLICY.success(&mut store, &user_id).await.unwrap();
+        }
+        assert_eq!(retry_after(SECOND_FACTOR_POLICY.check(&mut store, T0, &user_id).await), None);
+    });
+}
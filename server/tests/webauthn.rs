@@ -0,0 +1,118 @@
+use client_common::webauthn::{Authenticator, SoftwareAuthenticator};
+use common::api::{self, UserId, WebauthnChallenge, WebauthnCreationOptions, WebauthnRequestOptions, WebauthnSignature};
+use server::webauthn::{assertion_finish, registration_finish, SUPPORTED_ALGORITHMS};
+
+const RP_ID: &str = "127.0.0.1";
+const ORIGIN: &str = "http://127.0.0.1:8081";
+
+struct Registered {
+    authenticator: SoftwareAuthenticator,
+    credential_id: api::WebauthnCredentialId,
+    public_key: Vec<u8>,
+    sign_count: u32,
+}
+
+fn register() -> Registered {
+    let mut authenticator = SoftwareAuthenticator::new(ORIGIN);
+    let challenge = WebauthnChallenge::gen();
+    let attestation = authenticator.make_credential(&WebauthnCreationOptions {
+        rp_id: RP_ID.to_owned(),
+        user_handle: UserId::gen(),
+        challenge: challenge.clone(),
+        algorithms: SUPPORTED_ALGORITHMS.to_vec(),
+        exclude_credentials: vec![],
+    }).unwrap();
+
+    let (public_key, sign_count) = registration_finish(RP_ID, ORIGIN, &challenge, &attestation).unwrap();
+
+    Registered {
+        authenticator,
+        credential_id: attestation.credential_id,
+        public_key,
+        sign_count,
+    }
+}
+
+fn request_options(registered: &Registered, challenge: &WebauthnChallenge) -> WebauthnRequestOptions {
+    WebauthnRequestOptions {
+        rp_id: RP_ID.to_owned(),
+        challenge: challenge.clone(),
+        allow_credentials: vec![registered.credential_id.clone()],
+    }
+}
+
+#[test]
+fn registration_and_assertion() {
+    let mut registered = register();
+    assert_eq!(registered.sign_count, 0);
+
+    for expected_sign_count in 1..=3 {
+        let challenge = WebauthnChallenge::gen();
+        let assertion = registered.authenticator.get_assertion(&request_options(&registered, &challenge)).unwrap();
+        let sign_count = assertion_finish(RP_ID, ORIGIN, &challenge, &registered.public_key, registered.sign_count, &assertion).unwrap();
+        assert_eq!(sign_count, expected_sign_count);
+        registered.sign_count = sign_count;
+    }
+}
+
+#[test]
+fn registration_rejects_wrong_challenge_origin_or_rp_id() {
+    let mut authenticator = SoftwareAuthenticator::new(ORIGIN);
+    let challenge = WebauthnChallenge::gen();
+    let attestation = authenticator.make_credential(&WebauthnCreationOptions {
+        rp_id: RP_ID.to_owned(),
+        user_handle: UserId::gen(),
+        challenge: challenge.clone(),
+        algorithms: SUPPORTED_ALGORITHMS.to_vec(),
+        exclude_credentials: vec![],
+    }).unwrap();
+
+    assert!(matches!(registration_finish(RP_ID, ORIGIN, &WebauthnChallenge::gen(), &attestation), Err(api::Error::InvalidSecondFactor)));
+    assert!(matches!(registration_finish(RP_ID, "https://evil.example", &challenge, &attestation), Err(api::Error::InvalidSecondFactor)));
+    assert!(matches!(registration_finish("evil.example", ORIGIN, &challenge, &attestation), Err(api::Error::InvalidSecondFactor)));
+}
+
+#[test]
+fn assertion_rejects_wrong_challenge_origin_or_rp_id() {
+    let mut registered = register();
+    let challenge = WebauthnChallenge::gen();
+    let assertion = registered.authenticator.get_assertion(&request_options(&registered, &challenge)).unwrap();
+
+    assert!(matches!(assertion_finish(RP_ID, ORIGIN, &WebauthnChallenge::gen(), &registered.public_key, registered.sign_count, &assertion), Err(api::Error::InvalidSecondFactor)));
+    assert!(matches!(assertion_finish(RP_ID, "https://evil.example", &challenge, &registered.public_key, registered.sign_count, &assertion), Err(api::Error::InvalidSecondFactor)));
+    assert!(matches!(assertion_finish("evil.example", ORIGIN, &challenge, &registered.public_key, registered.sign_count, &assertion), Err(api::Error::InvalidSecondFactor)));
+}
+
+#[test]
+fn assertion_rejects_replayed_sign_count() {
+    let mut registered = register();
+    let challenge = WebauthnChallenge::gen();
+    let assertion = registered.authenticator.get_assertion(&request_options(&registered, &challenge)).unwrap();
+    let sign_count = assertion_finish(RP_ID, ORIGIN, &challenge, &registered.public_key, registered.sign_count, &assertion).unwrap();
+
+    // the same assertion can't be accepted twice
+    assert!(matches!(assertion_finish(RP_ID, ORIGIN, &challenge, &registered.public_key, sign_count, &assertion), Err(api::Error::InvalidSecondFactor)));
+}
+
+#[test]
+fn assertion_rejects_tampered_signature() {
+    let mut registered = register();
+    let challenge = WebauthnChallenge::gen();
+    let mut assertion = registered.authenticator.get_assertion(&request_options(&registered, &challenge)).unwrap();
+
+    let mut signature = assertion.signature.as_slice().to_vec();
+    signature[0] ^= 1;
+    assertion.signature = WebauthnSignature::from(signature);
+
+    assert!(matches!(assertion_finish(RP_ID, ORIGIN, &challenge, &registered.public_key, registered.sign_count, &assertion), Err(api::Error::InvalidSecondFactor)));
+}
+
+#[test]
+fn assertion_rejects_other_credential_key() {
+    let mut registered = register();
+    let other = register();
+    let challenge = WebauthnChallenge::gen();
+    let assertion = registered.authenticator.get_assertion(&request_options(&registered, &challenge)).unwrap();
+
+    assert!(matches!(assertion_finish(RP_ID, ORIGIN, &challenge, &other.public_key, registered.sign_count, &assertion), Err(api::Error::InvalidSecondFactor)));
+}